@@ -1,7 +1,7 @@
 use std::sync::Arc;
 
 use gloo_net::http::Request;
-use shared::{ChatRequest, ConversationResponse};
+use shared::{ChatRequest, ConversationResponse, Source};
 use uuid::Uuid;
 use yew::prelude::*;
 use yew_hooks::use_interval;
@@ -20,6 +20,7 @@ fn App() -> Html {
 
     let answer: UseStateHandle<Option<String>> = use_state(|| None);
     let context: UseStateHandle<Option<String>> = use_state(|| None);
+    let sources: UseStateHandle<Vec<Source>> = use_state(Vec::new);
 
     let conversation_slug: UseStateHandle<Uuid> = use_state(Uuid::new_v4);
 
@@ -27,6 +28,7 @@ fn App() -> Html {
         let question = question.clone();
         let answer = answer.clone();
         let context = context.clone();
+        let sources = sources.clone();
         let textarea_ref = textarea_ref.clone();
 
         move |e: SubmitEvent| {
@@ -36,6 +38,7 @@ fn App() -> Html {
             question.set(Some(value));
             answer.set(None);
             context.set(None);
+            sources.set(vec![]);
 
             e.prevent_default();
         }
@@ -43,6 +46,7 @@ fn App() -> Html {
     {
         let answer = answer.clone();
         let context = context.clone();
+        let sources = sources.clone();
         let question = question.clone();
         let conversation_slug = conversation_slug.clone();
         use_interval(
@@ -50,6 +54,7 @@ fn App() -> Html {
                 let conversation_slug = conversation_slug.clone();
                 let answer = answer.clone();
                 let context = context.clone();
+                let sources = sources.clone();
                 let question = question.clone();
 
                 if question.is_none() || answer.is_some() {
@@ -71,6 +76,7 @@ fn App() -> Html {
                     if let Some(answer_resp) = answer_resp {
                         answer.set(answer_resp.answer);
                         context.set(answer_resp.context);
+                        sources.set(answer_resp.sources);
                     }
                 });
             },
@@ -96,6 +102,10 @@ fn App() -> Html {
                     let req = ChatRequest {
                         question: q.to_owned(),
                         conversation_slug: *conversation_slug,
+                        rerank: false,
+                        corpora: vec![],
+                        prompt_variant: None,
+                        strategy: None,
                     };
 
                     let answer_resp: ConversationResponse = Request::post(&chat_api_url)
@@ -147,6 +157,16 @@ fn App() -> Html {
             if let Some(a) = answer.as_ref() {
                 <p>{"Answer: "}{ a }</p>
             }
+            if !sources.is_empty() {
+                <div>
+                    <p>{"Sources:"}</p>
+                    <ul>
+                        { for sources.iter().map(|source| html! {
+                            <li>{"["}{ source.number }{"] "}{ &source.path }</li>
+                        }) }
+                    </ul>
+                </div>
+            }
         </div>
     }
 }
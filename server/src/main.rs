@@ -11,7 +11,11 @@ use axum::{
 use miette::{Context, IntoDiagnostic, Result};
 use rusqlite::{params, Connection, OptionalExtension, Row};
 use shared::{ChatRequest, ConversationResponse};
-use snakegpt::{get_context, respond_to_with_context, setup, EmbeddingConnection};
+use snakegpt::{
+    condense_question, Config, Corpora, CorpusSelection, Exchange, HttpReranker, LlmReranker,
+    Prompts, Reranker, RetrievalOptions, StoragePaths, StrategyName, StrategySetup,
+    CHAT_DEFAULT_MODEL, DEFAULT_CORPUS, DEFAULT_MAX_STEPS,
+};
 use tower::ServiceExt;
 use tower_http::{
     cors::{Any, CorsLayer},
@@ -26,17 +30,23 @@ impl AppConnection {
     pub fn setup_schema_v0(&self) -> Result<()> {
         let conn = self.0.lock().unwrap();
 
-        conn.execute(
+        conn.execute_batch(
             "
             CREATE TABLE IF NOT EXISTS conversations (
-                slug                  TEXT NOT NULL,
+                slug                  TEXT NOT NULL
+            );
+            CREATE UNIQUE INDEX IF NOT EXISTS uniq_index_conversations_slugs on conversations (slug);
+
+            CREATE TABLE IF NOT EXISTS messages (
+                conversation_id       INTEGER NOT NULL,
                 question              TEXT NOT NULL,
+                standalone_question   TEXT,
                 context               TEXT,
-                answer                TEXT
+                answer                TEXT,
+                sources               TEXT
             );
-            CREATE UNIQUE INDEX IF NOT EXISTS uniq_index_conversations_slugs on conversations (slug);
+            CREATE INDEX IF NOT EXISTS index_messages_conversation_id on messages (conversation_id);
             ",
-            (),
         )
         .into_diagnostic()?;
 
@@ -56,7 +66,8 @@ impl AppConnection {
 
 #[derive(Debug, Clone)]
 struct AppState {
-    embedding_connection: EmbeddingConnection,
+    corpora: Corpora,
+    prompts: Prompts,
     app_connection: AppConnection,
 }
 
@@ -66,9 +77,15 @@ impl FromRef<AppState> for AppConnection {
     }
 }
 
-impl FromRef<AppState> for EmbeddingConnection {
+impl FromRef<AppState> for Corpora {
+    fn from_ref(conn: &AppState) -> Self {
+        conn.corpora.clone()
+    }
+}
+
+impl FromRef<AppState> for Prompts {
     fn from_ref(conn: &AppState) -> Self {
-        conn.embedding_connection.clone()
+        conn.prompts.clone()
     }
 }
 
@@ -81,10 +98,7 @@ async fn main() -> Result<()> {
         // allow requests from any origin
         .allow_origin(Any);
 
-    let conn = setup()?;
-    let conn = Mutex::new(conn);
-    let conn = Arc::new(conn);
-    let conn = EmbeddingConnection(conn);
+    let corpora = Corpora::open(&StoragePaths::from_env(), &corpora_from_env()?)?;
 
     let app_conn = Connection::open_in_memory().into_diagnostic()?;
     let app_conn = Mutex::new(app_conn);
@@ -96,7 +110,8 @@ async fn main() -> Result<()> {
         .wrap_err("Couldn't setup app DB schema ")?;
 
     let state = AppState {
-        embedding_connection: conn,
+        corpora,
+        prompts: Prompts::from_env()?,
         app_connection: app_conn,
     };
 
@@ -126,51 +141,168 @@ async fn main() -> Result<()> {
 }
 
 async fn start_chat(
-    State(conn): State<EmbeddingConnection>,
+    State(corpora): State<Corpora>,
+    State(prompts): State<Prompts>,
     State(app): State<AppConnection>,
     extract::Json(r): Json<ChatRequest>,
-) -> Json<ConversationResponse> {
+) -> Result<Json<ConversationResponse>, (StatusCode, String)> {
     let question = r.question;
+    let corpora = corpora
+        .select(&r.corpora)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let prompts = prompts
+        .with_variant(r.prompt_variant.as_deref())
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let options = retrieval_options(r.rerank, &prompts)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let strategy = r
+        .strategy
+        .as_deref()
+        .map(str::parse::<StrategyName>)
+        .transpose()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?
+        .unwrap_or_default();
 
-    let conversation_id = {
+    let (message_id, history) = {
         let app = app.0.lock().unwrap();
-        app.query_row(
-            "INSERT OR IGNORE INTO conversations (slug, question) VALUES (?, ?) returning rowid",
-            params![r.conversation_slug.to_string(), question],
-            |row: &Row| -> Result<i64, _> { row.get(0) },
+        app.execute(
+            "INSERT OR IGNORE INTO conversations (slug) VALUES (?)",
+            params![r.conversation_slug.to_string()],
         )
-        .unwrap()
+        .unwrap();
+        let conversation_id: i64 = app
+            .query_row(
+                "SELECT rowid FROM conversations WHERE slug = ?",
+                params![r.conversation_slug.to_string()],
+                |row| row.get(0),
+            )
+            .unwrap();
+
+        let history = app
+            .prepare(
+                "SELECT question, answer FROM messages
+                WHERE conversation_id = ? AND answer IS NOT NULL
+                ORDER BY rowid",
+            )
+            .unwrap()
+            .query_map(params![conversation_id], |row| {
+                Ok(Exchange {
+                    question: row.get(0)?,
+                    answer: row.get(1)?,
+                })
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+
+        let message_id = app
+            .query_row(
+                "INSERT INTO messages (conversation_id, question) VALUES (?, ?) returning rowid",
+                params![conversation_id, question],
+                |row: &Row| -> Result<i64, _> { row.get(0) },
+            )
+            .unwrap();
+
+        (message_id, history)
     };
 
     let convo_resp = convo_resp_from_slug(&app, r.conversation_slug).unwrap();
+    let conversation_slug = r.conversation_slug;
 
-    let conversation_id = conversation_id;
     tokio::spawn(async move {
-        let (context, question) = get_context(question.clone(), conn.clone()).await.unwrap();
+        let config = Config::from_env().unwrap();
+        let client = config.client().unwrap();
+        let standalone_question = condense_question(&client, &prompts, &history, &question)
+            .await
+            .unwrap();
         {
             let app = app.0.lock().unwrap();
             app.execute(
-                "UPDATE conversations SET context = ? WHERE rowid = ?",
-                params![context, conversation_id],
+                "UPDATE messages SET standalone_question = ? WHERE rowid = ?",
+                params![standalone_question, message_id],
             )
             .unwrap();
         }
 
-        // Create a new conversation in the DB with the question
-        let resp = respond_to_with_context(context, question);
-        let (answer, _context) = resp.await.unwrap();
+        let strategy = strategy.build(StrategySetup {
+            config,
+            corpora,
+            options,
+            prompts,
+            model: CHAT_DEFAULT_MODEL.to_string(),
+            max_steps: DEFAULT_MAX_STEPS,
+        });
+        let response = strategy.answer(&standalone_question, None).await.unwrap();
+        let answer = response.answer;
+        let sources = serde_json::to_string(&answer.sources).unwrap();
 
         {
             let app = app.0.lock().unwrap();
             app.execute(
-                "UPDATE conversations SET answer = ? WHERE rowid = ?",
-                params![answer, conversation_id],
+                "UPDATE messages SET context = ?, answer = ?, sources = ? WHERE rowid = ?",
+                params![
+                    response.context.to_prompt(),
+                    answer.text,
+                    sources,
+                    message_id
+                ],
             )
             .unwrap();
+
+            // Each step is saved as an action named for the tool it called, `answer` for the
+            // step that answered, or `invalid` for a turn the model got wrong
+            for step in &response.trace {
+                let action_type = step.action_type();
+                let action_data = serde_json::json!({
+                    "message_id": message_id,
+                    "strategy": response.strategy,
+                    "step": step,
+                });
+
+                app.execute(
+                    "INSERT INTO actions (conversation_id, action_type, action_data) VALUES (?, ?, ?)",
+                    params![conversation_slug.to_string(), action_type, action_data.to_string()],
+                )
+                .unwrap();
+            }
         }
     });
 
-    Json(convo_resp.unwrap())
+    Ok(Json(convo_resp.unwrap()))
+}
+
+/// Reads the corpora to load from `SNAKEGPT_CORPORA`, like `community,official:0.5`
+fn corpora_from_env() -> Result<Vec<CorpusSelection>> {
+    let Ok(corpora) = std::env::var("SNAKEGPT_CORPORA") else {
+        return Ok(vec![DEFAULT_CORPUS.parse().unwrap()]);
+    };
+
+    corpora
+        .split(',')
+        .map(|selection| selection.trim().parse().map_err(|e| miette::miette!("{e}")))
+        .collect()
+}
+
+/// Uses the local reranker when `RERANKER_URL` is set and falls back to asking the chat model
+fn retrieval_options(rerank: bool, prompts: &Prompts) -> Result<RetrievalOptions> {
+    if !rerank {
+        return Ok(RetrievalOptions {
+            prompts: prompts.clone(),
+            ..Default::default()
+        });
+    }
+
+    let reranker: Arc<dyn Reranker> = match HttpReranker::from_env() {
+        Some(reranker) => Arc::new(reranker),
+        None => Arc::new(LlmReranker::new(Config::from_env()?, prompts.clone())),
+    };
+
+    Ok(RetrievalOptions {
+        candidates: 50,
+        reranker: Some(reranker),
+        prompts: prompts.clone(),
+        ..Default::default()
+    })
 }
 
 async fn get_convo(
@@ -180,6 +312,7 @@ async fn get_convo(
     Json(convo_resp_from_slug(&app, convo_slug).unwrap())
 }
 
+/// Responds with the latest message in the conversation
 fn convo_resp_from_slug(
     app: &AppConnection,
     convo_slug: Uuid,
@@ -187,14 +320,26 @@ fn convo_resp_from_slug(
     let app = app.0.lock().unwrap();
     let convo: Option<ConversationResponse> = app
         .query_row(
-            "SELECT question, answer, context FROM conversations WHERE slug = ?",
+            "SELECT messages.question, messages.answer, messages.context, messages.sources, messages.standalone_question
+            FROM messages
+            JOIN conversations ON conversations.rowid = messages.conversation_id
+            WHERE conversations.slug = ?
+            ORDER BY messages.rowid DESC
+            LIMIT 1",
             params![convo_slug.to_string()],
             |row: &Row| {
+                let sources: Option<String> = row.get(3)?;
+                let sources = sources
+                    .and_then(|s| serde_json::from_str(&s).ok())
+                    .unwrap_or_default();
+
                 Ok(ConversationResponse {
                     slug: convo_slug,
                     question: row.get(0)?,
+                    standalone_question: row.get(4)?,
                     answer: row.get(1)?,
                     context: row.get(2)?,
+                    sources,
                 })
             },
         )
@@ -1,16 +1,91 @@
+use std::str::FromStr;
+
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+/// Picks a corpus to search and optionally overrides how heavily it is weighted
+///
+/// Parses from `name` or `name:weight`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct CorpusSelection {
+    pub name: String,
+    #[serde(default)]
+    pub weight: Option<f64>,
+}
+
+impl CorpusSelection {
+    /// Distances are divided by the weight, so it has to be a finite number above 0 to rank
+    /// anything sensibly
+    pub fn valid_weight(weight: f64) -> bool {
+        weight.is_finite() && weight > 0.0
+    }
+}
+
+impl FromStr for CorpusSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((name, weight)) => {
+                let weight = weight
+                    .parse()
+                    .ok()
+                    .filter(|weight| CorpusSelection::valid_weight(*weight))
+                    .ok_or_else(|| {
+                        format!(
+                            "Invalid weight for corpus {name}: {weight}, it has to be a number above 0"
+                        )
+                    })?;
+
+                Ok(Self {
+                    name: name.to_string(),
+                    weight: Some(weight),
+                })
+            }
+            None => Ok(Self {
+                name: s.to_string(),
+                weight: None,
+            }),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ChatRequest {
     pub conversation_slug: Uuid,
     pub question: String,
+    /// Run the retrieved candidates through a reranker before answering
+    #[serde(default)]
+    pub rerank: bool,
+    /// Which corpora to search. Empty searches all of them
+    #[serde(default)]
+    pub corpora: Vec<CorpusSelection>,
+    /// Named prompt variant to answer with, the default prompts when missing
+    #[serde(default)]
+    pub prompt_variant: Option<String>,
+    /// How to answer, like `simple-rag`. The default strategy when missing
+    #[serde(default)]
+    pub strategy: Option<String>,
+}
+
+/// A page from the retrieved context that the answer cited as `[number]`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Source {
+    pub number: usize,
+    #[serde(default)]
+    pub corpus: String,
+    pub path: String,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ConversationResponse {
     pub slug: Uuid,
     pub question: String,
+    /// The question rewritten to stand on its own using the earlier messages, used for retrieval
+    #[serde(default)]
+    pub standalone_question: Option<String>,
     pub context: Option<String>,
     pub answer: Option<String>,
+    #[serde(default)]
+    pub sources: Vec<Source>,
 }
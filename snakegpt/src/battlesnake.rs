@@ -0,0 +1,517 @@
+use std::{fmt, str::FromStr};
+
+use itertools::Itertools;
+use miette::miette;
+use serde::{Deserialize, Serialize};
+
+mod facts;
+mod render;
+mod rules;
+
+pub use facts::{board_facts, BoardFacts, MoveFacts, MoveOutcome};
+pub use render::render_board;
+pub use rules::{advance, Elimination, EliminationCause, Rules, TurnResult, SNAKE_MAX_HEALTH};
+
+/// The widest or tallest board we accept, the size of the largest official board
+pub const MAX_BOARD_SIZE: i32 = 25;
+
+/// What a snake gets in a `/move` request, the state of the game it is playing
+///
+/// Only `board` is required, so a board pasted on its own is understood too
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GameState {
+    #[serde(default)]
+    pub game: Game,
+    #[serde(default)]
+    pub turn: u32,
+    pub board: Board,
+    /// The snake the request was sent to
+    #[serde(default)]
+    pub you: Option<Snake>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Game {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub ruleset: Ruleset,
+    #[serde(default)]
+    pub map: String,
+    /// How long each snake has to respond, in milliseconds
+    #[serde(default)]
+    pub timeout: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Ruleset {
+    /// Like `standard`, `royale`, `constrictor`, `wrapped` or `solo`
+    pub name: String,
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub settings: RulesetSettings,
+}
+
+impl Default for Ruleset {
+    fn default() -> Self {
+        Self {
+            name: "standard".to_string(),
+            version: String::new(),
+            settings: RulesetSettings::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RulesetSettings {
+    /// Percent chance of new food spawning each turn
+    #[serde(default)]
+    pub food_spawn_chance: u32,
+    #[serde(default)]
+    pub minimum_food: u32,
+    /// Health lost for ending a turn in a hazard, on top of the usual one
+    #[serde(default = "default_hazard_damage")]
+    pub hazard_damage_per_turn: i32,
+    #[serde(default)]
+    pub royale: RoyaleSettings,
+}
+
+impl Default for RulesetSettings {
+    fn default() -> Self {
+        Self {
+            food_spawn_chance: 15,
+            minimum_food: 1,
+            hazard_damage_per_turn: default_hazard_damage(),
+            royale: RoyaleSettings::default(),
+        }
+    }
+}
+
+fn default_hazard_damage() -> i32 {
+    14
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoyaleSettings {
+    #[serde(default)]
+    pub shrink_every_n_turns: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Board {
+    pub height: i32,
+    pub width: i32,
+    #[serde(default)]
+    pub food: Vec<Coord>,
+    #[serde(default)]
+    pub hazards: Vec<Coord>,
+    pub snakes: Vec<Snake>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Snake {
+    pub id: String,
+    #[serde(default)]
+    pub name: String,
+    pub health: i32,
+    /// Head first
+    pub body: Vec<Coord>,
+    #[serde(default)]
+    pub squad: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub struct Coord {
+    pub x: i32,
+    pub y: i32,
+}
+
+/// `up` is towards larger `y`, the way the game draws the board
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Direction {
+    pub const ALL: [Direction; 4] = [
+        Direction::Up,
+        Direction::Down,
+        Direction::Left,
+        Direction::Right,
+    ];
+
+    fn offset(self) -> (i32, i32) {
+        match self {
+            Direction::Up => (0, 1),
+            Direction::Down => (0, -1),
+            Direction::Left => (-1, 0),
+            Direction::Right => (1, 0),
+        }
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Direction::Up => "up",
+            Direction::Down => "down",
+            Direction::Left => "left",
+            Direction::Right => "right",
+        })
+    }
+}
+
+impl FromStr for Direction {
+    type Err = miette::Report;
+
+    fn from_str(s: &str) -> miette::Result<Self> {
+        Direction::ALL
+            .into_iter()
+            .find(|direction| direction.to_string().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| miette!("Unknown move {s}, expected up, down, left or right"))
+    }
+}
+
+impl fmt::Display for Coord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({}, {})", self.x, self.y)
+    }
+}
+
+impl Snake {
+    pub fn head(&self) -> Coord {
+        self.body[0]
+    }
+
+    pub fn length(&self) -> usize {
+        self.body.len()
+    }
+
+    /// The name to show for the snake, its id when it has none
+    pub fn label(&self) -> &str {
+        if self.name.is_empty() {
+            &self.id
+        } else {
+            &self.name
+        }
+    }
+}
+
+impl Board {
+    pub fn contains(&self, coord: Coord) -> bool {
+        (0..self.width).contains(&coord.x) && (0..self.height).contains(&coord.y)
+    }
+
+    /// The square next to `coord`, wrapping around the edges when `wrapped`.
+    /// `None` when the move would leave the board
+    pub fn step(&self, coord: Coord, direction: Direction, wrapped: bool) -> Option<Coord> {
+        let (dx, dy) = direction.offset();
+        let next = Coord {
+            x: coord.x + dx,
+            y: coord.y + dy,
+        };
+
+        if wrapped {
+            Some(Coord {
+                x: next.x.rem_euclid(self.width),
+                y: next.y.rem_euclid(self.height),
+            })
+        } else {
+            self.contains(next).then_some(next)
+        }
+    }
+
+    /// How many moves it takes to get from `a` to `b`, ignoring anything in the way
+    pub fn distance(&self, a: Coord, b: Coord, wrapped: bool) -> i32 {
+        let dx = (a.x - b.x).abs();
+        let dy = (a.y - b.y).abs();
+
+        if wrapped {
+            dx.min(self.width - dx) + dy.min(self.height - dy)
+        } else {
+            dx + dy
+        }
+    }
+}
+
+impl GameState {
+    pub fn is_wrapped(&self) -> bool {
+        self.game.ruleset.name == "wrapped"
+    }
+
+    /// Whether this is a board we can make sense of, rather than JSON that just looks like one
+    ///
+    /// Boards larger than [MAX_BOARD_SIZE] are refused too, since drawing one or working out
+    /// the room around a move visits every square
+    fn is_valid(&self) -> bool {
+        let board = &self.board;
+        let snakes = || board.snakes.iter().chain(&self.you);
+
+        (1..=MAX_BOARD_SIZE).contains(&board.width)
+            && (1..=MAX_BOARD_SIZE).contains(&board.height)
+            && snakes().all(|snake| !snake.body.is_empty())
+            && snakes()
+                .flat_map(|snake| &snake.body)
+                .chain(&board.food)
+                .chain(&board.hazards)
+                .all(|coord| board.contains(*coord))
+    }
+
+    /// The snake we are answering for, as it appears on the board
+    pub fn you(&self) -> Option<&Snake> {
+        let you = self.you.as_ref()?;
+
+        Some(
+            self.board
+                .snakes
+                .iter()
+                .find(|snake| snake.id == you.id)
+                .unwrap_or(you),
+        )
+    }
+
+    /// The board drawn out with the facts about it, to put in a prompt
+    pub fn to_prompt(&self) -> String {
+        let mut prompt = format!(
+            "The question is about this Battlesnake game, turn {turn} of a {ruleset} game on a {width}x{height} board.\n\
+            The board is drawn with y going up, each snake is a letter, uppercase for its head.\n\
+            Food is *, hazards are ~ and food in a hazard is %\n\n{board}\n",
+            turn = self.turn,
+            ruleset = self.game.ruleset.name,
+            width = self.board.width,
+            height = self.board.height,
+            board = render_board(self),
+        );
+
+        prompt.push_str(&board_facts(self).to_prompt());
+
+        prompt
+    }
+}
+
+/// A game state found in a question
+#[derive(Clone, Debug)]
+pub struct PastedGameState<'a> {
+    pub state: GameState,
+    /// The rest of the question, without the JSON or a code fence around it
+    pub question: String,
+    /// The JSON as it was pasted
+    pub json: &'a str,
+}
+
+/// Used to search with when the question is nothing but a game state
+const DEFAULT_BOARD_QUESTION: &str = "What move should my snake make?";
+
+/// Finds the first Battlesnake game state pasted into the text, like a `/move` request body
+pub fn find_game_state(text: &str) -> Option<PastedGameState<'_>> {
+    text.match_indices('{').find_map(|(start, _)| {
+        let mut states = serde_json::Deserializer::from_str(&text[start..]).into_iter();
+        let state: GameState = states.next()?.ok()?;
+        if !state.is_valid() {
+            return None;
+        }
+
+        let end = start + states.byte_offset();
+        let question = format!("{}\n{}", &text[..start], &text[end..])
+            .lines()
+            .filter(|line| !line.trim().starts_with("```"))
+            .map(str::trim_end)
+            .join("\n")
+            .trim()
+            .to_string();
+
+        Some(PastedGameState {
+            state,
+            question: if question.is_empty() {
+                DEFAULT_BOARD_QUESTION.to_string()
+            } else {
+                question
+            },
+            json: &text[start..end],
+        })
+    })
+}
+
+/// Splits any game state out of a question
+///
+/// Returns the question to search the docs with, and when there was a game state, a
+/// description of the board to show the model in place of the JSON
+pub fn describe_game_state(question: &str) -> (String, Option<String>) {
+    match find_game_state(question) {
+        Some(pasted) => (pasted.question, Some(pasted.state.to_prompt())),
+        None => (question.to_string(), None),
+    }
+}
+
+/// A snake at full health, named after its id
+#[cfg(test)]
+fn test_snake(id: &str, body: &[(i32, i32)]) -> Snake {
+    Snake {
+        id: id.to_string(),
+        name: id.to_string(),
+        health: SNAKE_MAX_HEALTH,
+        body: body.iter().map(|&(x, y)| Coord { x, y }).collect(),
+        squad: String::new(),
+    }
+}
+
+/// A 7x7 game where the first snake is `you`, with no food spawning so turns stay predictable
+#[cfg(test)]
+fn test_game(rules: &str, snakes: Vec<Snake>) -> GameState {
+    let mut ruleset = Ruleset {
+        name: rules.to_string(),
+        ..Default::default()
+    };
+    ruleset.settings.food_spawn_chance = 0;
+    ruleset.settings.minimum_food = 0;
+
+    GameState {
+        game: Game {
+            ruleset,
+            ..Default::default()
+        },
+        turn: 10,
+        you: snakes.first().cloned(),
+        board: Board {
+            width: 7,
+            height: 7,
+            food: vec![],
+            hazards: vec![],
+            snakes,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOVE_BODY: &str = r##"{
+  "game": {"id": "g1", "ruleset": {"name": "wrapped", "version": "v1.2.3"}, "map": "standard", "timeout": 500},
+  "turn": 14,
+  "board": {
+    "height": 11,
+    "width": 11,
+    "food": [{"x": 5, "y": 5}],
+    "hazards": [],
+    "snakes": [
+      {"id": "snake-1", "name": "Mine", "health": 54, "body": [{"x": 0, "y": 0}, {"x": 1, "y": 0}, {"x": 2, "y": 0}], "latency": "111", "head": {"x": 0, "y": 0}, "length": 3, "shout": "", "squad": "", "customizations": {"color": "#FF0000"}}
+    ]
+  },
+  "you": {"id": "snake-1", "name": "Mine", "health": 54, "body": [{"x": 0, "y": 0}, {"x": 1, "y": 0}, {"x": 2, "y": 0}]}
+}"##;
+
+    #[test]
+    fn finds_a_move_body_in_a_code_fence() {
+        let text = format!("Why did my snake die here?\n```json\n{MOVE_BODY}\n```\nIt went left.");
+
+        let pasted = find_game_state(&text).unwrap();
+        assert_eq!(pasted.json, MOVE_BODY);
+        assert_eq!(
+            pasted
+                .question
+                .lines()
+                .filter(|line| !line.is_empty())
+                .collect_vec(),
+            vec!["Why did my snake die here?", "It went left."]
+        );
+        assert_eq!(pasted.state.turn, 14);
+        assert!(pasted.state.is_wrapped());
+        assert_eq!(pasted.state.you().unwrap().label(), "Mine");
+    }
+
+    #[test]
+    fn a_board_on_its_own_gets_the_default_question() {
+        let text = r#"{"height": 3, "width": 3, "snakes": [{"id": "a", "health": 90, "body": [{"x": 1, "y": 1}]}]}"#;
+        assert!(find_game_state(text).is_none());
+
+        let text = format!(r#"{{"board": {text}}}"#);
+        let pasted = find_game_state(&text).unwrap();
+        assert_eq!(pasted.question, DEFAULT_BOARD_QUESTION);
+        assert_eq!(pasted.state.game.ruleset.name, "standard");
+        assert!(pasted.state.you().is_none());
+    }
+
+    #[test]
+    fn skips_json_that_is_not_a_game_state() {
+        assert!(find_game_state(r#"My config is {"move": "up", "shout": "hi"}"#).is_none());
+        assert!(find_game_state(r#"{"board": {"width": 11, "height": 11}}"#).is_none());
+
+        let text = format!(r#"I return {{"move": "up"}} for {MOVE_BODY}"#);
+        assert_eq!(find_game_state(&text).unwrap().json, MOVE_BODY);
+    }
+
+    #[test]
+    fn rejects_boards_that_cannot_be_played() {
+        let empty_body = r#"{"board": {"width": 5, "height": 5, "snakes": [{"id": "a", "health": 90, "body": []}]}}"#;
+        assert!(find_game_state(empty_body).is_none());
+
+        let empty_you = r#"{"board": {"width": 5, "height": 5, "snakes": []}, "you": {"id": "a", "health": 90, "body": []}}"#;
+        assert!(find_game_state(empty_you).is_none());
+
+        let no_width = r#"{"board": {"width": 0, "height": 5, "snakes": []}}"#;
+        assert!(find_game_state(no_width).is_none());
+    }
+
+    #[test]
+    fn rejects_oversized_boards() {
+        let board = |size: i32| {
+            format!(
+                r#"{{"board": {{"width": {size}, "height": {size}, "snakes": [{{"id": "a", "health": 90, "body": [{{"x": 0, "y": 0}}]}}]}}}}"#
+            )
+        };
+
+        assert!(find_game_state(&board(MAX_BOARD_SIZE)).is_some());
+        assert!(find_game_state(&board(MAX_BOARD_SIZE + 1)).is_none());
+        assert!(find_game_state(&board(i32::MAX)).is_none());
+    }
+
+    #[test]
+    fn rejects_anything_off_the_board() {
+        let state = |snake: &str, food: &str, hazard: &str| {
+            format!(
+                r#"{{"board": {{"width": 5, "height": 5, "food": [{{"x": {food}, "y": 0}}], "hazards": [{{"x": {hazard}, "y": 0}}],
+                "snakes": [{{"id": "a", "health": 90, "body": [{{"x": {snake}, "y": 0}}]}}]}}}}"#
+            )
+        };
+
+        assert!(find_game_state(&state("4", "4", "4")).is_some());
+        assert!(find_game_state(&state("5", "4", "4")).is_none());
+        assert!(find_game_state(&state("4", "-1", "4")).is_none());
+        assert!(find_game_state(&state("4", "4", "5")).is_none());
+    }
+
+    #[test]
+    fn wrapped_distances_go_the_short_way_round() {
+        let board = Board {
+            height: 11,
+            width: 11,
+            food: vec![],
+            hazards: vec![],
+            snakes: vec![],
+        };
+        let a = Coord { x: 0, y: 1 };
+        let b = Coord { x: 10, y: 8 };
+
+        assert_eq!(board.distance(a, b, false), 17);
+        assert_eq!(board.distance(a, b, true), 1 + 4);
+        assert_eq!(board.distance(a, a, true), 0);
+
+        assert_eq!(board.step(a, Direction::Left, false), None);
+        assert_eq!(
+            board.step(a, Direction::Left, true),
+            Some(Coord { x: 10, y: 1 })
+        );
+        assert_eq!(
+            board.step(b, Direction::Up, true),
+            Some(Coord { x: 10, y: 9 })
+        );
+    }
+}
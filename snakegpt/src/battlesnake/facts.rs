@@ -0,0 +1,516 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt::Write as _,
+};
+
+use serde::Serialize;
+
+use super::{render::snake_letters, Board, Coord, Direction, GameState, Snake};
+
+/// What can be worked out from a game state without asking the model
+#[derive(Clone, Debug, Serialize)]
+pub struct BoardFacts {
+    pub snakes: Vec<SnakeFacts>,
+    /// Every move `you` could make, empty when the game state has no `you`
+    pub moves: Vec<MoveFacts>,
+    /// The closest food to your head and how many moves away it is
+    pub nearest_food: Option<(Coord, i32)>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SnakeFacts {
+    /// What the snake is drawn with in [super::render_board]
+    pub letter: char,
+    pub name: String,
+    pub you: bool,
+    pub length: usize,
+    pub health: i32,
+    pub head: Coord,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MoveFacts {
+    pub direction: Direction,
+    /// Where the head ends up, `None` when it would leave the board
+    pub to: Option<Coord>,
+    pub outcome: MoveOutcome,
+    /// Why the move is risky or deadly, and anything else worth knowing about it
+    pub notes: Vec<String>,
+    /// How many squares can be reached from `to`, a move into a smaller space than the
+    /// snake's length is likely a trap
+    pub room: usize,
+    /// Moves from `to` to the closest food
+    pub food_distance: Option<i32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MoveOutcome {
+    Safe,
+    /// Might get the snake eliminated, depending on what the others do
+    Risky,
+    /// Gets the snake eliminated whatever the others do
+    Deadly,
+}
+
+/// Works out which moves are safe for `you`, where the head-to-head risks are and how far
+/// away food is
+///
+/// This looks one turn ahead. A snake's tail moves out of the way unless the snake just ate,
+/// or the game is constrictor where snakes never shrink.
+pub fn board_facts(state: &GameState) -> BoardFacts {
+    let board = &state.board;
+    let wrapped = state.is_wrapped();
+    let you = state.you();
+
+    let snakes = board
+        .snakes
+        .iter()
+        .zip(snake_letters())
+        .map(|(snake, letter)| SnakeFacts {
+            letter,
+            name: snake.label().to_string(),
+            you: you.is_some_and(|you| you.id == snake.id),
+            length: snake.length(),
+            health: snake.health,
+            head: snake.head(),
+        })
+        .collect();
+
+    let Some(you) = you else {
+        return BoardFacts {
+            snakes,
+            moves: vec![],
+            nearest_food: None,
+        };
+    };
+
+    let blocked = blocked_squares(state);
+    let moves = Direction::ALL
+        .into_iter()
+        .map(|direction| move_facts(state, you, direction, &blocked))
+        .collect();
+
+    BoardFacts {
+        snakes,
+        moves,
+        nearest_food: board
+            .food
+            .iter()
+            .map(|food| (*food, board.distance(you.head(), *food, wrapped)))
+            .min_by_key(|(_, distance)| *distance),
+    }
+}
+
+/// Squares that will still have a snake on them after every snake moves
+fn blocked_squares(state: &GameState) -> HashSet<Coord> {
+    let constrictor = state.game.ruleset.name == "constrictor";
+
+    state
+        .board
+        .snakes
+        .iter()
+        .flat_map(|snake| {
+            let keep = if constrictor || tail_stays(snake) {
+                snake.body.len()
+            } else {
+                snake.body.len() - 1
+            };
+            snake.body[..keep].iter().copied()
+        })
+        .collect()
+}
+
+/// A snake that just ate has its tail doubled up, so the tail doesn't move next turn
+pub(super) fn tail_stays(snake: &Snake) -> bool {
+    let len = snake.body.len();
+    len < 2 || snake.body[len - 1] == snake.body[len - 2]
+}
+
+fn move_facts(
+    state: &GameState,
+    you: &Snake,
+    direction: Direction,
+    blocked: &HashSet<Coord>,
+) -> MoveFacts {
+    let board = &state.board;
+    let wrapped = state.is_wrapped();
+    let mut facts = MoveFacts {
+        direction,
+        to: None,
+        outcome: MoveOutcome::Safe,
+        notes: vec![],
+        room: 0,
+        food_distance: None,
+    };
+
+    let Some(to) = board.step(you.head(), direction, wrapped) else {
+        facts.outcome = MoveOutcome::Deadly;
+        facts.notes.push("leaves the board".to_string());
+        return facts;
+    };
+    facts.to = Some(to);
+
+    let eats = board.food.contains(&to);
+    if you
+        .body
+        .get(1)
+        .is_some_and(|neck| *neck == to && *neck != you.head())
+    {
+        flag(
+            &mut facts,
+            MoveOutcome::Deadly,
+            "turns back into your own neck".to_string(),
+        );
+    } else if let Some(owner) = board.snakes.iter().find(|snake| snake.body.contains(&to)) {
+        let whose = if owner.id == you.id {
+            "your own".to_string()
+        } else {
+            format!("{}'s", owner.label())
+        };
+        if blocked.contains(&to) {
+            flag(
+                &mut facts,
+                MoveOutcome::Deadly,
+                format!("runs into {whose} body"),
+            );
+        } else if owner.id != you.id && head_next_to_food(board, owner, wrapped) {
+            flag(
+                &mut facts,
+                MoveOutcome::Risky,
+                format!("{whose} tail is there and stays put if they eat this turn"),
+            );
+        }
+    }
+
+    for other in board.snakes.iter().filter(|snake| snake.id != you.id) {
+        if board.distance(other.head(), to, wrapped) != 1 {
+            continue;
+        }
+
+        let length = other.length();
+        match length.cmp(&you.length()) {
+            std::cmp::Ordering::Greater => flag(
+                &mut facts,
+                MoveOutcome::Risky,
+                format!(
+                    "{} (length {length}) could move there too and would win the head-to-head",
+                    other.label()
+                ),
+            ),
+            std::cmp::Ordering::Equal => flag(
+                &mut facts,
+                MoveOutcome::Risky,
+                format!(
+                    "{} (length {length}) could move there too and a head-to-head would eliminate you both",
+                    other.label()
+                ),
+            ),
+            std::cmp::Ordering::Less => facts.notes.push(format!(
+                "{} (length {length}) could move there too, but you would win the head-to-head",
+                other.label()
+            )),
+        }
+    }
+
+    let mut health = you.health - 1;
+    if board.hazards.contains(&to) && !eats {
+        let damage = state.game.ruleset.settings.hazard_damage_per_turn;
+        health -= damage;
+        flag(
+            &mut facts,
+            MoveOutcome::Risky,
+            format!("is in a hazard, costing {damage} extra health"),
+        );
+    }
+    if eats {
+        facts
+            .notes
+            .push("eats food, restoring health to 100".to_string());
+    } else if health <= 0 {
+        flag(
+            &mut facts,
+            MoveOutcome::Deadly,
+            "runs out of health".to_string(),
+        );
+    }
+
+    if facts.outcome != MoveOutcome::Deadly {
+        let room = room(board, to, blocked, wrapped);
+        facts.room = room;
+        if room < you.length() {
+            flag(
+                &mut facts,
+                MoveOutcome::Risky,
+                format!(
+                    "only {room} squares of room, fewer than your length of {}",
+                    you.length()
+                ),
+            );
+        }
+    }
+    facts.food_distance = board
+        .food
+        .iter()
+        .map(|food| board.distance(to, *food, wrapped))
+        .min();
+
+    facts
+}
+
+/// Notes something about a move that makes it at least as bad as `outcome`
+fn flag(facts: &mut MoveFacts, outcome: MoveOutcome, note: String) {
+    facts.outcome = facts.outcome.max(outcome);
+    facts.notes.push(note);
+}
+
+fn head_next_to_food(board: &Board, snake: &Snake, wrapped: bool) -> bool {
+    board
+        .food
+        .iter()
+        .any(|food| board.distance(snake.head(), *food, wrapped) == 1)
+}
+
+/// How many open squares can be reached from `start`, counting `start` itself
+fn room(board: &Board, start: Coord, blocked: &HashSet<Coord>, wrapped: bool) -> usize {
+    let mut seen = HashSet::from([start]);
+    let mut queue = VecDeque::from([start]);
+
+    while let Some(coord) = queue.pop_front() {
+        for direction in Direction::ALL {
+            let Some(next) = board.step(coord, direction, wrapped) else {
+                continue;
+            };
+            if !blocked.contains(&next) && seen.insert(next) {
+                queue.push_back(next);
+            }
+        }
+    }
+
+    seen.len()
+}
+
+impl BoardFacts {
+    pub fn to_prompt(&self) -> String {
+        let mut prompt = String::from("\nSnakes:\n");
+        for snake in &self.snakes {
+            let _ = writeln!(
+                prompt,
+                "- {letter} is {name}{you}: length {length}, health {health}, head at {head}",
+                letter = snake.letter,
+                name = snake.name,
+                you = if snake.you { " (you)" } else { "" },
+                length = snake.length,
+                health = snake.health,
+                head = snake.head,
+            );
+        }
+
+        if !self.moves.is_empty() {
+            prompt.push_str("\nYour moves, looking one turn ahead:\n");
+        }
+        for facts in &self.moves {
+            let outcome = match facts.outcome {
+                MoveOutcome::Safe => "safe",
+                MoveOutcome::Risky => "risky",
+                MoveOutcome::Deadly => "deadly",
+            };
+            let _ = write!(prompt, "- {}", facts.direction);
+            if let Some(to) = facts.to {
+                let _ = write!(prompt, " to {to}");
+            }
+            let _ = write!(prompt, ": {outcome}");
+            if !facts.notes.is_empty() {
+                let _ = write!(prompt, ", {}", facts.notes.join(", "));
+            }
+            if facts.outcome != MoveOutcome::Deadly {
+                let _ = write!(prompt, ". {} squares of room", facts.room);
+                if let Some(distance) = facts.food_distance {
+                    let _ = write!(prompt, ", food {distance} moves away");
+                }
+            }
+            prompt.push('\n');
+        }
+
+        if let Some((food, distance)) = self.nearest_food {
+            let _ = writeln!(
+                prompt,
+                "\nThe nearest food to your head is at {food}, {distance} moves away"
+            );
+        }
+
+        prompt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battlesnake::{test_game as game, test_snake as snake};
+
+    fn facts(state: &GameState, direction: Direction) -> MoveFacts {
+        board_facts(state)
+            .moves
+            .into_iter()
+            .find(|facts| facts.direction == direction)
+            .unwrap()
+    }
+
+    #[test]
+    fn walls_and_necks_are_deadly() {
+        let state = game("standard", vec![snake("you", &[(0, 0), (1, 0), (2, 0)])]);
+
+        let left = facts(&state, Direction::Left);
+        assert_eq!(left.outcome, MoveOutcome::Deadly);
+        assert_eq!(left.to, None);
+        assert_eq!(left.notes, vec!["leaves the board"]);
+        assert_eq!(
+            facts(&state, Direction::Right).notes,
+            vec!["turns back into your own neck"]
+        );
+
+        let up = facts(&state, Direction::Up);
+        assert_eq!(up.outcome, MoveOutcome::Safe);
+        assert_eq!(up.to, Some(Coord { x: 0, y: 1 }));
+    }
+
+    #[test]
+    fn tails_move_out_of_the_way_unless_they_stay() {
+        let you = snake("you", &[(3, 3), (3, 2), (2, 2), (2, 3)]);
+        let other = snake("b", &[(5, 5), (5, 4), (4, 4), (4, 3)]);
+        let state = game("standard", vec![you.clone(), other.clone()]);
+
+        assert_eq!(facts(&state, Direction::Left).outcome, MoveOutcome::Safe);
+        assert_eq!(facts(&state, Direction::Right).outcome, MoveOutcome::Safe);
+
+        // b just ate, so its tail is doubled up and stays put
+        let mut fed = other.clone();
+        fed.body.push(Coord { x: 4, y: 3 });
+        let state = game("standard", vec![you.clone(), fed]);
+        assert_eq!(
+            facts(&state, Direction::Right).notes,
+            vec!["runs into b's body"]
+        );
+
+        // Snakes never shrink in constrictor
+        let state = game("constrictor", vec![you, other]);
+        assert_eq!(
+            facts(&state, Direction::Left).notes,
+            vec!["runs into your own body"]
+        );
+        assert_eq!(facts(&state, Direction::Right).outcome, MoveOutcome::Deadly);
+    }
+
+    #[test]
+    fn head_to_heads_depend_on_length() {
+        let state = game(
+            "standard",
+            vec![
+                snake("you", &[(3, 3), (3, 2), (3, 1)]),
+                snake("big", &[(5, 3), (6, 3), (6, 2), (6, 1)]),
+                snake("twin", &[(1, 3), (0, 3), (0, 2)]),
+                snake("small", &[(3, 5), (3, 6)]),
+            ],
+        );
+
+        let right = facts(&state, Direction::Right);
+        assert_eq!(right.outcome, MoveOutcome::Risky);
+        assert_eq!(
+            right.notes,
+            vec!["big (length 4) could move there too and would win the head-to-head"]
+        );
+
+        let left = facts(&state, Direction::Left);
+        assert_eq!(left.outcome, MoveOutcome::Risky);
+        assert_eq!(
+            left.notes,
+            vec![
+                "twin (length 3) could move there too and a head-to-head would eliminate you both"
+            ]
+        );
+
+        let up = facts(&state, Direction::Up);
+        assert_eq!(up.outcome, MoveOutcome::Safe);
+        assert_eq!(
+            up.notes,
+            vec!["small (length 2) could move there too, but you would win the head-to-head"]
+        );
+    }
+
+    #[test]
+    fn food_hazards_and_health() {
+        let mut you = snake("you", &[(3, 3), (3, 2), (3, 1)]);
+        you.health = 1;
+        let mut state = game("standard", vec![you]);
+        state.board.food = vec![Coord { x: 3, y: 4 }];
+        state.board.hazards = vec![Coord { x: 4, y: 3 }];
+
+        let up = facts(&state, Direction::Up);
+        assert_eq!(up.outcome, MoveOutcome::Safe);
+        assert_eq!(up.notes, vec!["eats food, restoring health to 100"]);
+        assert_eq!(up.food_distance, Some(0));
+
+        let left = facts(&state, Direction::Left);
+        assert_eq!(left.outcome, MoveOutcome::Deadly);
+        assert_eq!(left.notes, vec!["runs out of health"]);
+
+        let right = facts(&state, Direction::Right);
+        assert_eq!(right.outcome, MoveOutcome::Deadly);
+        assert_eq!(
+            right.notes,
+            vec![
+                "is in a hazard, costing 14 extra health",
+                "runs out of health"
+            ]
+        );
+
+        assert_eq!(
+            board_facts(&state).nearest_food,
+            Some((Coord { x: 3, y: 4 }, 1))
+        );
+    }
+
+    #[test]
+    fn small_spaces_are_risky() {
+        let mut state = game(
+            "standard",
+            vec![snake("you", &[(0, 1), (1, 1), (2, 1), (2, 2)])],
+        );
+        state.board.width = 3;
+        state.board.height = 3;
+
+        let down = facts(&state, Direction::Down);
+        assert_eq!(down.outcome, MoveOutcome::Risky);
+        assert_eq!(down.room, 3);
+        assert_eq!(
+            down.notes,
+            vec!["only 3 squares of room, fewer than your length of 4"]
+        );
+    }
+
+    #[test]
+    fn wrapped_boards_go_over_the_edge() {
+        let mut state = game("wrapped", vec![snake("you", &[(0, 3), (1, 3), (2, 3)])]);
+        state.board.food = vec![Coord { x: 6, y: 0 }];
+
+        let left = facts(&state, Direction::Left);
+        assert_eq!(left.outcome, MoveOutcome::Safe);
+        assert_eq!(left.to, Some(Coord { x: 6, y: 3 }));
+        assert_eq!(left.food_distance, Some(3));
+        assert_eq!(
+            board_facts(&state).nearest_food,
+            Some((Coord { x: 6, y: 0 }, 4))
+        );
+    }
+
+    #[test]
+    fn no_moves_without_you() {
+        let mut state = game("standard", vec![snake("a", &[(3, 3), (3, 2)])]);
+        state.you = None;
+
+        let facts = board_facts(&state);
+        assert!(facts.moves.is_empty());
+        assert_eq!(facts.snakes.len(), 1);
+        assert!(!facts.snakes[0].you);
+    }
+}
@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+
+use itertools::Itertools;
+
+use super::{Coord, GameState};
+
+/// Draws the board as text, top row first, with the coordinates along the edges
+///
+/// Each snake gets a letter in board order, uppercase for its head and lowercase for the rest
+/// of its body. Food is `*`, hazards are `~` and food in a hazard is `%`.
+pub fn render_board(state: &GameState) -> String {
+    let board = &state.board;
+
+    let mut cells: HashMap<Coord, char> = HashMap::new();
+    for coord in &board.hazards {
+        cells.insert(*coord, '~');
+    }
+    for coord in &board.food {
+        let cell = if cells.contains_key(coord) { '%' } else { '*' };
+        cells.insert(*coord, cell);
+    }
+    // Tails first so a head on top of another snake's tail shows up
+    for (snake, letter) in board.snakes.iter().zip(snake_letters()) {
+        for coord in snake.body.iter().skip(1).rev() {
+            cells.insert(*coord, letter.to_ascii_lowercase());
+        }
+    }
+    for (snake, letter) in board.snakes.iter().zip(snake_letters()) {
+        cells.insert(snake.head(), letter);
+    }
+
+    let label_width = (board.height - 1).to_string().len();
+    let mut rows = (0..board.height)
+        .rev()
+        .map(|y| {
+            let row = (0..board.width)
+                .map(|x| cells.get(&Coord { x, y }).copied().unwrap_or('.'))
+                .join(" ");
+            format!("{y:>label_width$} {row}")
+        })
+        .collect_vec();
+
+    // Only the last digit of x fits under each column
+    rows.push(format!(
+        "{:label_width$} {}",
+        "",
+        (0..board.width).map(|x| x % 10).join(" ")
+    ));
+
+    rows.join("\n")
+}
+
+/// The letter each snake is drawn with, in board order
+pub(super) fn snake_letters() -> impl Iterator<Item = char> {
+    ('A'..='Z').cycle()
+}
@@ -0,0 +1,796 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use itertools::Itertools;
+use miette::{miette, Result};
+use serde::Serialize;
+
+use super::{facts::tail_stays, Coord, Direction, GameState, Snake, MAX_BOARD_SIZE};
+
+/// Health a snake starts with and gets back by eating
+pub const SNAKE_MAX_HEALTH: i32 = 100;
+
+/// The rulesets [advance] knows how to play
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Rules {
+    Standard,
+    /// Standard, with a hazard zone that closes in over the game
+    Royale,
+    /// Snakes grow every turn and never starve, there is no food
+    Constrictor,
+    /// Moving off one edge of the board comes back on the opposite edge
+    Wrapped,
+    /// A single snake playing for as long as it can survive
+    Solo,
+}
+
+impl Rules {
+    pub const ALL: [Rules; 5] = [
+        Rules::Standard,
+        Rules::Royale,
+        Rules::Constrictor,
+        Rules::Wrapped,
+        Rules::Solo,
+    ];
+}
+
+impl fmt::Display for Rules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Rules::Standard => "standard",
+            Rules::Royale => "royale",
+            Rules::Constrictor => "constrictor",
+            Rules::Wrapped => "wrapped",
+            Rules::Solo => "solo",
+        })
+    }
+}
+
+impl FromStr for Rules {
+    type Err = miette::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Rules::ALL
+            .into_iter()
+            .find(|rules| rules.to_string() == s)
+            .ok_or_else(|| {
+                miette!(
+                    "Unknown ruleset {s}, expected one of: {}",
+                    Rules::ALL.map(|rules| rules.to_string()).join(", ")
+                )
+            })
+    }
+}
+
+/// What happened over one turn
+#[derive(Clone, Debug, Serialize)]
+pub struct TurnResult {
+    pub rules: Rules,
+    /// The game after the turn, without the snakes that were eliminated
+    pub state: GameState,
+    /// The move each snake made, including the ones that weren't given a move
+    pub moves: Vec<(String, Direction)>,
+    pub eliminations: Vec<Elimination>,
+    /// Snakes that ate, and where
+    pub fed: Vec<(String, Coord)>,
+    pub game_over: bool,
+    /// The last snake standing, when the game ended with one
+    pub winner: Option<String>,
+    /// Parts of the turn that are random and weren't simulated
+    pub notes: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Elimination {
+    /// The eliminated snake's id, names aren't unique
+    pub id: String,
+    /// The name to show for the snake
+    pub snake: String,
+    pub cause: EliminationCause,
+    /// The other snake involved in a collision
+    pub by: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EliminationCause {
+    OutOfHealth,
+    WallCollision,
+    SelfCollision,
+    SnakeCollision,
+    HeadCollision,
+}
+
+impl fmt::Display for Elimination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let by = self.by.as_deref().unwrap_or("another snake");
+        match self.cause {
+            EliminationCause::OutOfHealth => write!(f, "{} ran out of health", self.snake),
+            EliminationCause::WallCollision => write!(f, "{} moved off the board", self.snake),
+            EliminationCause::SelfCollision => {
+                write!(f, "{} ran into its own body", self.snake)
+            }
+            EliminationCause::SnakeCollision => {
+                write!(f, "{} ran into {by}'s body", self.snake)
+            }
+            EliminationCause::HeadCollision => {
+                write!(f, "{} lost a head-to-head with {by}", self.snake)
+            }
+        }
+    }
+}
+
+/// Plays one turn of the game's ruleset, following the official rules
+///
+/// `moves` pairs a snake's id or name with its move. A snake without a move keeps going the
+/// way it was heading, or up on its first turn, like a snake that timed out. Each turn
+/// snakes move, lose a point of health plus any hazard damage, eat, and then are eliminated
+/// for running out of health, leaving the board or colliding. Food spawning and the royale
+/// hazard zone are random, so they are left out and mentioned in the notes.
+pub fn advance(state: &GameState, moves: &[(String, Direction)]) -> Result<TurnResult> {
+    let rules: Rules = state.game.ruleset.name.parse()?;
+    if !state.is_valid() {
+        return Err(miette!(
+            "The board needs a width and height between 1 and {MAX_BOARD_SIZE}, every snake needs a body, and everything has to be on the board"
+        ));
+    }
+    let wrapped = rules == Rules::Wrapped;
+    let board = &state.board;
+
+    let mut requested: HashMap<&str, Direction> = HashMap::new();
+    for (name, direction) in moves {
+        let snake = board
+            .snakes
+            .iter()
+            .find(|snake| snake.id == *name)
+            .or_else(|| board.snakes.iter().find(|snake| snake.name == *name))
+            .ok_or_else(|| {
+                miette!(
+                    "There is no snake called {name}, expected one of: {}",
+                    board.snakes.iter().map(Snake::label).join(", ")
+                )
+            })?;
+        requested.insert(&snake.id, *direction);
+    }
+
+    let mut snakes = board.snakes.clone();
+    let mut food = board.food.clone();
+    let mut applied = vec![];
+
+    for snake in &mut snakes {
+        let direction = requested
+            .get(snake.id.as_str())
+            .copied()
+            .unwrap_or_else(|| heading(state, snake, wrapped));
+        applied.push((snake.label().to_string(), direction));
+
+        let (dx, dy) = direction.offset();
+        let mut head = Coord {
+            x: snake.head().x + dx,
+            y: snake.head().y + dy,
+        };
+        if wrapped {
+            head.x = head.x.rem_euclid(board.width);
+            head.y = head.y.rem_euclid(board.height);
+        }
+        snake.body.pop();
+        snake.body.insert(0, head);
+        snake.health -= 1;
+    }
+
+    // Stacked hazards hurt once for each layer, food in a hazard cancels the damage
+    let damage = state.game.ruleset.settings.hazard_damage_per_turn;
+    for snake in &mut snakes {
+        if food.contains(&snake.head()) {
+            continue;
+        }
+        let layers = board
+            .hazards
+            .iter()
+            .filter(|hazard| **hazard == snake.head())
+            .count() as i32;
+        snake.health = (snake.health - damage * layers).max(0);
+    }
+
+    let mut fed = vec![];
+    food.retain(|food| {
+        let mut eaten = false;
+        for snake in snakes.iter_mut().filter(|snake| snake.head() == *food) {
+            snake.health = SNAKE_MAX_HEALTH;
+            snake.body.push(*snake.body.last().unwrap());
+            fed.push((snake.label().to_string(), *food));
+            eaten = true;
+        }
+        !eaten
+    });
+
+    let eliminations = eliminate(state, &snakes);
+    snakes.retain(|snake| !eliminations.iter().any(|e| e.id == snake.id));
+
+    if rules == Rules::Constrictor {
+        food.clear();
+        for snake in &mut snakes {
+            snake.health = SNAKE_MAX_HEALTH;
+            if !tail_stays(snake) {
+                snake.body.push(*snake.body.last().unwrap());
+            }
+        }
+    }
+
+    let mut next = state.clone();
+    next.turn += 1;
+    next.board.food = food;
+    next.you = state
+        .you
+        .as_ref()
+        .and_then(|you| snakes.iter().find(|snake| snake.id == you.id).cloned());
+    next.board.snakes = snakes;
+
+    let game_over = match rules {
+        Rules::Solo => next.board.snakes.is_empty(),
+        _ => next.board.snakes.len() <= 1,
+    };
+    let winner = (game_over && rules != Rules::Solo && next.board.snakes.len() == 1)
+        .then(|| next.board.snakes[0].label().to_string());
+
+    Ok(TurnResult {
+        rules,
+        notes: random_parts(&next, rules),
+        state: next,
+        moves: applied,
+        eliminations,
+        fed,
+        game_over,
+        winner,
+    })
+}
+
+/// The way the snake is heading, from its neck to its head
+fn heading(state: &GameState, snake: &Snake, wrapped: bool) -> Direction {
+    let Some(neck) = snake.body.get(1).filter(|neck| **neck != snake.head()) else {
+        return Direction::Up;
+    };
+
+    Direction::ALL
+        .into_iter()
+        .find(|direction| state.board.step(*neck, *direction, wrapped) == Some(snake.head()))
+        .unwrap_or(Direction::Up)
+}
+
+/// Works out which snakes are eliminated after they've moved and eaten
+///
+/// Running out of health and leaving the board are checked first, and those snakes are out
+/// of the way before collisions are checked. Collisions are all checked against the same
+/// positions, so two snakes can eliminate each other.
+fn eliminate(state: &GameState, snakes: &[Snake]) -> Vec<Elimination> {
+    let board = &state.board;
+    let mut eliminations = vec![];
+
+    for snake in snakes {
+        let cause = if snake.health <= 0 {
+            EliminationCause::OutOfHealth
+        } else if !board.contains(snake.head()) {
+            EliminationCause::WallCollision
+        } else {
+            continue;
+        };
+
+        eliminations.push(Elimination {
+            id: snake.id.clone(),
+            snake: snake.label().to_string(),
+            cause,
+            by: None,
+        });
+    }
+
+    let remaining = snakes
+        .iter()
+        .filter(|snake| !eliminations.iter().any(|e| e.id == snake.id))
+        .collect_vec();
+
+    let mut collisions = vec![];
+    for snake in &remaining {
+        let head = snake.head();
+
+        let collision = if snake.body[1..].contains(&head) {
+            Some((EliminationCause::SelfCollision, None))
+        } else if let Some(other) = remaining
+            .iter()
+            .find(|other| other.id != snake.id && other.body[1..].contains(&head))
+        {
+            Some((EliminationCause::SnakeCollision, Some(other)))
+        } else {
+            remaining
+                .iter()
+                .find(|other| {
+                    other.id != snake.id && other.head() == head && snake.length() <= other.length()
+                })
+                .map(|other| (EliminationCause::HeadCollision, Some(other)))
+        };
+
+        if let Some((cause, by)) = collision {
+            collisions.push(Elimination {
+                id: snake.id.clone(),
+                snake: snake.label().to_string(),
+                cause,
+                by: by.map(|other| other.label().to_string()),
+            });
+        }
+    }
+    eliminations.extend(collisions);
+
+    eliminations
+}
+
+fn random_parts(state: &GameState, rules: Rules) -> Vec<String> {
+    let settings = &state.game.ruleset.settings;
+    let mut notes = vec![];
+
+    if rules != Rules::Constrictor
+        && (settings.food_spawn_chance > 0
+            || state.board.food.len() < settings.minimum_food as usize)
+    {
+        notes.push(format!(
+            "New food may spawn at random, a {}% chance each turn with at least {} on the board",
+            settings.food_spawn_chance, settings.minimum_food
+        ));
+    }
+
+    let shrink_every = settings.royale.shrink_every_n_turns;
+    if rules == Rules::Royale && shrink_every > 0 && state.turn.is_multiple_of(shrink_every) {
+        notes.push(
+            "The hazard zone grows in from a random side this turn, the hazards shown are from \
+            before it grew"
+                .to_string(),
+        );
+    }
+
+    notes
+}
+
+impl TurnResult {
+    /// What happened, followed by the board after the turn, to show the model
+    pub fn to_prompt(&self) -> String {
+        let mut prompt = format!(
+            "Turn {} with {} rules, moves: {}\n",
+            self.state.turn - 1,
+            self.rules,
+            self.moves
+                .iter()
+                .map(|(snake, direction)| format!("{snake} {direction}"))
+                .join(", ")
+        );
+
+        for (snake, food) in &self.fed {
+            prompt.push_str(&format!("{snake} ate the food at {food}\n"));
+        }
+        if self.eliminations.is_empty() {
+            prompt.push_str("No snakes were eliminated\n");
+        }
+        for elimination in &self.eliminations {
+            prompt.push_str(&format!("{elimination}\n"));
+        }
+        if self.game_over {
+            match &self.winner {
+                Some(winner) => prompt.push_str(&format!("The game is over, {winner} wins\n")),
+                None => prompt.push_str("The game is over with no snakes left\n"),
+            }
+        }
+        for note in &self.notes {
+            prompt.push_str(&format!("{note}\n"));
+        }
+
+        prompt.push_str(&format!(
+            "\nThe board after the turn:\n{}\n",
+            super::render_board(&self.state)
+        ));
+        for snake in &self.state.board.snakes {
+            prompt.push_str(&format!(
+                "{}: length {}, health {}, head at {}\n",
+                snake.label(),
+                snake.length(),
+                snake.health,
+                snake.head()
+            ));
+        }
+
+        prompt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::battlesnake::{test_game as game, test_snake as snake};
+
+    fn coords(coords: &[(i32, i32)]) -> Vec<Coord> {
+        coords.iter().map(|&(x, y)| Coord { x, y }).collect()
+    }
+
+    fn moves(moves: &[(&str, Direction)]) -> Vec<(String, Direction)> {
+        moves
+            .iter()
+            .map(|(snake, direction)| (snake.to_string(), *direction))
+            .collect()
+    }
+
+    fn eliminations(result: &TurnResult) -> Vec<(&str, EliminationCause, Option<&str>)> {
+        result
+            .eliminations
+            .iter()
+            .map(|e| (e.id.as_str(), e.cause, e.by.as_deref()))
+            .collect()
+    }
+
+    fn remaining(result: &TurnResult) -> Vec<&str> {
+        result
+            .state
+            .board
+            .snakes
+            .iter()
+            .map(|snake| snake.id.as_str())
+            .collect()
+    }
+
+    fn find<'a>(result: &'a TurnResult, id: &str) -> &'a Snake {
+        result
+            .state
+            .board
+            .snakes
+            .iter()
+            .find(|snake| snake.id == id)
+            .unwrap()
+    }
+
+    #[test]
+    fn equal_length_head_to_head_eliminates_both() {
+        let state = game(
+            "standard",
+            vec![
+                snake("a", &[(2, 3), (1, 3), (0, 3)]),
+                snake("b", &[(4, 3), (5, 3), (6, 3)]),
+            ],
+        );
+
+        let result = advance(
+            &state,
+            &moves(&[("a", Direction::Right), ("b", Direction::Left)]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            eliminations(&result),
+            [
+                ("a", EliminationCause::HeadCollision, Some("b")),
+                ("b", EliminationCause::HeadCollision, Some("a")),
+            ]
+        );
+        assert!(result.game_over);
+        assert_eq!(result.winner, None);
+    }
+
+    #[test]
+    fn longer_snake_wins_head_to_head() {
+        let state = game(
+            "standard",
+            vec![
+                snake("a", &[(2, 3), (1, 3), (0, 3)]),
+                snake("b", &[(4, 3), (5, 3), (6, 3), (6, 2)]),
+            ],
+        );
+
+        let result = advance(
+            &state,
+            &moves(&[("a", Direction::Right), ("b", Direction::Left)]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            eliminations(&result),
+            [("a", EliminationCause::HeadCollision, Some("b"))]
+        );
+        assert_eq!(remaining(&result), ["b"]);
+        assert!(result.game_over);
+        assert_eq!(result.winner.as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn running_into_another_body_eliminates_only_the_mover() {
+        let state = game(
+            "standard",
+            vec![
+                snake("a", &[(2, 2), (2, 1), (2, 0)]),
+                snake("b", &[(3, 4), (3, 3), (2, 3), (1, 3)]),
+                snake("c", &[(6, 6), (6, 5)]),
+            ],
+        );
+
+        let result = advance(
+            &state,
+            &moves(&[
+                ("a", Direction::Up),
+                ("b", Direction::Up),
+                ("c", Direction::Left),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            eliminations(&result),
+            [("a", EliminationCause::SnakeCollision, Some("b"))]
+        );
+        assert_eq!(remaining(&result), ["b", "c"]);
+        assert!(!result.game_over);
+    }
+
+    #[test]
+    fn moving_into_a_tail_that_moves_away_is_safe() {
+        let state = game(
+            "standard",
+            vec![
+                snake("a", &[(2, 2), (2, 1), (3, 1), (3, 2)]),
+                snake("b", &[(5, 5), (5, 4)]),
+            ],
+        );
+
+        let result = advance(&state, &moves(&[("a", Direction::Right)])).unwrap();
+
+        assert!(result.eliminations.is_empty());
+        assert_eq!(find(&result, "a").head(), Coord { x: 3, y: 2 });
+    }
+
+    #[test]
+    fn self_collision() {
+        let state = game(
+            "standard",
+            vec![
+                snake("a", &[(2, 2), (2, 1), (3, 1), (3, 2), (3, 3)]),
+                snake("b", &[(5, 5), (5, 4)]),
+            ],
+        );
+
+        let result = advance(&state, &moves(&[("a", Direction::Right)])).unwrap();
+
+        assert_eq!(
+            eliminations(&result),
+            [("a", EliminationCause::SelfCollision, None)]
+        );
+    }
+
+    #[test]
+    fn leaving_the_board() {
+        let state = game(
+            "standard",
+            vec![
+                snake("a", &[(0, 3), (1, 3), (2, 3)]),
+                snake("b", &[(5, 5), (5, 4)]),
+            ],
+        );
+
+        let result = advance(&state, &moves(&[("a", Direction::Left)])).unwrap();
+
+        assert_eq!(
+            eliminations(&result),
+            [("a", EliminationCause::WallCollision, None)]
+        );
+        assert_eq!(result.winner.as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn starvation() {
+        let state = game(
+            "standard",
+            vec![
+                Snake {
+                    health: 1,
+                    ..snake("a", &[(2, 2), (2, 1)])
+                },
+                Snake {
+                    health: 2,
+                    ..snake("b", &[(5, 2), (5, 1)])
+                },
+            ],
+        );
+
+        let result = advance(&state, &[]).unwrap();
+
+        assert_eq!(
+            eliminations(&result),
+            [("a", EliminationCause::OutOfHealth, None)]
+        );
+        assert_eq!(find(&result, "b").health, 1);
+    }
+
+    #[test]
+    fn eating_restores_health_and_grows_the_snake() {
+        let mut state = game(
+            "standard",
+            vec![
+                Snake {
+                    health: 30,
+                    ..snake("a", &[(2, 2), (2, 1), (2, 0)])
+                },
+                snake("b", &[(5, 5), (5, 4)]),
+            ],
+        );
+        state.board.food = coords(&[(2, 3), (0, 0)]);
+
+        let result = advance(&state, &moves(&[("a", Direction::Up)])).unwrap();
+
+        let a = find(&result, "a");
+        assert_eq!(a.health, SNAKE_MAX_HEALTH);
+        // The new tail is stacked on the old one, so the snake is a square longer next turn
+        assert_eq!(a.body, coords(&[(2, 3), (2, 2), (2, 1), (2, 1)]));
+        assert_eq!(result.fed, [("a".to_string(), Coord { x: 2, y: 3 })]);
+        assert_eq!(result.state.board.food, coords(&[(0, 0)]));
+    }
+
+    #[test]
+    fn snakes_without_a_move_keep_going_straight() {
+        let state = game(
+            "standard",
+            vec![
+                snake("a", &[(2, 2), (1, 2)]),
+                snake("b", &[(5, 5), (5, 5), (5, 5)]),
+            ],
+        );
+
+        let result = advance(&state, &[]).unwrap();
+
+        assert_eq!(
+            result.moves,
+            [
+                ("a".to_string(), Direction::Right),
+                ("b".to_string(), Direction::Up),
+            ]
+        );
+    }
+
+    #[test]
+    fn royale_hazards_stack_and_food_cancels_them() {
+        let mut state = game(
+            "royale",
+            vec![
+                Snake {
+                    health: 50,
+                    ..snake("a", &[(1, 1), (1, 2)])
+                },
+                Snake {
+                    health: 50,
+                    ..snake("b", &[(5, 5), (5, 4)])
+                },
+                Snake {
+                    health: 10,
+                    ..snake("c", &[(3, 5), (3, 4)])
+                },
+            ],
+        );
+        state.game.ruleset.settings.royale.shrink_every_n_turns = 11;
+        state.board.hazards = coords(&[(1, 0), (1, 0), (5, 6), (3, 6)]);
+        state.board.food = coords(&[(5, 6)]);
+
+        let result = advance(&state, &[]).unwrap();
+
+        assert_eq!(find(&result, "a").health, 50 - 1 - 2 * 14);
+        assert_eq!(find(&result, "b").health, SNAKE_MAX_HEALTH);
+        assert_eq!(
+            eliminations(&result),
+            [("c", EliminationCause::OutOfHealth, None)]
+        );
+        // Turn 11 is when the zone grows, which is random and only noted
+        assert_eq!(result.notes.len(), 1);
+    }
+
+    #[test]
+    fn constrictor_snakes_grow_every_turn_and_food_is_cleared() {
+        let mut state = game(
+            "constrictor",
+            vec![
+                Snake {
+                    health: 5,
+                    ..snake("a", &[(2, 2), (2, 1), (2, 1)])
+                },
+                snake("b", &[(5, 5), (5, 4), (5, 4)]),
+            ],
+        );
+        state.board.food = coords(&[(0, 0)]);
+
+        let result = advance(&state, &moves(&[("a", Direction::Up)])).unwrap();
+
+        let a = find(&result, "a");
+        assert_eq!(a.body, coords(&[(2, 3), (2, 2), (2, 1), (2, 1)]));
+        assert_eq!(a.health, SNAKE_MAX_HEALTH);
+        assert!(result.state.board.food.is_empty());
+        assert!(result.notes.is_empty());
+    }
+
+    #[test]
+    fn constrictor_tails_never_move_out_of_the_way() {
+        let state = game(
+            "constrictor",
+            vec![
+                snake("a", &[(2, 2), (2, 1), (3, 1), (3, 2), (3, 2)]),
+                snake("b", &[(5, 5), (5, 4), (5, 4)]),
+            ],
+        );
+
+        let result = advance(&state, &moves(&[("a", Direction::Right)])).unwrap();
+
+        assert_eq!(
+            eliminations(&result),
+            [("a", EliminationCause::SelfCollision, None)]
+        );
+    }
+
+    #[test]
+    fn wrapped_snakes_cross_the_edges() {
+        let state = game(
+            "wrapped",
+            vec![snake("a", &[(0, 3), (1, 3)]), snake("b", &[(4, 6), (4, 5)])],
+        );
+
+        let result = advance(&state, &moves(&[("a", Direction::Left)])).unwrap();
+
+        assert!(result.eliminations.is_empty());
+        assert_eq!(find(&result, "a").head(), Coord { x: 6, y: 3 });
+        assert_eq!(find(&result, "b").head(), Coord { x: 4, y: 0 });
+    }
+
+    #[test]
+    fn solo_games_only_end_when_the_snake_is_eliminated() {
+        let state = game("solo", vec![snake("a", &[(3, 3), (3, 2), (3, 1)])]);
+
+        let result = advance(&state, &[]).unwrap();
+        assert!(!result.game_over);
+
+        let result = advance(&state, &moves(&[("a", Direction::Down)])).unwrap();
+        assert_eq!(
+            eliminations(&result),
+            [("a", EliminationCause::SelfCollision, None)]
+        );
+        assert!(result.game_over);
+        assert_eq!(result.winner, None);
+    }
+
+    #[test]
+    fn standard_games_end_with_one_snake_left() {
+        let state = game("standard", vec![snake("a", &[(3, 3), (3, 2)])]);
+
+        let result = advance(&state, &[]).unwrap();
+
+        assert!(result.game_over);
+        assert_eq!(result.winner.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn snakes_sharing_a_name_are_eliminated_by_id() {
+        let mut state = game(
+            "standard",
+            vec![snake("a", &[(0, 3), (1, 3)]), snake("b", &[(5, 3), (5, 2)])],
+        );
+        for snake in &mut state.board.snakes {
+            snake.name = "twin".to_string();
+        }
+
+        let result = advance(&state, &moves(&[("a", Direction::Left)])).unwrap();
+
+        assert_eq!(
+            eliminations(&result),
+            [("a", EliminationCause::WallCollision, None)]
+        );
+        assert_eq!(remaining(&result), ["b"]);
+    }
+
+    #[test]
+    fn rejects_unknown_snakes_rulesets_and_empty_boards() {
+        let state = game("standard", vec![snake("a", &[(3, 3), (3, 2)])]);
+        assert!(advance(&state, &moves(&[("nobody", Direction::Up)])).is_err());
+
+        let state = game("squads", vec![snake("a", &[(3, 3), (3, 2)])]);
+        assert!(advance(&state, &[]).is_err());
+
+        let mut state = game("wrapped", vec![snake("a", &[(3, 3), (3, 2)])]);
+        state.board.width = 0;
+        assert!(advance(&state, &[]).is_err());
+    }
+}
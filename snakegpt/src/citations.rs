@@ -0,0 +1,101 @@
+use shared::Source;
+
+use crate::context::Context;
+
+/// Finds every citation marker like `[2]` or `[1, 3]` in the answer
+///
+/// Numbers are returned in the order they first appear, without duplicates
+pub fn parse_citations(answer: &str) -> Vec<usize> {
+    let mut numbers = vec![];
+
+    for (start, _) in answer.match_indices('[') {
+        let rest = &answer[start + 1..];
+        let Some(end) = rest.find(']') else {
+            continue;
+        };
+
+        let parsed: Option<Vec<usize>> = rest[..end]
+            .split(',')
+            .map(|n| n.trim().parse().ok())
+            .collect();
+
+        for n in parsed.into_iter().flatten() {
+            if !numbers.contains(&n) {
+                numbers.push(n);
+            }
+        }
+    }
+
+    numbers
+}
+
+/// Resolves the citations in the answer against the context it was generated from
+///
+/// Citations that point outside the retrieved chunks are dropped
+pub fn cited_sources(answer: &str, context: &Context) -> Vec<Source> {
+    parse_citations(answer)
+        .into_iter()
+        .filter_map(|number| {
+            context.chunk(number).map(|chunk| Source {
+                number,
+                corpus: chunk.corpus.clone(),
+                path: chunk.page_path.clone(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::ContextChunk;
+
+    fn context(pages: &[&str]) -> Context {
+        Context {
+            chunks: pages
+                .iter()
+                .map(|page| ContextChunk {
+                    corpus: "official".to_string(),
+                    page_path: page.to_string(),
+                    text: String::new(),
+                    distance: 0.0,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn parses_adjacent_markers() {
+        assert_eq!(parse_citations("Snakes move [1][2]."), vec![1, 2]);
+    }
+
+    #[test]
+    fn parses_lists() {
+        assert_eq!(
+            parse_citations("Food heals [1, 3] and [2,4]"),
+            vec![1, 3, 2, 4]
+        );
+    }
+
+    #[test]
+    fn keeps_the_first_appearance_of_duplicates() {
+        assert_eq!(parse_citations("[2] then [1] and [2, 1]"), vec![2, 1]);
+    }
+
+    #[test]
+    fn ignores_brackets_that_are_not_citations() {
+        assert_eq!(
+            parse_citations("Use [link](url), [1, x], [] and [0.5] but [3"),
+            Vec::<usize>::new()
+        );
+    }
+
+    #[test]
+    fn drops_citations_outside_the_context() {
+        let sources = cited_sources("See [0], [2] and [5]", &context(&["a.md", "b.md"]));
+
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources[0].number, 2);
+        assert_eq!(sources[0].path, "b.md");
+    }
+}
@@ -0,0 +1,66 @@
+use itertools::Itertools;
+use miette::{miette, Result};
+
+use crate::{battlesnake::find_game_state, openai::Client, CompletionRequest, PromptName, Prompts};
+
+/// An earlier question in the conversation and the answer it got
+#[derive(Clone, Debug)]
+pub struct Exchange {
+    pub question: String,
+    pub answer: String,
+}
+
+/// Rewrites a follow-up question into one that makes sense without the conversation
+///
+/// Retrieval only sees a single query, so something like "what about in wrapped mode?" needs
+/// the earlier questions folded into it before it is worth embedding.
+/// With no history the question is returned as is. A pasted game state is left out of the
+/// rewrite and added back afterwards, so the model can't mangle it.
+pub async fn condense_question(
+    client: &Client,
+    prompts: &Prompts,
+    history: &[Exchange],
+    question: &str,
+) -> Result<String> {
+    if history.is_empty() {
+        return Ok(question.to_owned());
+    }
+
+    let pasted = find_game_state(question);
+    let question = pasted
+        .as_ref()
+        .map_or(question, |pasted| pasted.question.as_str());
+
+    let history = history
+        .iter()
+        .map(|exchange| {
+            format!(
+                "User: {}\nAssistant: {}",
+                exchange.question.trim(),
+                exchange.answer.trim()
+            )
+        })
+        .join("\n\n");
+
+    let prompt = prompts.render(
+        PromptName::Condense,
+        &[("history", &history), ("question", question)],
+    );
+
+    let response = client
+        .completion(CompletionRequest::gpt_3_5_turbo(&prompt))
+        .await?;
+    let standalone = response
+        .choices
+        .first()
+        .ok_or_else(|| miette!("Condensing the question got no choices back"))?
+        .message
+        .content
+        .trim()
+        .to_owned();
+
+    Ok(match pasted {
+        Some(pasted) => format!("{standalone}\n\n{}", pasted.json),
+        None => standalone,
+    })
+}
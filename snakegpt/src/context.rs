@@ -0,0 +1,45 @@
+use itertools::Itertools;
+use serde::Serialize;
+
+/// A window of sentences pulled from a single page around a search hit
+#[derive(Clone, Debug, Serialize)]
+pub struct ContextChunk {
+    pub corpus: String,
+    pub page_path: String,
+    pub text: String,
+    pub distance: f64,
+}
+
+/// The retrieved context for a question, in the order it is shown to the model
+///
+/// Chunks are numbered starting from 1 so the model can cite them like `[2]`
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Context {
+    pub chunks: Vec<ContextChunk>,
+}
+
+impl Context {
+    pub fn new(chunks: Vec<ContextChunk>) -> Self {
+        Self { chunks }
+    }
+
+    /// Looks up a chunk by the 1-based number it was given in the prompt
+    pub fn chunk(&self, number: usize) -> Option<&ContextChunk> {
+        number.checked_sub(1).and_then(|i| self.chunks.get(i))
+    }
+
+    pub fn to_prompt(&self) -> String {
+        self.chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                format!(
+                    "[{number}] (from {path})\n{text}",
+                    number = i + 1,
+                    path = chunk.page_path,
+                    text = chunk.text.trim().replace("\n\n", "\n")
+                )
+            })
+            .join("\n\n")
+    }
+}
@@ -0,0 +1,93 @@
+use std::sync::{Arc, Mutex};
+
+use miette::{miette, Result};
+use shared::CorpusSelection;
+
+use crate::{index::VectorIndex, paths::StoragePaths, setup, EmbeddingConnection};
+
+pub const DEFAULT_CORPUS: &str = "sample";
+
+/// A named set of ingested docs, like the community docs or the rules repo
+#[derive(Clone, Debug)]
+pub struct Corpus {
+    pub name: String,
+    pub conn: EmbeddingConnection,
+    pub index: Arc<dyn VectorIndex>,
+    /// Search distances from this corpus are divided by its weight,
+    /// so anything above 1.0 ranks it higher than the others
+    pub weight: f64,
+}
+
+impl Corpus {
+    pub fn open(paths: &StoragePaths, selection: &CorpusSelection) -> Result<Self> {
+        let (conn, index) = setup(paths, &selection.name)?;
+
+        Ok(Self {
+            name: selection.name.clone(),
+            conn: EmbeddingConnection(Arc::new(Mutex::new(conn))),
+            index,
+            weight: selected_weight(selection)?.unwrap_or(1.0),
+        })
+    }
+}
+
+/// The weight a selection overrides, checked again here because selections from JSON
+/// never went through [CorpusSelection]'s `FromStr`
+fn selected_weight(selection: &CorpusSelection) -> Result<Option<f64>> {
+    match selection.weight {
+        Some(weight) if !CorpusSelection::valid_weight(weight) => Err(miette!(
+            help = "Weights have to be a number above 0, like docs:2 or rules:0.5",
+            "Invalid weight for corpus {name}: {weight}",
+            name = selection.name
+        )),
+        weight => Ok(weight),
+    }
+}
+
+/// Every corpus loaded by the process
+#[derive(Clone, Debug)]
+pub struct Corpora(pub Vec<Corpus>);
+
+impl Corpora {
+    pub fn open(paths: &StoragePaths, selections: &[CorpusSelection]) -> Result<Self> {
+        selections
+            .iter()
+            .map(|selection| Corpus::open(paths, selection))
+            .collect::<Result<Vec<_>>>()
+            .map(Self)
+    }
+
+    /// Picks the corpora a query should search, applying any weight overrides.
+    /// An empty selection searches everything with the default weights
+    pub fn select(&self, selections: &[CorpusSelection]) -> Result<Vec<Corpus>> {
+        if selections.is_empty() {
+            return Ok(self.0.clone());
+        }
+
+        selections
+            .iter()
+            .map(|selection| {
+                let corpus = self
+                    .0
+                    .iter()
+                    .find(|corpus| corpus.name == selection.name)
+                    .ok_or_else(|| {
+                        miette!(
+                            "Unknown corpus {name}, expected one of: {names}",
+                            name = selection.name,
+                            names = self.names().join(", ")
+                        )
+                    })?;
+
+                Ok(Corpus {
+                    weight: selected_weight(selection)?.unwrap_or(corpus.weight),
+                    ..corpus.clone()
+                })
+            })
+            .collect()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.0.iter().map(|corpus| corpus.name.as_str()).collect()
+    }
+}
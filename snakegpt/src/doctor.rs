@@ -0,0 +1,309 @@
+use miette::{IntoDiagnostic, Result};
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+use crate::{
+    embedding::{decode_embedding, encode_embedding},
+    fetch_embedding,
+    index::VectorIndex,
+    metadata::EmbeddingSettings,
+    openai::Client,
+};
+
+/// How many examples of each problem to keep for the report
+const SAMPLE_SIZE: usize = 5;
+
+/// Everything that is out of sync between `pages`, `sentences` and the vector index
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Diagnosis {
+    /// Sentences that never got an embedding, usually from a `prepare` run that failed part way.
+    /// Re-running `prepare` skips them since their text is already stored.
+    pub missing_embeddings: Finding,
+    /// Sentences whose embedding can't be read or doesn't have the DB's dimension
+    pub invalid_embeddings: Finding,
+    /// Sentences pointing at a page that doesn't exist
+    pub orphan_sentences: Finding,
+    /// Pages that were never split into sentences
+    pub unparsed_pages: Finding,
+    pub embedded_sentences: usize,
+    pub indexed_sentences: usize,
+}
+
+/// How many rows have a problem, and a few of them to go look at
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Finding {
+    /// The rowids of every affected row
+    pub rowids: Vec<i64>,
+    /// A short description of the first few affected rows
+    pub samples: Vec<String>,
+}
+
+impl Finding {
+    pub fn count(&self) -> usize {
+        self.rowids.len()
+    }
+
+    fn push(&mut self, rowid: i64, sample: impl FnOnce() -> String) {
+        if self.samples.len() < SAMPLE_SIZE {
+            self.samples.push(sample());
+        }
+        self.rowids.push(rowid);
+    }
+}
+
+impl Diagnosis {
+    pub fn index_in_sync(&self) -> bool {
+        self.indexed_sentences == self.embedded_sentences
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.missing_embeddings.count() == 0
+            && self.invalid_embeddings.count() == 0
+            && self.orphan_sentences.count() == 0
+            && self.unparsed_pages.count() == 0
+            && self.index_in_sync()
+    }
+}
+
+/// Checks the DB and its index agree with each other, without changing anything
+pub fn diagnose(conn: &Connection, index: &dyn VectorIndex) -> Result<Diagnosis> {
+    let settings = EmbeddingSettings::load(conn)?;
+    let mut diagnosis = Diagnosis::default();
+
+    let mut stmt = conn
+        .prepare(
+            "select sentences.rowid, sentences.text, sentences.embedding, pages.rowid is null
+            from sentences
+            left join pages on pages.rowid = sentences.page_id",
+        )
+        .into_diagnostic()?;
+    let mut rows = stmt.query(()).into_diagnostic()?;
+
+    while let Some(row) = rows.next().into_diagnostic()? {
+        let rowid: i64 = row.get(0).into_diagnostic()?;
+        let text: String = row.get(1).into_diagnostic()?;
+        let embedding: Option<Vec<u8>> = row.get(2).into_diagnostic()?;
+        let orphan: bool = row.get(3).into_diagnostic()?;
+
+        if orphan {
+            diagnosis
+                .orphan_sentences
+                .push(rowid, || sentence_sample(rowid, &text));
+        }
+
+        match embedding {
+            None => diagnosis
+                .missing_embeddings
+                .push(rowid, || sentence_sample(rowid, &text)),
+            Some(blob) => {
+                diagnosis.embedded_sentences += 1;
+
+                match decode_embedding(&blob) {
+                    Ok(embedding) if embedding.len() == settings.dimension => {}
+                    Ok(embedding) => diagnosis.invalid_embeddings.push(rowid, || {
+                        format!(
+                            "{} ({} dimensions)",
+                            sentence_sample(rowid, &text),
+                            embedding.len()
+                        )
+                    }),
+                    Err(e) => diagnosis
+                        .invalid_embeddings
+                        .push(rowid, || format!("{} ({e})", sentence_sample(rowid, &text))),
+                }
+            }
+        }
+    }
+
+    let mut stmt = conn
+        .prepare("select rowid, path from pages where parsed_text is null")
+        .into_diagnostic()?;
+    let mut rows = stmt.query(()).into_diagnostic()?;
+    while let Some(row) = rows.next().into_diagnostic()? {
+        let rowid: i64 = row.get(0).into_diagnostic()?;
+        let path: String = row.get(1).into_diagnostic()?;
+        diagnosis.unparsed_pages.push(rowid, || path);
+    }
+
+    diagnosis.indexed_sentences = index.count(conn)?;
+
+    Ok(diagnosis)
+}
+
+/// Deletes every sentence whose page no longer exists
+pub fn delete_orphans(
+    conn: &Connection,
+    index: &dyn VectorIndex,
+    diagnosis: &Diagnosis,
+) -> Result<usize> {
+    for rowid in &diagnosis.orphan_sentences.rowids {
+        index.remove(conn, *rowid)?;
+        conn.execute("delete from sentences where rowid = ?1", params![rowid])
+            .into_diagnostic()?;
+    }
+
+    Ok(diagnosis.orphan_sentences.count())
+}
+
+/// Embeds a sentence again, replacing whatever embedding it had before
+pub async fn reembed_sentence(
+    conn: &Connection,
+    index: &dyn VectorIndex,
+    client: &Client,
+    settings: &EmbeddingSettings,
+    rowid: i64,
+) -> Result<()> {
+    let text: String = conn
+        .query_row(
+            "select text from sentences where rowid = ?1",
+            params![rowid],
+            |row| row.get(0),
+        )
+        .into_diagnostic()?;
+
+    let embedding = fetch_embedding(client, settings, &text).await?;
+
+    index.remove(conn, rowid)?;
+    conn.execute(
+        "update sentences set embedding = ?1 where rowid = ?2",
+        params![encode_embedding(&embedding, settings.quantization), rowid],
+    )
+    .into_diagnostic()?;
+    index.insert(conn, rowid)
+}
+
+fn sentence_sample(rowid: i64, text: &str) -> String {
+    const MAX_CHARS: usize = 80;
+
+    let text = text.trim().replace('\n', " ");
+    if text.chars().count() > MAX_CHARS {
+        let truncated: String = text.chars().take(MAX_CHARS).collect();
+        format!("#{rowid} {truncated}...")
+    } else {
+        format!("#{rowid} {text}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{embedding::Quantization, index::BruteForceIndex, schema::migrate};
+
+    fn db() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        EmbeddingSettings {
+            dimension: 2,
+            ..Default::default()
+        }
+        .save(&conn)
+        .unwrap();
+        conn.execute_batch(
+            "insert into pages (path, parsed_text) values ('rules/food.md', 'Eat food');
+            insert into pages (path, parsed_text) values ('rules/moves.md', null);",
+        )
+        .unwrap();
+        conn
+    }
+
+    fn sentence(conn: &Connection, page_id: i64, text: &str, embedding: Option<Vec<u8>>) {
+        conn.execute(
+            "insert into sentences (page_id, page_index, text, embedding) values (?1, 0, ?2, ?3)",
+            params![page_id, text, embedding],
+        )
+        .unwrap();
+    }
+
+    fn embedding(values: &[f64]) -> Option<Vec<u8>> {
+        Some(encode_embedding(values, Quantization::F32))
+    }
+
+    #[test]
+    fn a_consistent_db_is_healthy() {
+        let conn = db();
+        sentence(&conn, 1, "Eat food", embedding(&[1.0, 0.0]));
+        conn.execute("update pages set parsed_text = 'Moves' where rowid = 2", ())
+            .unwrap();
+        let index = BruteForceIndex::load(&conn).unwrap();
+
+        let diagnosis = diagnose(&conn, &index).unwrap();
+        assert!(diagnosis.is_healthy());
+        assert_eq!(diagnosis.embedded_sentences, 1);
+        assert_eq!(diagnosis.indexed_sentences, 1);
+    }
+
+    #[test]
+    fn finds_missing_invalid_and_orphan_rows() {
+        let conn = db();
+        sentence(&conn, 1, "Eat food", embedding(&[1.0, 0.0]));
+        sentence(&conn, 1, "Not embedded yet", None);
+        sentence(&conn, 1, "Cut off", Some(vec![1, 2]));
+        sentence(&conn, 1, "Wrong model", embedding(&[1.0, 0.0, 0.0]));
+        sentence(&conn, 9, "Page was deleted", embedding(&[0.0, 1.0]));
+        sentence(&conn, 9, "Page was deleted before embedding", None);
+        let index = BruteForceIndex::load(&conn).unwrap();
+
+        let diagnosis = diagnose(&conn, &index).unwrap();
+        assert_eq!(diagnosis.missing_embeddings.rowids, vec![2, 6]);
+        assert_eq!(diagnosis.invalid_embeddings.rowids, vec![3, 4]);
+        assert_eq!(
+            diagnosis.invalid_embeddings.samples,
+            vec![
+                "#3 Cut off (Embedding blob is 2 bytes which is not a whole number of f32s)",
+                "#4 Wrong model (3 dimensions)"
+            ]
+        );
+        assert_eq!(diagnosis.orphan_sentences.rowids, vec![5, 6]);
+        assert_eq!(diagnosis.unparsed_pages.rowids, vec![2]);
+        assert_eq!(diagnosis.unparsed_pages.samples, vec!["rules/moves.md"]);
+        assert_eq!(diagnosis.embedded_sentences, 4);
+        // The undecodable embedding was left out of the index
+        assert_eq!(diagnosis.indexed_sentences, 3);
+        assert!(!diagnosis.is_healthy());
+    }
+
+    #[test]
+    fn deletes_only_the_orphans() {
+        let conn = db();
+        sentence(&conn, 1, "Eat food", embedding(&[1.0, 0.0]));
+        sentence(&conn, 1, "Not embedded yet", None);
+        sentence(&conn, 9, "Page was deleted", embedding(&[0.0, 1.0]));
+        sentence(&conn, 9, "Page was deleted before embedding", None);
+        let index = BruteForceIndex::load(&conn).unwrap();
+
+        let diagnosis = diagnose(&conn, &index).unwrap();
+        assert_eq!(delete_orphans(&conn, &index, &diagnosis).unwrap(), 2);
+
+        let remaining: Vec<i64> = conn
+            .prepare("select rowid from sentences order by rowid")
+            .unwrap()
+            .query_map((), |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(remaining, vec![1, 2]);
+        assert_eq!(index.count(&conn).unwrap(), 1);
+
+        let diagnosis = diagnose(&conn, &index).unwrap();
+        assert_eq!(diagnosis.orphan_sentences.count(), 0);
+        assert!(diagnosis.index_in_sync());
+    }
+
+    #[test]
+    fn keeps_a_few_short_samples() {
+        let conn = db();
+        for i in 0..8 {
+            sentence(&conn, 1, &format!("Sentence {i}"), None);
+        }
+        sentence(&conn, 1, &"long ".repeat(30), None);
+        let index = BruteForceIndex::load(&conn).unwrap();
+
+        let missing = diagnose(&conn, &index).unwrap().missing_embeddings;
+        assert_eq!(missing.count(), 9);
+        assert_eq!(missing.samples.len(), SAMPLE_SIZE);
+        assert_eq!(
+            sentence_sample(9, &"long ".repeat(30)),
+            format!("#9 {}...", &"long ".repeat(16)[..80])
+        );
+    }
+}
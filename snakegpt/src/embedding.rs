@@ -0,0 +1,327 @@
+use std::{fmt, str::FromStr};
+
+use miette::{miette, Result};
+use serde::Serialize;
+
+/// sqlite-vector's `vector_to_blob` format starts with these two bytes, followed by the
+/// little-endian f32s. Writing the same layout keeps the blobs readable by vss0.
+const VECTOR_BLOB_HEADER: [u8; 2] = [b'v', 1];
+/// Followed by the f32 scale and then one i8 per dimension
+const INT8_BLOB_HEADER: [u8; 2] = [b'q', 8];
+/// Followed by the dimension as a u32 and then one bit per dimension
+const BINARY_BLOB_HEADER: [u8; 2] = [b'q', 1];
+
+/// How embeddings are stored in `sentences.embedding`
+///
+/// Quantizing shrinks the DB, and the in memory brute force index, by 4x for int8 and 32x for
+/// binary. Searches rescore the best matches at full precision to win back most of the accuracy.
+/// Only f32 embeddings can be indexed by sqlite-vss.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Quantization {
+    /// Full precision, 4 bytes per dimension
+    #[default]
+    F32,
+    /// 1 byte per dimension
+    Int8,
+    /// 1 bit per dimension
+    Binary,
+}
+
+impl Quantization {
+    /// Bits stored per dimension, converting only ever goes towards fewer
+    fn bits(self) -> usize {
+        match self {
+            Quantization::F32 => 32,
+            Quantization::Int8 => 8,
+            Quantization::Binary => 1,
+        }
+    }
+
+    /// Whether embeddings stored like this can be converted to `to` without re-embedding
+    pub fn can_convert_to(self, to: Quantization) -> bool {
+        to.bits() <= self.bits()
+    }
+}
+
+impl fmt::Display for Quantization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Quantization::F32 => "f32",
+            Quantization::Int8 => "int8",
+            Quantization::Binary => "binary",
+        })
+    }
+}
+
+impl FromStr for Quantization {
+    type Err = miette::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "f32" => Ok(Quantization::F32),
+            "int8" => Ok(Quantization::Int8),
+            "binary" => Ok(Quantization::Binary),
+            _ => Err(miette!(
+                "Unknown quantization {s}, expected one of: f32, int8, binary"
+            )),
+        }
+    }
+}
+
+pub fn encode_embedding(embedding: &[f64], quantization: Quantization) -> Vec<u8> {
+    let embedding: Vec<f32> = embedding.iter().map(|v| *v as f32).collect();
+
+    match quantization {
+        Quantization::F32 => {
+            let mut blob = Vec::with_capacity(VECTOR_BLOB_HEADER.len() + embedding.len() * 4);
+            blob.extend_from_slice(&VECTOR_BLOB_HEADER);
+            for value in embedding {
+                blob.extend_from_slice(&value.to_le_bytes());
+            }
+
+            blob
+        }
+        Quantization::Int8 => {
+            let (scale, values) = quantize_int8(&embedding);
+
+            let mut blob = Vec::with_capacity(INT8_BLOB_HEADER.len() + 4 + values.len());
+            blob.extend_from_slice(&INT8_BLOB_HEADER);
+            blob.extend_from_slice(&scale.to_le_bytes());
+            blob.extend(values.iter().map(|v| *v as u8));
+
+            blob
+        }
+        Quantization::Binary => {
+            let mut blob = Vec::with_capacity(BINARY_BLOB_HEADER.len() + 4 + embedding.len() / 8);
+            blob.extend_from_slice(&BINARY_BLOB_HEADER);
+            blob.extend_from_slice(&(embedding.len() as u32).to_le_bytes());
+            for chunk in embedding.chunks(8) {
+                let byte = chunk
+                    .iter()
+                    .enumerate()
+                    .fold(0u8, |byte, (bit, v)| byte | (u8::from(*v > 0.0) << bit));
+                blob.push(byte);
+            }
+
+            blob
+        }
+    }
+}
+
+/// Reads an embedding blob in any of the stored formats, undoing any quantization
+pub fn decode_embedding(blob: &[u8]) -> Result<Vec<f32>> {
+    Ok(match QuantizedEmbedding::from_blob(blob)? {
+        QuantizedEmbedding::F32(values) => values,
+        QuantizedEmbedding::Int8 { scale, values } => {
+            values.iter().map(|v| *v as f32 * scale).collect()
+        }
+        QuantizedEmbedding::Binary { dimension, bits } => (0..dimension)
+            .map(|i| if bit(&bits, i) { 1.0 } else { -1.0 })
+            .collect(),
+    })
+}
+
+/// An embedding kept in the format it was stored in
+#[derive(Clone, Debug)]
+pub(crate) enum QuantizedEmbedding {
+    F32(Vec<f32>),
+    Int8 { scale: f32, values: Vec<i8> },
+    Binary { dimension: usize, bits: Vec<u64> },
+}
+
+impl QuantizedEmbedding {
+    pub(crate) fn from_blob(blob: &[u8]) -> Result<Self> {
+        if let Some(data) = blob.strip_prefix(&INT8_BLOB_HEADER) {
+            if data.len() < 4 {
+                return Err(miette!("int8 embedding blob is missing its scale"));
+            }
+            let (scale, values) = data.split_at(4);
+
+            return Ok(Self::Int8 {
+                scale: f32::from_le_bytes([scale[0], scale[1], scale[2], scale[3]]),
+                values: values.iter().map(|v| *v as i8).collect(),
+            });
+        }
+
+        if let Some(data) = blob.strip_prefix(&BINARY_BLOB_HEADER) {
+            if data.len() < 4 {
+                return Err(miette!("Binary embedding blob is missing its dimension"));
+            }
+            let (dimension, bytes) = data.split_at(4);
+            let dimension =
+                u32::from_le_bytes([dimension[0], dimension[1], dimension[2], dimension[3]])
+                    as usize;
+            if bytes.len() != dimension.div_ceil(8) {
+                return Err(miette!(
+                    "Binary embedding blob has {} bytes of bits for {dimension} dimensions",
+                    bytes.len()
+                ));
+            }
+
+            return Ok(Self::Binary {
+                dimension,
+                bits: bytes
+                    .chunks(8)
+                    .map(|chunk| {
+                        let mut word = [0u8; 8];
+                        word[..chunk.len()].copy_from_slice(chunk);
+                        u64::from_le_bytes(word)
+                    })
+                    .collect(),
+            });
+        }
+
+        let data = match blob.strip_prefix(&VECTOR_BLOB_HEADER) {
+            Some(data) if data.len() % 4 == 0 => data,
+            _ => blob,
+        };
+
+        if data.len() % 4 != 0 {
+            return Err(miette!(
+                "Embedding blob is {} bytes which is not a whole number of f32s",
+                blob.len()
+            ));
+        }
+
+        Ok(Self::F32(
+            data.chunks_exact(4)
+                .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                .collect(),
+        ))
+    }
+}
+
+/// Scales the values so the largest one lands on 127
+pub(crate) fn quantize_int8(embedding: &[f32]) -> (f32, Vec<i8>) {
+    let max = embedding.iter().fold(0.0f32, |max, v| max.max(v.abs()));
+    let scale = if max > 0.0 { max / 127.0 } else { 1.0 };
+
+    let values = embedding
+        .iter()
+        .map(|v| (v / scale).round().clamp(-127.0, 127.0) as i8)
+        .collect();
+
+    (scale, values)
+}
+
+/// Packs the signs of the values into bits, the same layout [QuantizedEmbedding::Binary] uses
+pub(crate) fn quantize_binary(embedding: &[f32]) -> Vec<u64> {
+    embedding
+        .chunks(64)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0u64, |word, (bit, v)| word | (u64::from(*v > 0.0) << bit))
+        })
+        .collect()
+}
+
+pub(crate) fn bit(bits: &[u64], i: usize) -> bool {
+    bits[i / 64] & (1 << (i % 64)) != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn f32_embeddings_round_trip_exactly() {
+        let embedding = [0.5, -0.25, 1.0, 0.0];
+
+        let blob = encode_embedding(&embedding, Quantization::F32);
+        assert_eq!(blob[..2], VECTOR_BLOB_HEADER);
+        assert_eq!(blob.len(), 2 + 4 * 4);
+        assert_eq!(decode_embedding(&blob).unwrap(), vec![0.5, -0.25, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn reads_f32_blobs_without_a_header() {
+        let blob: Vec<u8> = [0.5f32, -2.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+
+        assert_eq!(decode_embedding(&blob).unwrap(), vec![0.5, -2.0]);
+    }
+
+    #[test]
+    fn int8_embeddings_round_trip_within_half_a_step() {
+        let embedding = [0.9, -0.45, 0.01, 0.0, -0.9];
+
+        let blob = encode_embedding(&embedding, Quantization::Int8);
+        assert_eq!(blob[..2], INT8_BLOB_HEADER);
+        assert_eq!(blob.len(), 2 + 4 + embedding.len());
+
+        let step = 0.9 / 127.0;
+        for (decoded, original) in decode_embedding(&blob).unwrap().iter().zip(embedding) {
+            assert!(
+                (*decoded as f64 - original).abs() <= step / 2.0 + 1e-6,
+                "{decoded} is too far from {original}"
+            );
+        }
+    }
+
+    #[test]
+    fn binary_embeddings_keep_the_signs() {
+        let embedding = [0.3, -0.1, 0.0, 2.0, -5.0, 0.1, 0.1, -0.1, 0.7, -0.7];
+
+        let blob = encode_embedding(&embedding, Quantization::Binary);
+        assert_eq!(blob[..2], BINARY_BLOB_HEADER);
+        assert_eq!(blob.len(), 2 + 4 + 2);
+        assert_eq!(
+            decode_embedding(&blob).unwrap(),
+            vec![1.0, -1.0, -1.0, 1.0, -1.0, 1.0, 1.0, -1.0, 1.0, -1.0]
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_and_unknown_blobs() {
+        // Just the int8 header, with no scale
+        assert!(QuantizedEmbedding::from_blob(&[b'q', 8, 0, 0]).is_err());
+        // 10 binary dimensions need 2 bytes of bits
+        assert!(QuantizedEmbedding::from_blob(&[b'q', 1, 10, 0, 0, 0, 0xff]).is_err());
+        assert!(QuantizedEmbedding::from_blob(&[b'q', 1, 10]).is_err());
+        // An f32 blob that lost its last byte
+        assert!(QuantizedEmbedding::from_blob(&[b'v', 1, 0, 0, 128]).is_err());
+        assert!(QuantizedEmbedding::from_blob(&[b'x', 2, 0, 0, 128]).is_err());
+    }
+
+    #[test]
+    fn int8_values_stay_in_range() {
+        let (scale, values) = quantize_int8(&[2.0, -2.0, 1.0, 0.004]);
+        assert_eq!(scale, 2.0 / 127.0);
+        assert_eq!(values, vec![127, -127, 64, 0]);
+
+        let (scale, values) = quantize_int8(&[0.0, 0.0]);
+        assert_eq!(scale, 1.0);
+        assert_eq!(values, vec![0, 0]);
+
+        let (_, values) = quantize_int8(&[f32::MAX, f32::MIN, 1.0]);
+        assert_eq!(values[..2], [127, -127]);
+    }
+
+    #[test]
+    fn binary_bits_fill_words_in_order() {
+        let mut embedding = vec![-1.0f32; 70];
+        embedding[0] = 1.0;
+        embedding[63] = 1.0;
+        embedding[64] = 0.5;
+        embedding[69] = 0.0;
+
+        let bits = quantize_binary(&embedding);
+        assert_eq!(bits, vec![1 | 1 << 63, 1]);
+        assert!(bit(&bits, 0) && bit(&bits, 63) && bit(&bits, 64));
+        assert!(!bit(&bits, 1) && !bit(&bits, 69));
+    }
+
+    #[test]
+    fn only_converts_to_smaller_formats() {
+        assert!(Quantization::F32.can_convert_to(Quantization::Int8));
+        assert!(Quantization::Int8.can_convert_to(Quantization::Binary));
+        assert!(Quantization::Int8.can_convert_to(Quantization::Int8));
+        assert!(!Quantization::Binary.can_convert_to(Quantization::Int8));
+        assert!(!Quantization::Int8.can_convert_to(Quantization::F32));
+    }
+}
@@ -0,0 +1,79 @@
+use std::path::Path;
+
+use miette::{miette, Context as _, IntoDiagnostic, Result};
+use serde::{Deserialize, Serialize};
+
+mod answers;
+mod retrieval;
+
+pub use answers::{
+    answer_report_markdown, evaluate_answers, AnswerConfig, AnswerEval, AnswerGrade, AnswerResult,
+    Judge,
+};
+pub use retrieval::{evaluate_retrieval, QuestionDiff, QuestionResult, RetrievalEval};
+
+/// A question with what we expect to get back for it, one per line of a golden set
+///
+/// Retrieval evals need `pages` or `passages`, answer evals need `answer`
+///
+/// ```json
+/// {"question": "How much health does food restore?", "pages": ["rules/food.md"], "passages": ["restores a snake's health"], "answer": "Eating food restores health to 100"}
+/// ```
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct GoldenQuestion {
+    pub question: String,
+    /// Pages that should be retrieved, matched against the end of each chunk's page path
+    #[serde(default)]
+    pub pages: Vec<String>,
+    /// Text that should appear in a retrieved chunk, ignoring case and whitespace
+    #[serde(default)]
+    pub passages: Vec<String>,
+    /// A correct answer to grade generated answers against
+    #[serde(default)]
+    pub answer: Option<String>,
+}
+
+impl GoldenQuestion {
+    /// How many separate things retrieval is expected to find
+    pub fn expected_count(&self) -> usize {
+        self.pages.len() + self.passages.len()
+    }
+}
+
+/// Reads a JSONL golden set, skipping blank lines
+pub fn load_golden_questions(path: &Path) -> Result<Vec<GoldenQuestion>> {
+    let contents = std::fs::read_to_string(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Could not read {}", path.display()))?;
+
+    let questions = contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let question: GoldenQuestion = serde_json::from_str(line).map_err(|e| {
+                miette!(
+                    "Line {} of {} is not a golden question: {e}",
+                    i + 1,
+                    path.display()
+                )
+            })?;
+            if question.expected_count() == 0 && question.answer.is_none() {
+                return Err(miette!(
+                    help = "Add the pages or passages retrieval should find, or a correct answer",
+                    "Line {} of {} doesn't expect anything",
+                    i + 1,
+                    path.display()
+                ));
+            }
+
+            Ok(question)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if questions.is_empty() {
+        return Err(miette!("{} has no questions", path.display()));
+    }
+
+    Ok(questions)
+}
@@ -0,0 +1,421 @@
+use std::fmt::Write as _;
+
+use futures::{stream, StreamExt};
+use miette::{miette, IntoDiagnostic, Result};
+use serde::{Deserialize, Serialize};
+
+use super::GoldenQuestion;
+use crate::{
+    answer_prompt,
+    context::Context,
+    corpus::Corpus,
+    openai::{completion::CompletionRequest, Client},
+    respond_with_prompt,
+    retrieval::{get_context_with_options, RetrievalOptions},
+    PromptName, Prompts, Source, CHAT_DEFAULT_MODEL, CONCURRENT_REQUESTS,
+};
+
+/// One way of answering questions, the thing an answer eval compares
+#[derive(Clone, Debug, Serialize)]
+pub struct AnswerConfig {
+    /// How the config is labelled in reports
+    pub name: String,
+    pub model: String,
+    /// Where the answer prompt comes from
+    #[serde(skip)]
+    pub prompts: Prompts,
+}
+
+/// The model that grades answers and where its prompt comes from
+#[derive(Clone, Debug)]
+pub struct Judge {
+    pub model: String,
+    pub prompts: Prompts,
+}
+
+impl Default for Judge {
+    fn default() -> Self {
+        Self {
+            model: CHAT_DEFAULT_MODEL.to_string(),
+            prompts: Prompts::default(),
+        }
+    }
+}
+
+/// The judge's verdict on one answer, both scores are from 1 to 5
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AnswerGrade {
+    pub correctness: u8,
+    pub faithfulness: u8,
+    pub reasoning: String,
+}
+
+/// How one config answered one golden question
+#[derive(Clone, Debug, Serialize)]
+pub struct AnswerResult {
+    pub question: String,
+    pub reference: String,
+    pub answer: Option<String>,
+    pub sources: Vec<Source>,
+    pub grade: Option<AnswerGrade>,
+    /// Why there is no answer or grade
+    pub error: Option<String>,
+}
+
+/// How one config did across a golden set
+#[derive(Clone, Debug, Serialize)]
+pub struct AnswerEval {
+    pub config: AnswerConfig,
+    /// Mean scores over the graded answers
+    pub correctness: f64,
+    pub faithfulness: f64,
+    pub graded: usize,
+    pub failed: usize,
+    pub questions: Vec<AnswerResult>,
+}
+
+/// Answers every golden question with each config and has the judge grade the answers
+///
+/// Context is retrieved once per question and shared by the configs, so any difference comes
+/// from the prompt or model. A failed answer or grade is recorded on the question rather than
+/// ending the eval.
+pub async fn evaluate_answers(
+    client: &Client,
+    questions: &[GoldenQuestion],
+    corpora: &[Corpus],
+    options: &RetrievalOptions,
+    configs: &[AnswerConfig],
+    judge: &Judge,
+) -> Result<Vec<AnswerEval>> {
+    if let Some(golden) = questions.iter().find(|golden| golden.answer.is_none()) {
+        return Err(miette!(
+            help = "Add the correct `answer` for the judge to grade against",
+            "\"{}\" has no answer to compare with",
+            golden.question
+        ));
+    }
+
+    let results = stream::iter(questions)
+        .map(|golden| async move {
+            let (context, question) =
+                get_context_with_options(golden.question.clone(), corpora, options).await?;
+
+            let mut results = Vec::with_capacity(configs.len());
+            for config in configs {
+                results.push(
+                    answer_and_grade(client, golden, &context, &question, config, judge).await,
+                );
+            }
+
+            Result::<_>::Ok(results)
+        })
+        .buffered(CONCURRENT_REQUESTS)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(configs
+        .iter()
+        .enumerate()
+        .map(|(i, config)| {
+            AnswerEval::new(
+                config.clone(),
+                results.iter().map(|results| results[i].clone()).collect(),
+            )
+        })
+        .collect())
+}
+
+impl AnswerEval {
+    fn new(config: AnswerConfig, questions: Vec<AnswerResult>) -> Self {
+        let grades: Vec<&AnswerGrade> = questions.iter().filter_map(|q| q.grade.as_ref()).collect();
+        let mean = |score: fn(&AnswerGrade) -> u8| {
+            if grades.is_empty() {
+                0.0
+            } else {
+                grades.iter().map(|grade| score(grade) as f64).sum::<f64>() / grades.len() as f64
+            }
+        };
+
+        Self {
+            config,
+            correctness: mean(|grade| grade.correctness),
+            faithfulness: mean(|grade| grade.faithfulness),
+            graded: grades.len(),
+            failed: questions.len() - grades.len(),
+            questions,
+        }
+    }
+}
+
+impl Judge {
+    pub async fn grade(
+        &self,
+        client: &Client,
+        question: &str,
+        reference: &str,
+        context: &Context,
+        answer: &str,
+    ) -> Result<AnswerGrade> {
+        let prompt = self.prompts.render(
+            PromptName::Judge,
+            &[
+                ("question", question),
+                ("reference", reference),
+                ("context", &context.to_prompt()),
+                ("answer", answer),
+            ],
+        );
+
+        let response = client
+            .completion(CompletionRequest::with_model(&prompt, &self.model))
+            .await?;
+        let verdict = &response
+            .choices
+            .first()
+            .ok_or_else(|| miette!("The judge got no choices back"))?
+            .message
+            .content;
+
+        parse_grade(verdict)
+    }
+}
+
+/// Reads the judge's JSON verdict out of its response
+fn parse_grade(verdict: &str) -> Result<AnswerGrade> {
+    // Models like to wrap JSON in prose or code fences
+    let json = match (verdict.find('{'), verdict.rfind('}')) {
+        (Some(start), Some(end)) if start < end => &verdict[start..=end],
+        _ => return Err(miette!("The judge didn't respond with JSON: {verdict}")),
+    };
+    let grade: AnswerGrade = serde_json::from_str(json).into_diagnostic()?;
+    for score in [grade.correctness, grade.faithfulness] {
+        if !(1..=5).contains(&score) {
+            return Err(miette!(
+                "The judge gave a score of {score}, expected 1 to 5"
+            ));
+        }
+    }
+
+    Ok(grade)
+}
+
+async fn answer_and_grade(
+    client: &Client,
+    golden: &GoldenQuestion,
+    context: &Context,
+    question: &str,
+    config: &AnswerConfig,
+    judge: &Judge,
+) -> AnswerResult {
+    let reference = golden.answer.clone().unwrap_or_default();
+    let mut result = AnswerResult {
+        question: golden.question.clone(),
+        reference,
+        answer: None,
+        sources: vec![],
+        grade: None,
+        error: None,
+    };
+
+    let prompt = answer_prompt(&config.prompts, context, question);
+    let answer = match respond_with_prompt(client, context, &prompt, &config.model).await {
+        Ok(answer) => answer,
+        Err(e) => {
+            result.error = Some(e.to_string());
+            return result;
+        }
+    };
+
+    match judge
+        .grade(client, question, &result.reference, context, &answer.text)
+        .await
+    {
+        Ok(grade) => result.grade = Some(grade),
+        Err(e) => result.error = Some(e.to_string()),
+    }
+    result.answer = Some(answer.text);
+    result.sources = answer.sources;
+
+    result
+}
+
+/// A markdown summary of the configs followed by every question's scores side by side
+pub fn answer_report_markdown(evals: &[AnswerEval], judge: &Judge) -> String {
+    let mut report = String::from("# Answer eval\n\n");
+    let _ = writeln!(report, "Graded by {}\n", judge.model);
+
+    report.push_str("| Config | Model | Correctness | Faithfulness | Graded | Failed |\n");
+    report.push_str("| --- | --- | --- | --- | --- | --- |\n");
+    for eval in evals {
+        let _ = writeln!(
+            report,
+            "| {} | {} | {:.2} | {:.2} | {} | {} |",
+            markdown_cell(&eval.config.name),
+            markdown_cell(&eval.config.model),
+            eval.correctness,
+            eval.faithfulness,
+            eval.graded,
+            eval.failed
+        );
+    }
+
+    report.push_str("\n## Questions\n\nScores are correctness / faithfulness\n\n| Question |");
+    for eval in evals {
+        let _ = write!(report, " {} |", markdown_cell(&eval.config.name));
+    }
+    report.push_str("\n| --- |");
+    for _ in evals {
+        report.push_str(" --- |");
+    }
+    report.push('\n');
+
+    let question_count = evals.first().map_or(0, |eval| eval.questions.len());
+    for i in 0..question_count {
+        let _ = write!(
+            report,
+            "| {} |",
+            markdown_cell(&evals[0].questions[i].question)
+        );
+        for eval in evals {
+            let result = &eval.questions[i];
+            let cell = match (&result.grade, &result.error) {
+                (Some(grade), _) => format!("{} / {}", grade.correctness, grade.faithfulness),
+                (None, Some(e)) => format!("failed: {}", markdown_cell(e)),
+                (None, None) => "-".to_string(),
+            };
+            let _ = write!(report, " {cell} |");
+        }
+        report.push('\n');
+    }
+
+    report
+}
+
+fn markdown_cell(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace('|', "\\|")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(question: &str, grade: Option<(u8, u8)>, error: Option<&str>) -> AnswerResult {
+        AnswerResult {
+            question: question.to_string(),
+            reference: "Reference".to_string(),
+            answer: grade.map(|_| "An answer".to_string()),
+            sources: vec![],
+            grade: grade.map(|(correctness, faithfulness)| AnswerGrade {
+                correctness,
+                faithfulness,
+                reasoning: String::new(),
+            }),
+            error: error.map(str::to_string),
+        }
+    }
+
+    fn config(name: &str, model: &str) -> AnswerConfig {
+        AnswerConfig {
+            name: name.to_string(),
+            model: model.to_string(),
+            prompts: Prompts::default(),
+        }
+    }
+
+    #[test]
+    fn reads_a_clean_verdict() {
+        let grade =
+            parse_grade(r#"{"correctness": 4, "faithfulness": 5, "reasoning": "Close enough"}"#)
+                .unwrap();
+
+        assert_eq!(grade.correctness, 4);
+        assert_eq!(grade.faithfulness, 5);
+        assert_eq!(grade.reasoning, "Close enough");
+    }
+
+    #[test]
+    fn reads_a_verdict_wrapped_in_prose() {
+        let grade = parse_grade(
+            "Here is my verdict:\n```json\n{\"correctness\": 2, \"faithfulness\": 3, \"reasoning\": \"Missed {the} point\"}\n```\nHope that helps!",
+        )
+        .unwrap();
+
+        assert_eq!(grade.correctness, 2);
+        assert_eq!(grade.faithfulness, 3);
+        assert_eq!(grade.reasoning, "Missed {the} point");
+    }
+
+    #[test]
+    fn rejects_malformed_verdicts() {
+        assert!(parse_grade("The answer is great, 5 out of 5").is_err());
+        assert!(parse_grade("} backwards {").is_err());
+        assert!(
+            parse_grade(r#"{"correctness": 4, "faithfulness": "high", "reasoning": ""}"#).is_err()
+        );
+        assert!(parse_grade(r#"{"correctness": 4, "reasoning": ""}"#).is_err());
+        assert!(parse_grade(r#"{"correctness": 0, "faithfulness": 3, "reasoning": ""}"#).is_err());
+        assert!(parse_grade(r#"{"correctness": 4, "faithfulness": 6, "reasoning": ""}"#).is_err());
+    }
+
+    #[test]
+    fn averages_only_the_graded_answers() {
+        let eval = AnswerEval::new(
+            config("baseline", "gpt"),
+            vec![
+                result("A", Some((4, 5)), None),
+                result("B", Some((2, 4)), None),
+                result("C", None, Some("timed out")),
+            ],
+        );
+
+        assert_eq!(eval.correctness, 3.0);
+        assert_eq!(eval.faithfulness, 4.5);
+        assert_eq!(eval.graded, 2);
+        assert_eq!(eval.failed, 1);
+    }
+
+    #[test]
+    fn writes_the_report() {
+        let evals = [
+            AnswerEval::new(
+                config("baseline", "gpt-3.5-turbo"),
+                vec![
+                    result("How much | health?", Some((5, 4)), None),
+                    result("Why did I\ndie?", None, Some("The judge | failed")),
+                ],
+            ),
+            AnswerEval::new(
+                config("terse", "gpt-4"),
+                vec![
+                    result("How much | health?", Some((3, 3)), None),
+                    result("Why did I\ndie?", None, None),
+                ],
+            ),
+        ];
+        let judge = Judge {
+            model: "gpt-4".to_string(),
+            prompts: Prompts::default(),
+        };
+
+        assert_eq!(
+            answer_report_markdown(&evals, &judge),
+            "# Answer eval\n\n\
+            Graded by gpt-4\n\n\
+            | Config | Model | Correctness | Faithfulness | Graded | Failed |\n\
+            | --- | --- | --- | --- | --- | --- |\n\
+            | baseline | gpt-3.5-turbo | 5.00 | 4.00 | 1 | 1 |\n\
+            | terse | gpt-4 | 3.00 | 3.00 | 1 | 1 |\n\
+            \n## Questions\n\n\
+            Scores are correctness / faithfulness\n\n\
+            | Question | baseline | terse |\n\
+            | --- | --- | --- |\n\
+            | How much \\| health? | 5 / 4 | 3 / 3 |\n\
+            | Why did I die? | failed: The judge \\| failed | - |\n"
+        );
+    }
+}
@@ -0,0 +1,325 @@
+use futures::{stream, StreamExt};
+use itertools::Itertools;
+use miette::{miette, Result};
+use serde::Serialize;
+
+use super::GoldenQuestion;
+use crate::{
+    context::ContextChunk,
+    corpus::Corpus,
+    retrieval::{get_context_with_options, RetrievalOptions},
+    CONCURRENT_REQUESTS,
+};
+
+/// How well retrieval did across a golden set, averaged over the questions
+#[derive(Clone, Debug, Serialize)]
+pub struct RetrievalEval {
+    /// How many chunks were retrieved for each question
+    pub k: usize,
+    pub recall: f64,
+    pub mrr: f64,
+    pub ndcg: f64,
+    pub questions: Vec<QuestionResult>,
+}
+
+/// How retrieval did for a single golden question
+#[derive(Clone, Debug, Serialize)]
+pub struct QuestionResult {
+    pub question: String,
+    /// `corpus: path` for each retrieved chunk, best first
+    pub retrieved: Vec<String>,
+    /// The 1-based ranks of the chunks that found something expected
+    pub hits: Vec<usize>,
+    /// Expected pages and passages that none of the chunks matched
+    pub missed: Vec<String>,
+    pub recall: f64,
+    pub reciprocal_rank: f64,
+    pub ndcg: f64,
+}
+
+/// A question that scored differently under two retrieval setups, as `(baseline, candidate)`
+#[derive(Clone, Debug, Serialize)]
+pub struct QuestionDiff {
+    pub question: String,
+    pub recall: (f64, f64),
+    pub reciprocal_rank: (f64, f64),
+    pub ndcg: (f64, f64),
+}
+
+/// Retrieves context for every golden question and scores it against what was expected
+///
+/// Metrics are computed at `options.limit`
+pub async fn evaluate_retrieval(
+    questions: &[GoldenQuestion],
+    corpora: &[Corpus],
+    options: &RetrievalOptions,
+) -> Result<RetrievalEval> {
+    if let Some(golden) = questions.iter().find(|golden| golden.expected_count() == 0) {
+        return Err(miette!(
+            help = "Add the pages or passages retrieval should find",
+            "\"{}\" doesn't say what retrieval should find",
+            golden.question
+        ));
+    }
+
+    let results = stream::iter(questions)
+        .map(|golden| async move {
+            let (context, _question) =
+                get_context_with_options(golden.question.clone(), corpora, options).await?;
+
+            Result::<_>::Ok(score(golden, &context.chunks, options.limit))
+        })
+        .buffered(CONCURRENT_REQUESTS)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(RetrievalEval::new(options.limit, results))
+}
+
+impl RetrievalEval {
+    fn new(k: usize, questions: Vec<QuestionResult>) -> Self {
+        let mean = |metric: fn(&QuestionResult) -> f64| {
+            if questions.is_empty() {
+                0.0
+            } else {
+                questions.iter().map(metric).sum::<f64>() / questions.len() as f64
+            }
+        };
+
+        Self {
+            k,
+            recall: mean(|q| q.recall),
+            mrr: mean(|q| q.reciprocal_rank),
+            ndcg: mean(|q| q.ndcg),
+            questions,
+        }
+    }
+
+    /// The questions whose scores changed in `other`
+    ///
+    /// Both evals have to come from the same golden set, questions are matched up by position
+    pub fn diff(&self, other: &RetrievalEval) -> Vec<QuestionDiff> {
+        const EPSILON: f64 = 1e-9;
+
+        self.questions
+            .iter()
+            .zip(&other.questions)
+            .filter(|(a, b)| {
+                (a.recall - b.recall).abs() > EPSILON
+                    || (a.reciprocal_rank - b.reciprocal_rank).abs() > EPSILON
+                    || (a.ndcg - b.ndcg).abs() > EPSILON
+            })
+            .map(|(a, b)| QuestionDiff {
+                question: a.question.clone(),
+                recall: (a.recall, b.recall),
+                reciprocal_rank: (a.reciprocal_rank, b.reciprocal_rank),
+                ndcg: (a.ndcg, b.ndcg),
+            })
+            .collect()
+    }
+}
+
+/// Scores the retrieved chunks for one question
+///
+/// Each chunk's gain is how many expected pages and passages it finds that no better ranked
+/// chunk already found. The ideal ranking puts all of those gains, plus one for every expected
+/// item that was missed, at the top. That way a chunk that covers both an expected page and
+/// passage counts fully, and missing something lowers nDCG as well as recall.
+fn score(golden: &GoldenQuestion, chunks: &[ContextChunk], k: usize) -> QuestionResult {
+    let passages = golden
+        .passages
+        .iter()
+        .map(|passage| normalize_text(passage))
+        .collect_vec();
+
+    let mut found = vec![false; golden.expected_count()];
+    let mut gains = Vec::with_capacity(chunks.len());
+    for chunk in chunks {
+        let text = normalize_text(&chunk.text);
+        let matches = golden
+            .pages
+            .iter()
+            .map(|page| chunk.page_path.ends_with(page.as_str()))
+            .chain(
+                passages
+                    .iter()
+                    .map(|passage| text.contains(passage.as_str())),
+            );
+
+        let mut gain = 0;
+        for (found, matched) in found.iter_mut().zip(matches) {
+            if matched && !*found {
+                *found = true;
+                gain += 1;
+            }
+        }
+        gains.push(gain);
+    }
+
+    let found_count = found.iter().filter(|found| **found).count();
+    let missed = golden
+        .pages
+        .iter()
+        .chain(&golden.passages)
+        .zip(&found)
+        .filter(|(_, found)| !**found)
+        .map(|(expected, _)| expected.clone())
+        .collect_vec();
+
+    let hits = gains
+        .iter()
+        .enumerate()
+        .filter(|(_, gain)| **gain > 0)
+        .map(|(i, _)| i + 1)
+        .collect_vec();
+
+    let mut ideal_gains = gains
+        .iter()
+        .copied()
+        .chain(std::iter::repeat_n(1, missed.len()))
+        .collect_vec();
+    ideal_gains.sort_unstable_by(|a, b| b.cmp(a));
+    ideal_gains.truncate(k.max(1));
+    let ideal = dcg(&ideal_gains);
+
+    QuestionResult {
+        question: golden.question.clone(),
+        retrieved: chunks
+            .iter()
+            .map(|chunk| format!("{}: {}", chunk.corpus, chunk.page_path))
+            .collect(),
+        recall: found_count as f64 / golden.expected_count().max(1) as f64,
+        reciprocal_rank: hits.first().map_or(0.0, |rank| 1.0 / *rank as f64),
+        ndcg: if ideal > 0.0 {
+            dcg(&gains) / ideal
+        } else {
+            0.0
+        },
+        hits,
+        missed,
+    }
+}
+
+fn dcg(gains: &[usize]) -> f64 {
+    gains
+        .iter()
+        .enumerate()
+        .map(|(i, gain)| *gain as f64 / (i as f64 + 2.0).log2())
+        .sum()
+}
+
+fn normalize_text(text: &str) -> String {
+    text.split_whitespace().join(" ").to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn golden(pages: &[&str], passages: &[&str]) -> GoldenQuestion {
+        GoldenQuestion {
+            question: "How do snakes grow?".to_string(),
+            pages: pages.iter().map(|page| page.to_string()).collect(),
+            passages: passages.iter().map(|passage| passage.to_string()).collect(),
+            answer: None,
+        }
+    }
+
+    fn chunk(page_path: &str, text: &str) -> ContextChunk {
+        ContextChunk {
+            corpus: "official".to_string(),
+            page_path: page_path.to_string(),
+            text: text.to_string(),
+            distance: 0.0,
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn dcg_discounts_by_log_rank() {
+        assert_close(dcg(&[]), 0.0);
+        assert_close(dcg(&[1]), 1.0);
+        assert_close(dcg(&[0, 1, 1]), 1.0 / 3f64.log2() + 0.5);
+        assert_close(dcg(&[2, 1]), 2.0 + 1.0 / 3f64.log2());
+    }
+
+    #[test]
+    fn finds_pages_and_passages() {
+        let result = score(
+            &golden(&["rules/food.md"], &["Snakes grow"]),
+            &[
+                chunk("docs/movement.md", "Snakes move one square a turn"),
+                chunk("docs/rules/food.md", "Food spawns every few turns"),
+                chunk("docs/eating.md", "snakes\n  GROW when they eat"),
+            ],
+            3,
+        );
+
+        assert_eq!(result.hits, vec![2, 3]);
+        assert!(result.missed.is_empty());
+        assert_close(result.recall, 1.0);
+        assert_close(result.reciprocal_rank, 0.5);
+        // Gains [0, 1, 1] against the ideal [1, 1, 0]
+        let ideal = 1.0 + 1.0 / 3f64.log2();
+        assert_close(result.ndcg, (1.0 / 3f64.log2() + 0.5) / ideal);
+    }
+
+    #[test]
+    fn missed_pages_count_against_the_ideal_ranking() {
+        let result = score(
+            &golden(&["a.md", "b.md"], &[]),
+            &[chunk("a.md", "A"), chunk("c.md", "C")],
+            2,
+        );
+
+        assert_eq!(result.hits, vec![1]);
+        assert_eq!(result.missed, vec!["b.md".to_string()]);
+        assert_close(result.recall, 0.5);
+        assert_close(result.reciprocal_rank, 1.0);
+        // Gains [1, 0] against the ideal [1, 1]
+        assert_close(result.ndcg, 1.0 / (1.0 + 1.0 / 3f64.log2()));
+    }
+
+    #[test]
+    fn a_chunk_only_counts_each_expectation_once() {
+        let result = score(
+            &golden(&["a.md"], &[]),
+            &[chunk("a.md", "First"), chunk("a.md", "Second")],
+            2,
+        );
+
+        assert_eq!(result.hits, vec![1]);
+        assert_close(result.recall, 1.0);
+        assert_close(result.ndcg, 1.0);
+    }
+
+    #[test]
+    fn nothing_relevant_retrieved_scores_zero() {
+        let result = score(&golden(&["a.md"], &[]), &[chunk("z.md", "Z")], 1);
+
+        assert!(result.hits.is_empty());
+        assert_eq!(result.missed, vec!["a.md".to_string()]);
+        assert_close(result.recall, 0.0);
+        assert_close(result.reciprocal_rank, 0.0);
+        assert_close(result.ndcg, 0.0);
+    }
+
+    #[test]
+    fn nothing_expected_scores_zero() {
+        let result = score(&golden(&[], &[]), &[chunk("a.md", "A")], 1);
+
+        assert!(result.hits.is_empty());
+        assert!(result.missed.is_empty());
+        assert_close(result.recall, 0.0);
+        assert_close(result.reciprocal_rank, 0.0);
+        assert_close(result.ndcg, 0.0);
+    }
+}
@@ -0,0 +1,58 @@
+use miette::{miette, Result};
+
+use crate::{openai::Client, CompletionRequest, PromptName, Prompts};
+
+/// Extra texts to embed alongside the question before searching
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueryExpansion {
+    /// Only search with the question itself
+    #[default]
+    None,
+    /// Have the model rewrite the question this many ways, closer to how the docs are written
+    MultiQuery(usize),
+    /// Have the model write a Hypothetical Document (HyDE), an answer that reads like the docs,
+    /// and search with that
+    Hyde,
+}
+
+/// Returns every query that should be embedded and searched, starting with the question itself
+pub async fn expand_query(
+    client: &Client,
+    prompts: &Prompts,
+    question: &str,
+    expansion: QueryExpansion,
+) -> Result<Vec<String>> {
+    let prompt = match expansion {
+        QueryExpansion::None => return Ok(vec![question.to_owned()]),
+        QueryExpansion::MultiQuery(count) => prompts.render(
+            PromptName::MultiQuery,
+            &[("count", &count.to_string()), ("question", question)],
+        ),
+        QueryExpansion::Hyde => prompts.render(PromptName::Hyde, &[("question", question)]),
+    };
+
+    let response = client
+        .completion(CompletionRequest::gpt_3_5_turbo(&prompt))
+        .await?;
+    let content = &response
+        .choices
+        .first()
+        .ok_or_else(|| miette!("Query expansion got no choices back"))?
+        .message
+        .content;
+
+    let mut queries = vec![question.to_owned()];
+    match expansion {
+        QueryExpansion::MultiQuery(count) => queries.extend(
+            content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .take(count)
+                .map(str::to_owned),
+        ),
+        _ => queries.push(content.trim().to_owned()),
+    }
+
+    Ok(queries)
+}
@@ -0,0 +1,219 @@
+use std::{
+    fmt::{self, Debug},
+    path::Path,
+    sync::Arc,
+};
+
+use miette::{IntoDiagnostic, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+pub use self::brute_force::BruteForceIndex;
+pub use self::hnsw::HnswIndex;
+pub use self::vss::{rebuild_vss_index, VssIndex};
+
+use crate::{
+    embedding::{decode_embedding, Quantization},
+    metadata::EmbeddingSettings,
+};
+
+mod brute_force;
+mod hnsw;
+mod vss;
+
+/// The kinds of [VectorIndex] a corpus can be searched with
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IndexName {
+    BruteForce,
+    Vss,
+    Hnsw,
+}
+
+impl fmt::Display for IndexName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            IndexName::BruteForce => "brute-force",
+            IndexName::Vss => "vss",
+            IndexName::Hnsw => "hnsw",
+        })
+    }
+}
+
+/// Nearest neighbour search over `sentences.embedding`
+///
+/// `sentences` is always the source of truth. An index only has to be told about changes so it
+/// can keep whatever it builds on the side in sync.
+pub trait VectorIndex: Debug + Send + Sync {
+    fn name(&self) -> IndexName;
+
+    /// The closest sentences to the embedding as `(rowid, distance)`, closest first
+    ///
+    /// Every index gives cosine distance, `1 - cosine_similarity`, so distances from corpora
+    /// searched with different indexes can be compared
+    fn search(&self, conn: &Connection, embedding: &[f64], limit: usize)
+        -> Result<Vec<(u32, f64)>>;
+
+    /// Picks up a sentence that was just embedded
+    fn insert(&self, conn: &Connection, rowid: i64) -> Result<()>;
+
+    /// Forgets a sentence that is about to be deleted
+    fn remove(&self, conn: &Connection, rowid: i64) -> Result<()>;
+
+    /// How many sentences the index can currently find
+    fn count(&self, conn: &Connection) -> Result<usize>;
+
+    /// Throws the index away and builds it again from `sentences`
+    fn rebuild(&self, conn: &Connection, dimension: usize) -> Result<()>;
+
+    /// Writes out anything the index keeps outside the DB
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Uses the HNSW index when one has been built next to the DB, then sqlite-vss when its
+/// extensions loaded, and otherwise searches in process
+///
+/// sqlite-vss only reads f32 embeddings, so quantized DBs are always searched in process
+pub fn open_index(
+    conn: &Connection,
+    hnsw_path: &Path,
+    vss_loaded: bool,
+) -> Result<Arc<dyn VectorIndex>> {
+    if hnsw_path.exists() {
+        Ok(Arc::new(HnswIndex::load(conn, hnsw_path)?))
+    } else if vss_loaded {
+        let quantization = EmbeddingSettings::load(conn)?.quantization;
+        if quantization == Quantization::F32 {
+            Ok(Arc::new(VssIndex::open(conn)?))
+        } else {
+            eprintln!(
+                "sqlite-vss can't search {quantization} embeddings, using brute force search"
+            );
+            Ok(Arc::new(BruteForceIndex::load(conn)?))
+        }
+    } else {
+        Ok(Arc::new(BruteForceIndex::load(conn)?))
+    }
+}
+
+/// Every embedded sentence as `(rowid, embedding blob)`
+fn load_embedding_blobs(conn: &Connection) -> Result<Vec<(i64, Vec<u8>)>> {
+    let mut stmt = conn
+        .prepare("select rowid, embedding from sentences where embedding is not null")
+        .into_diagnostic()?;
+    let rows = stmt
+        .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))
+        .into_diagnostic()?;
+
+    rows.collect::<Result<_, _>>().into_diagnostic()
+}
+
+/// Decodes every embedded sentence with `decode`
+///
+/// Sentences whose embedding can't be decoded are left out with a warning instead of failing, so
+/// the index still loads and `snakegpt-cli doctor` can report and re-embed them.
+fn decode_embedding_blobs<T>(
+    conn: &Connection,
+    decode: impl Fn(i64, &[u8]) -> Result<T>,
+) -> Result<Vec<T>> {
+    let mut decoded = Vec::new();
+    let mut skipped = 0;
+    for (rowid, blob) in load_embedding_blobs(conn)? {
+        match decode(rowid, &blob) {
+            Ok(value) => decoded.push(value),
+            Err(_) => skipped += 1,
+        }
+    }
+
+    if skipped > 0 {
+        eprintln!(
+            "Skipped {skipped} sentences whose embeddings couldn't be decoded, run `snakegpt-cli doctor --reembed` to fix them"
+        );
+    }
+
+    Ok(decoded)
+}
+
+fn load_embedding_blob(conn: &Connection, rowid: i64) -> Result<Option<Vec<u8>>> {
+    conn.query_row(
+        "select embedding from sentences where rowid = ?1 and embedding is not null",
+        params![rowid],
+        |row| row.get(0),
+    )
+    .optional()
+    .into_diagnostic()
+}
+
+/// Every embedded sentence as `(rowid, embedding)`, normalized so cosine similarity is a dot
+/// product
+fn load_normalized_embeddings(conn: &Connection) -> Result<Vec<(i64, Vec<f32>)>> {
+    decode_embedding_blobs(conn, |rowid, blob| Ok((rowid, normalized_embedding(blob)?)))
+}
+
+fn load_normalized_embedding(conn: &Connection, rowid: i64) -> Result<Option<Vec<f32>>> {
+    load_embedding_blob(conn, rowid)?
+        .map(|blob| normalized_embedding(&blob))
+        .transpose()
+}
+
+fn normalized_embedding(blob: &[u8]) -> Result<Vec<f32>> {
+    let mut embedding = decode_embedding(blob)?;
+    normalize(&mut embedding);
+
+    Ok(embedding)
+}
+
+/// Sums in fixed width lanes so the compiler can turn the loop into SIMD
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+
+    const LANES: usize = 8;
+    let mut sums = [0.0f32; LANES];
+
+    let a_chunks = a.chunks_exact(LANES);
+    let b_chunks = b.chunks_exact(LANES);
+    let remainder: f32 = a_chunks
+        .remainder()
+        .iter()
+        .zip(b_chunks.remainder())
+        .map(|(x, y)| x * y)
+        .sum();
+
+    for (a, b) in a_chunks.zip(b_chunks) {
+        for ((sum, x), y) in sums.iter_mut().zip(a).zip(b) {
+            *sum += x * y;
+        }
+    }
+
+    sums.iter().sum::<f32>() + remainder
+}
+
+fn normalize(v: &mut [f32]) {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+/// A DB with one sentence per embedding, the first one at rowid 1
+#[cfg(test)]
+fn test_db<E: AsRef<[f64]>>(embeddings: &[E], quantization: Quantization) -> Connection {
+    let mut conn = Connection::open_in_memory().unwrap();
+    crate::schema::migrate(&mut conn).unwrap();
+    for (i, embedding) in embeddings.iter().enumerate() {
+        conn.execute(
+            "insert into sentences (page_id, page_index, text, embedding) values (1, ?1, ?2, ?3)",
+            params![
+                i,
+                format!("sentence {i}"),
+                crate::embedding::encode_embedding(embedding.as_ref(), quantization)
+            ],
+        )
+        .unwrap();
+    }
+    conn
+}
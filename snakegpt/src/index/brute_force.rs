@@ -0,0 +1,355 @@
+use std::sync::RwLock;
+
+use miette::Result;
+use rusqlite::Connection;
+
+use super::{decode_embedding_blobs, dot, load_embedding_blob, normalize, IndexName, VectorIndex};
+use crate::embedding::{bit, quantize_binary, quantize_int8, QuantizedEmbedding};
+
+/// How many more candidates than asked for get rescored when the embeddings are quantized
+const RESCORE_FACTOR: usize = 4;
+
+/// Exact search that keeps every embedding in memory and compares the query against all of them
+///
+/// Needs no native extensions, so it is what we fall back to when sqlite-vss can't be loaded.
+/// Distances are cosine distances, `1 - cosine_similarity`.
+/// Build with the `parallel` feature to spread the scan across threads.
+///
+/// Quantized embeddings stay quantized in memory. They are ranked with integer math first and
+/// then the best few are rescored against the full precision query.
+#[derive(Debug)]
+pub struct BruteForceIndex {
+    vectors: RwLock<Vec<IndexedVector>>,
+}
+
+#[derive(Debug)]
+struct IndexedVector {
+    rowid: i64,
+    /// f32 embeddings are normalized up front so scoring is a plain dot product
+    embedding: QuantizedEmbedding,
+    /// The length of the dequantized embedding, 1 for f32 ones
+    norm: f32,
+}
+
+/// The query in every format the stored embeddings might be in
+struct Query {
+    values: Vec<f32>,
+    int8: (f32, Vec<i8>),
+    bits: Vec<u64>,
+}
+
+impl BruteForceIndex {
+    pub fn load(conn: &Connection) -> Result<Self> {
+        Ok(Self {
+            vectors: RwLock::new(load_vectors(conn)?),
+        })
+    }
+}
+
+impl VectorIndex for BruteForceIndex {
+    fn name(&self) -> IndexName {
+        IndexName::BruteForce
+    }
+
+    fn search(
+        &self,
+        _conn: &Connection,
+        embedding: &[f64],
+        limit: usize,
+    ) -> Result<Vec<(u32, f64)>> {
+        let query = Query::new(embedding);
+
+        let vectors = self.vectors.read().unwrap();
+        let mut scored = score_all(&vectors, &query);
+        scored.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+        let quantized = vectors
+            .iter()
+            .any(|v| !matches!(v.embedding, QuantizedEmbedding::F32(_)));
+        if quantized {
+            scored.truncate(limit * RESCORE_FACTOR);
+            for (i, similarity) in &mut scored {
+                *similarity = vectors[*i].similarity(&query.values);
+            }
+            scored.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        }
+
+        Ok(scored
+            .into_iter()
+            .take(limit)
+            .map(|(i, similarity)| (vectors[i].rowid as u32, 1.0 - similarity as f64))
+            .collect())
+    }
+
+    fn insert(&self, conn: &Connection, rowid: i64) -> Result<()> {
+        if let Some(blob) = load_embedding_blob(conn, rowid)? {
+            let vector = IndexedVector::new(rowid, &blob)?;
+
+            let mut vectors = self.vectors.write().unwrap();
+            vectors.retain(|v| v.rowid != rowid);
+            vectors.push(vector);
+        }
+
+        Ok(())
+    }
+
+    fn remove(&self, _conn: &Connection, rowid: i64) -> Result<()> {
+        self.vectors.write().unwrap().retain(|v| v.rowid != rowid);
+
+        Ok(())
+    }
+
+    fn count(&self, _conn: &Connection) -> Result<usize> {
+        Ok(self.vectors.read().unwrap().len())
+    }
+
+    fn rebuild(&self, conn: &Connection, _dimension: usize) -> Result<()> {
+        *self.vectors.write().unwrap() = load_vectors(conn)?;
+
+        Ok(())
+    }
+}
+
+impl IndexedVector {
+    fn new(rowid: i64, blob: &[u8]) -> Result<Self> {
+        let (embedding, norm) = match QuantizedEmbedding::from_blob(blob)? {
+            QuantizedEmbedding::F32(mut values) => {
+                normalize(&mut values);
+                (QuantizedEmbedding::F32(values), 1.0)
+            }
+            QuantizedEmbedding::Int8 { scale, values } => {
+                let norm = values
+                    .iter()
+                    .map(|v| (*v as f32 * scale).powi(2))
+                    .sum::<f32>()
+                    .sqrt();
+                (QuantizedEmbedding::Int8 { scale, values }, norm)
+            }
+            QuantizedEmbedding::Binary { dimension, bits } => (
+                QuantizedEmbedding::Binary { dimension, bits },
+                (dimension as f32).sqrt(),
+            ),
+        };
+
+        Ok(Self {
+            rowid,
+            embedding,
+            norm,
+        })
+    }
+
+    /// Cheap estimate of the cosine similarity used to pick what gets rescored
+    fn approximate_similarity(&self, query: &Query) -> f32 {
+        if self.norm == 0.0 {
+            return 0.0;
+        }
+
+        match &self.embedding {
+            QuantizedEmbedding::F32(values) => dot(values, &query.values),
+            QuantizedEmbedding::Int8 { scale, values } => {
+                let (query_scale, query_values) = &query.int8;
+                if values.len() != query_values.len() {
+                    return 0.0;
+                }
+
+                let sum: i32 = values
+                    .iter()
+                    .zip(query_values)
+                    .map(|(a, b)| *a as i32 * *b as i32)
+                    .sum();
+                sum as f32 * scale * query_scale / self.norm
+            }
+            QuantizedEmbedding::Binary { dimension, bits } => {
+                if bits.len() != query.bits.len() {
+                    return 0.0;
+                }
+
+                let differing: u32 = bits
+                    .iter()
+                    .zip(&query.bits)
+                    .map(|(a, b)| (a ^ b).count_ones())
+                    .sum();
+                1.0 - 2.0 * differing as f32 / *dimension as f32
+            }
+        }
+    }
+
+    /// Cosine similarity between the full precision query and the stored embedding
+    fn similarity(&self, query: &[f32]) -> f32 {
+        if self.norm == 0.0 {
+            return 0.0;
+        }
+
+        match &self.embedding {
+            QuantizedEmbedding::F32(values) => dot(values, query),
+            QuantizedEmbedding::Int8 { scale, values } => {
+                let sum: f32 = values.iter().zip(query).map(|(v, q)| *v as f32 * q).sum();
+                sum * scale / self.norm
+            }
+            QuantizedEmbedding::Binary { dimension, bits } => {
+                let sum: f32 = query
+                    .iter()
+                    .take(*dimension)
+                    .enumerate()
+                    .map(|(i, q)| if bit(bits, i) { *q } else { -q })
+                    .sum();
+                sum / self.norm
+            }
+        }
+    }
+}
+
+impl Query {
+    fn new(embedding: &[f64]) -> Self {
+        let mut values: Vec<f32> = embedding.iter().map(|v| *v as f32).collect();
+        normalize(&mut values);
+
+        Self {
+            int8: quantize_int8(&values),
+            bits: quantize_binary(&values),
+            values,
+        }
+    }
+}
+
+fn load_vectors(conn: &Connection) -> Result<Vec<IndexedVector>> {
+    decode_embedding_blobs(conn, IndexedVector::new)
+}
+
+/// Returns `(index into vectors, approximate similarity)` for every vector
+#[cfg(feature = "parallel")]
+fn score_all(vectors: &[IndexedVector], query: &Query) -> Vec<(usize, f32)> {
+    use rayon::prelude::*;
+
+    vectors
+        .par_iter()
+        .enumerate()
+        .map(|(i, v)| (i, v.approximate_similarity(query)))
+        .collect()
+}
+
+/// Returns `(index into vectors, approximate similarity)` for every vector
+#[cfg(not(feature = "parallel"))]
+fn score_all(vectors: &[IndexedVector], query: &Query) -> Vec<(usize, f32)> {
+    vectors
+        .iter()
+        .enumerate()
+        .map(|(i, v)| (i, v.approximate_similarity(query)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use super::*;
+    use crate::{embedding::Quantization, index::test_db};
+
+    fn rowids(results: &[(u32, f64)]) -> Vec<u32> {
+        results.iter().map(|(rowid, _)| *rowid).collect()
+    }
+
+    #[test]
+    fn searches_closest_first_up_to_the_limit() {
+        let conn = test_db(
+            &[&[0.0, 1.0], &[1.0, 0.0], &[1.0, 1.0], &[-1.0, 0.0]],
+            Quantization::F32,
+        );
+        let index = BruteForceIndex::load(&conn).unwrap();
+
+        let results = index.search(&conn, &[2.0, 0.0], 10).unwrap();
+        assert_eq!(rowids(&results), vec![2, 3, 1, 4]);
+        let distances = results.iter().map(|(_, distance)| *distance).collect_vec();
+        for (distance, expected) in distances.iter().zip([0.0, 1.0 - 0.5f64.sqrt(), 1.0, 2.0]) {
+            assert!(
+                (distance - expected).abs() < 1e-6,
+                "{distance} != {expected}"
+            );
+        }
+
+        assert_eq!(
+            rowids(&index.search(&conn, &[2.0, 0.0], 2).unwrap()),
+            vec![2, 3]
+        );
+        assert!(index.search(&conn, &[2.0, 0.0], 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn counts_inserts_and_removes() {
+        let conn = test_db(&[&[1.0, 0.0], &[0.0, 1.0]], Quantization::F32);
+        let index = BruteForceIndex::load(&conn).unwrap();
+        assert_eq!(index.count(&conn).unwrap(), 2);
+
+        index.remove(&conn, 1).unwrap();
+        assert_eq!(index.count(&conn).unwrap(), 1);
+        assert_eq!(
+            rowids(&index.search(&conn, &[1.0, 0.0], 10).unwrap()),
+            vec![2]
+        );
+
+        // Inserting again picks the sentence back up once, however often it is told
+        index.insert(&conn, 1).unwrap();
+        index.insert(&conn, 1).unwrap();
+        assert_eq!(index.count(&conn).unwrap(), 2);
+        assert_eq!(
+            rowids(&index.search(&conn, &[1.0, 0.0], 10).unwrap()),
+            vec![1, 2]
+        );
+
+        // Sentences without an embedding aren't indexed
+        conn.execute(
+            "insert into sentences (page_id, page_index, text) values (1, 2, 'unembedded')",
+            (),
+        )
+        .unwrap();
+        index.insert(&conn, 3).unwrap();
+        assert_eq!(index.count(&conn).unwrap(), 2);
+    }
+
+    #[test]
+    fn rescores_quantized_matches_at_full_precision() {
+        // Binary search alone puts 2 first since its signs differ from the query's in one
+        // place rather than three, but 1 points much more along the query's largest value
+        let conn = test_db(
+            &[&[1.0, -1.0, -1.0, -1.0], &[-1.0, 1.0, 1.0, 1.0]],
+            Quantization::Binary,
+        );
+        let index = BruteForceIndex::load(&conn).unwrap();
+        let query = [0.9, 0.3, 0.3, 0.1];
+
+        let vectors = index.vectors.read().unwrap();
+        let approximate = Query::new(&query);
+        assert!(
+            vectors[1].approximate_similarity(&approximate)
+                > vectors[0].approximate_similarity(&approximate)
+        );
+        drop(vectors);
+
+        assert_eq!(rowids(&index.search(&conn, &query, 1).unwrap()), vec![1]);
+        assert_eq!(rowids(&index.search(&conn, &query, 2).unwrap()), vec![1, 2]);
+    }
+
+    #[test]
+    fn quantized_distances_match_f32_ones_closely() {
+        let embeddings: [&[f64]; 3] = [&[1.0, 0.2, 0.0], &[0.1, 1.0, 0.3], &[-0.5, 0.5, 1.0]];
+        let query = [0.8, 0.4, 0.1];
+
+        let exact = {
+            let conn = test_db(&embeddings, Quantization::F32);
+            BruteForceIndex::load(&conn)
+                .unwrap()
+                .search(&conn, &query, 3)
+                .unwrap()
+        };
+        let conn = test_db(&embeddings, Quantization::Int8);
+        let int8 = BruteForceIndex::load(&conn)
+            .unwrap()
+            .search(&conn, &query, 3)
+            .unwrap();
+
+        assert_eq!(rowids(&int8), rowids(&exact));
+        for ((_, a), (_, b)) in int8.iter().zip(&exact) {
+            assert!((a - b).abs() < 0.01, "{a} is too far from {b}");
+        }
+    }
+}
@@ -0,0 +1,580 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap, HashSet},
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{self, AtomicBool},
+        RwLock,
+    },
+};
+
+use miette::{miette, IntoDiagnostic, Result, WrapErr};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    dot, load_normalized_embedding, load_normalized_embeddings, normalize, IndexName, VectorIndex,
+};
+
+/// How many neighbours a node links to on each layer above 0. Layer 0 keeps twice as many.
+const M: usize = 16;
+/// How wide the search for a new node's neighbours is while building
+const EF_CONSTRUCTION: usize = 100;
+/// How wide the search is at query time, raised to the limit when that is bigger
+const EF_SEARCH: usize = 64;
+
+/// Approximate search over a Hierarchical Navigable Small World graph, built in process
+///
+/// The graph, vectors included, is saved next to the corpus DB so the server can load it at
+/// startup without sqlite-vss. It works with any embedding dimension.
+/// Distances are cosine distances, `1 - cosine_similarity`, the same as [super::BruteForceIndex].
+///
+/// Removed sentences are only marked as deleted, run `reindex` to drop them from the graph.
+#[derive(Debug)]
+pub struct HnswIndex {
+    path: PathBuf,
+    graph: RwLock<Graph>,
+    /// Set when the graph has changed since it was last written to `path`
+    dirty: AtomicBool,
+}
+
+impl HnswIndex {
+    /// An empty index that [VectorIndex::flush] will write to `path`
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+            graph: RwLock::new(Graph::default()),
+            dirty: AtomicBool::new(true),
+        }
+    }
+
+    /// Reads the saved graph, rebuilding it from `sentences` when it can't be read or no longer
+    /// matches the DB, like after downloading a fresh copy of it
+    pub fn load(conn: &Connection, path: &Path) -> Result<Self> {
+        let index = Self::new(path);
+
+        match Graph::read(path) {
+            Ok(graph) if graph.matches(conn)? => {
+                *index.graph.write().unwrap() = graph;
+                index.dirty.store(false, atomic::Ordering::SeqCst);
+            }
+            Ok(_) => {
+                eprintln!("{} is out of date, rebuilding it", path.display());
+                index.rebuild(conn, 0)?;
+                index.flush()?;
+            }
+            Err(e) => {
+                eprintln!("{e:?}");
+                eprintln!("Rebuilding {}", path.display());
+                index.rebuild(conn, 0)?;
+                index.flush()?;
+            }
+        }
+
+        Ok(index)
+    }
+}
+
+impl VectorIndex for HnswIndex {
+    fn name(&self) -> IndexName {
+        IndexName::Hnsw
+    }
+
+    fn search(
+        &self,
+        _conn: &Connection,
+        embedding: &[f64],
+        limit: usize,
+    ) -> Result<Vec<(u32, f64)>> {
+        let mut query: Vec<f32> = embedding.iter().map(|v| *v as f32).collect();
+        normalize(&mut query);
+
+        let graph = self.graph.read().unwrap();
+        if let Some(node) = graph.nodes.first() {
+            if node.vector.len() != query.len() {
+                return Err(miette!(
+                    help = "Run `snakegpt-cli reindex` after changing embedding models",
+                    "The HNSW index holds {} dimension embeddings but the query has {}",
+                    node.vector.len(),
+                    query.len()
+                ));
+            }
+        }
+
+        Ok(graph
+            .search(&query, EF_SEARCH.max(limit))
+            .into_iter()
+            .filter(|candidate| !graph.nodes[candidate.id as usize].deleted)
+            .take(limit)
+            .map(|candidate| {
+                let node = &graph.nodes[candidate.id as usize];
+                (node.rowid as u32, candidate.distance as f64)
+            })
+            .collect())
+    }
+
+    fn insert(&self, conn: &Connection, rowid: i64) -> Result<()> {
+        if let Some(embedding) = load_normalized_embedding(conn, rowid)? {
+            self.graph.write().unwrap().insert(rowid, embedding);
+            self.dirty.store(true, atomic::Ordering::SeqCst);
+        }
+
+        Ok(())
+    }
+
+    fn remove(&self, _conn: &Connection, rowid: i64) -> Result<()> {
+        self.graph.write().unwrap().remove(rowid);
+        self.dirty.store(true, atomic::Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    fn count(&self, _conn: &Connection) -> Result<usize> {
+        Ok(self.graph.read().unwrap().by_rowid.len())
+    }
+
+    fn rebuild(&self, conn: &Connection, _dimension: usize) -> Result<()> {
+        let mut graph = Graph::default();
+        for (rowid, embedding) in load_normalized_embeddings(conn)? {
+            graph.insert(rowid, embedding);
+        }
+
+        *self.graph.write().unwrap() = graph;
+        self.dirty.store(true, atomic::Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        if self.dirty.swap(false, atomic::Ordering::SeqCst) {
+            if let Err(e) = self.graph.read().unwrap().write(&self.path) {
+                self.dirty.store(true, atomic::Ordering::SeqCst);
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Graph {
+    nodes: Vec<Node>,
+    entry_point: Option<u32>,
+    /// Drives the level each new node is given, so building the same DB gives the same graph
+    rng_state: u64,
+    #[serde(skip)]
+    by_rowid: HashMap<i64, u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Node {
+    rowid: i64,
+    /// Normalized so scoring is a plain dot product
+    vector: Vec<f32>,
+    /// Neighbour ids on each layer the node is part of, starting from layer 0
+    layers: Vec<Vec<u32>>,
+    deleted: bool,
+}
+
+/// A node id and its distance from whatever is being searched for, ordered by distance
+#[derive(Clone, Copy, Debug)]
+struct Candidate {
+    distance: f32,
+    id: u32,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance
+            .total_cmp(&other.distance)
+            .then(self.id.cmp(&other.id))
+    }
+}
+
+impl Graph {
+    fn read(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not open {}", path.display()))?;
+        let mut graph: Self = bincode::deserialize_from(BufReader::new(file))
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not read the HNSW index in {}", path.display()))?;
+
+        graph.by_rowid = graph
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| !node.deleted)
+            .map(|(id, node)| (node.rowid, id as u32))
+            .collect();
+
+        Ok(graph)
+    }
+
+    /// Writes to a temporary file first so a crash never leaves half an index behind
+    fn write(&self, path: &Path) -> Result<()> {
+        let tmp_path = path.with_extension("hnsw.tmp");
+        let file = File::create(&tmp_path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not create {}", tmp_path.display()))?;
+        let mut writer = BufWriter::new(file);
+        bincode::serialize_into(&mut writer, self).into_diagnostic()?;
+        writer.flush().into_diagnostic()?;
+
+        std::fs::rename(&tmp_path, path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not replace {}", path.display()))
+    }
+
+    /// Whether the graph holds exactly the sentences the DB has embeddings for
+    fn matches(&self, conn: &Connection) -> Result<bool> {
+        let (count, max_rowid): (usize, Option<i64>) = conn
+            .query_row(
+                "select count(*), max(rowid) from sentences where embedding is not null",
+                (),
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .into_diagnostic()?;
+
+        Ok(count == self.by_rowid.len()
+            && max_rowid
+                .into_iter()
+                .all(|rowid| self.by_rowid.contains_key(&rowid)))
+    }
+
+    fn insert(&mut self, rowid: i64, vector: Vec<f32>) {
+        // A sentence that is embedded again replaces its old node
+        self.remove(rowid);
+
+        let id = self.nodes.len() as u32;
+        let level = self.random_level();
+        self.nodes.push(Node {
+            rowid,
+            vector,
+            layers: vec![vec![]; level + 1],
+            deleted: false,
+        });
+        self.by_rowid.insert(rowid, id);
+
+        let Some(entry_point) = self.entry_point else {
+            self.entry_point = Some(id);
+            return;
+        };
+
+        let query = self.nodes[id as usize].vector.clone();
+        let top_level = self.level(entry_point);
+
+        let mut closest = entry_point;
+        for layer in (level + 1..=top_level).rev() {
+            closest = self.search_layer(&query, &[closest], 1, layer)[0].id;
+        }
+
+        let mut entry_points = vec![closest];
+        for layer in (0..=level.min(top_level)).rev() {
+            let candidates = self.search_layer(&query, &entry_points, EF_CONSTRUCTION, layer);
+            let neighbours: Vec<u32> = candidates.iter().take(M).map(|c| c.id).collect();
+
+            for &neighbour in &neighbours {
+                self.link(neighbour, id, layer);
+            }
+            self.nodes[id as usize].layers[layer] = neighbours;
+
+            entry_points = candidates.into_iter().map(|c| c.id).collect();
+        }
+
+        if level > top_level {
+            self.entry_point = Some(id);
+        }
+    }
+
+    fn remove(&mut self, rowid: i64) {
+        if let Some(id) = self.by_rowid.remove(&rowid) {
+            self.nodes[id as usize].deleted = true;
+        }
+    }
+
+    /// The closest nodes to the query, closest first. Includes deleted nodes.
+    fn search(&self, query: &[f32], ef: usize) -> Vec<Candidate> {
+        let Some(entry_point) = self.entry_point else {
+            return vec![];
+        };
+
+        let mut closest = entry_point;
+        for layer in (1..=self.level(entry_point)).rev() {
+            closest = self.search_layer(query, &[closest], 1, layer)[0].id;
+        }
+
+        self.search_layer(query, &[closest], ef, 0)
+    }
+
+    /// Best first search of one layer, keeping the `ef` closest nodes seen. Closest first.
+    fn search_layer(
+        &self,
+        query: &[f32],
+        entry_points: &[u32],
+        ef: usize,
+        layer: usize,
+    ) -> Vec<Candidate> {
+        let mut visited: HashSet<u32> = entry_points.iter().copied().collect();
+        let mut to_visit = BinaryHeap::new();
+        let mut found = BinaryHeap::new();
+
+        for &id in entry_points {
+            let candidate = Candidate {
+                distance: self.distance(query, id),
+                id,
+            };
+            to_visit.push(Reverse(candidate));
+            found.push(candidate);
+        }
+        while found.len() > ef {
+            found.pop();
+        }
+
+        while let Some(Reverse(current)) = to_visit.pop() {
+            let furthest = found
+                .peek()
+                .map_or(f32::INFINITY, |c: &Candidate| c.distance);
+            if found.len() >= ef && current.distance > furthest {
+                break;
+            }
+
+            for &neighbour in &self.nodes[current.id as usize].layers[layer] {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+
+                let candidate = Candidate {
+                    distance: self.distance(query, neighbour),
+                    id: neighbour,
+                };
+                let furthest = found.peek().map_or(f32::INFINITY, |c| c.distance);
+                if found.len() < ef || candidate.distance < furthest {
+                    to_visit.push(Reverse(candidate));
+                    found.push(candidate);
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+
+        found.into_sorted_vec()
+    }
+
+    /// Adds `to` to the neighbours of `from`, dropping the furthest one when that goes over the
+    /// layer's limit
+    fn link(&mut self, from: u32, to: u32, layer: usize) {
+        let max_neighbours = if layer == 0 { M * 2 } else { M };
+
+        let mut neighbours = std::mem::take(&mut self.nodes[from as usize].layers[layer]);
+        neighbours.push(to);
+
+        if neighbours.len() > max_neighbours {
+            let from_vector = &self.nodes[from as usize].vector;
+            let mut scored: Vec<Candidate> = neighbours
+                .iter()
+                .map(|&id| Candidate {
+                    distance: self.distance(from_vector, id),
+                    id,
+                })
+                .collect();
+            scored.sort();
+            neighbours = scored
+                .into_iter()
+                .take(max_neighbours)
+                .map(|c| c.id)
+                .collect();
+        }
+
+        self.nodes[from as usize].layers[layer] = neighbours;
+    }
+
+    fn level(&self, id: u32) -> usize {
+        self.nodes[id as usize].layers.len() - 1
+    }
+
+    fn distance(&self, query: &[f32], id: u32) -> f32 {
+        1.0 - dot(query, &self.nodes[id as usize].vector)
+    }
+
+    /// Picks a level with the exponentially decaying distribution from the HNSW paper, using
+    /// splitmix64 so no RNG crate is needed
+    fn random_level(&mut self) -> usize {
+        self.rng_state = self.rng_state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng_state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+
+        let uniform = ((z >> 11) as f64 / (1u64 << 53) as f64).max(f64::MIN_POSITIVE);
+        (-uniform.ln() / (M as f64).ln()).floor() as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::params;
+
+    use super::*;
+    use crate::{
+        embedding::{encode_embedding, Quantization},
+        index::{test_db, BruteForceIndex},
+    };
+
+    const DIMENSION: usize = 16;
+
+    /// Deterministic vectors spread over every direction
+    fn random_vectors(count: usize, seed: u64) -> Vec<Vec<f64>> {
+        let mut state = seed;
+        let mut next = move || {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let z = (state ^ (state >> 31)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            (z >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0
+        };
+
+        (0..count)
+            .map(|_| (0..DIMENSION).map(|_| next()).collect())
+            .collect()
+    }
+
+    /// A path in the temp dir for one test's index, cleared of anything a previous run left
+    fn index_path(test: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("snakegpt-{test}-{}.hnsw", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn built(conn: &Connection, path: &Path) -> HnswIndex {
+        let index = HnswIndex::new(path);
+        index.rebuild(conn, DIMENSION).unwrap();
+        index
+    }
+
+    fn rowids(results: &[(u32, f64)]) -> Vec<u32> {
+        results.iter().map(|(rowid, _)| *rowid).collect()
+    }
+
+    #[test]
+    fn finds_most_of_the_true_nearest_neighbours() {
+        let conn = test_db(&random_vectors(500, 1), Quantization::F32);
+        let hnsw = built(&conn, &index_path("recall"));
+        let exact = BruteForceIndex::load(&conn).unwrap();
+
+        let mut found = 0;
+        let queries = random_vectors(20, 2);
+        for query in &queries {
+            let expected = rowids(&exact.search(&conn, query, 10).unwrap());
+            let results = hnsw.search(&conn, query, 10).unwrap();
+
+            found += rowids(&results)
+                .iter()
+                .filter(|rowid| expected.contains(rowid))
+                .count();
+            assert!(results.windows(2).all(|pair| pair[0].1 <= pair[1].1 + 1e-6));
+        }
+
+        let recall = found as f64 / (queries.len() * 10) as f64;
+        assert!(recall >= 0.9, "recall was only {recall}");
+    }
+
+    #[test]
+    fn distances_match_brute_force() {
+        let conn = test_db(&random_vectors(50, 3), Quantization::F32);
+        let hnsw = built(&conn, &index_path("distances"));
+        let exact = BruteForceIndex::load(&conn).unwrap();
+        let query = &random_vectors(1, 4)[0];
+
+        let (rowid, distance) = hnsw.search(&conn, query, 1).unwrap()[0];
+        let (exact_rowid, exact_distance) = exact.search(&conn, query, 1).unwrap()[0];
+        assert_eq!(rowid, exact_rowid);
+        assert!((distance - exact_distance).abs() < 1e-5);
+    }
+
+    #[test]
+    fn removed_sentences_are_not_found() {
+        let vectors = random_vectors(100, 5);
+        let conn = test_db(&vectors, Quantization::F32);
+        let hnsw = built(&conn, &index_path("remove"));
+
+        let closest = hnsw.search(&conn, &vectors[41], 1).unwrap()[0].0;
+        assert_eq!(closest, 42);
+
+        hnsw.remove(&conn, 42).unwrap();
+        let results = hnsw.search(&conn, &vectors[41], 100).unwrap();
+        assert!(!rowids(&results).contains(&42));
+        assert_eq!(results.len(), 99);
+        assert_eq!(hnsw.count(&conn).unwrap(), 99);
+    }
+
+    #[test]
+    fn saves_and_loads_the_graph() {
+        let path = index_path("save");
+        let conn = test_db(&random_vectors(100, 6), Quantization::F32);
+        let hnsw = built(&conn, &path);
+        hnsw.flush().unwrap();
+        assert!(path.exists());
+        assert!(!path.with_extension("hnsw.tmp").exists());
+
+        let loaded = HnswIndex::load(&conn, &path).unwrap();
+        assert!(!loaded.dirty.load(atomic::Ordering::SeqCst));
+        assert_eq!(loaded.count(&conn).unwrap(), 100);
+
+        let query = &random_vectors(1, 7)[0];
+        assert_eq!(
+            hnsw.search(&conn, query, 10).unwrap(),
+            loaded.search(&conn, query, 10).unwrap()
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rebuilds_an_index_that_no_longer_matches_the_db() {
+        let path = index_path("stale");
+        let conn = test_db(&random_vectors(20, 8), Quantization::F32);
+        built(&conn, &path).flush().unwrap();
+        assert!(Graph::read(&path).unwrap().matches(&conn).unwrap());
+
+        conn.execute(
+            "insert into sentences (page_id, page_index, text, embedding) values (1, 20, 'new', ?1)",
+            params![encode_embedding(&random_vectors(1, 9)[0], Quantization::F32)],
+        )
+        .unwrap();
+        assert!(!Graph::read(&path).unwrap().matches(&conn).unwrap());
+
+        let loaded = HnswIndex::load(&conn, &path).unwrap();
+        assert_eq!(loaded.count(&conn).unwrap(), 21);
+        assert!(Graph::read(&path).unwrap().matches(&conn).unwrap());
+
+        // Same count, but a different set of sentences
+        conn.execute("delete from sentences where rowid = 1", ())
+            .unwrap();
+        conn.execute(
+            "insert into sentences (page_id, page_index, text, embedding) values (1, 21, 'newer', ?1)",
+            params![encode_embedding(&random_vectors(1, 10)[0], Quantization::F32)],
+        )
+        .unwrap();
+        assert!(!Graph::read(&path).unwrap().matches(&conn).unwrap());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+use miette::{IntoDiagnostic, Result};
+use rusqlite::{params, Connection};
+
+use super::{dot, load_normalized_embedding, normalize, IndexName, VectorIndex};
+use crate::metadata::EmbeddingSettings;
+
+/// The `vss_sentences` virtual table from sqlite-vss
+///
+/// sqlite-vss ranks by L2 distance, the hits are given cosine distances worked out from
+/// `sentences` so they compare with the other indexes.
+#[derive(Debug, Clone)]
+pub struct VssIndex;
+
+impl VssIndex {
+    /// DBs migrated while sqlite-vss wasn't available don't have the table yet, so it gets
+    /// built the first time they are opened with it
+    pub fn open(conn: &Connection) -> Result<Self> {
+        let exists: bool = conn
+            .query_row(
+                "select count(*) > 0 from sqlite_master where name = 'vss_sentences'",
+                (),
+                |row| row.get(0),
+            )
+            .into_diagnostic()?;
+
+        if !exists {
+            let settings = EmbeddingSettings::load(conn)?;
+            rebuild_vss_index(conn, settings.dimension)?;
+        }
+
+        Ok(Self)
+    }
+
+    /// Whether the vss0 module is loaded on this connection
+    pub fn available(conn: &Connection) -> Result<bool> {
+        conn.query_row(
+            "select count(*) > 0 from pragma_module_list where name = 'vss0'",
+            (),
+            |row| row.get(0),
+        )
+        .into_diagnostic()
+    }
+}
+
+impl VectorIndex for VssIndex {
+    fn name(&self) -> IndexName {
+        IndexName::Vss
+    }
+
+    fn search(
+        &self,
+        conn: &Connection,
+        embedding: &[f64],
+        limit: usize,
+    ) -> Result<Vec<(u32, f64)>> {
+        let embedding_json = serde_json::to_string(embedding).into_diagnostic()?;
+        let mut st = conn
+            .prepare(
+                "select rowid, distance
+  from vss_sentences
+  where vss_search(
+    embedding,
+    vector_from_json(?1)
+  )
+  limit ?2;",
+            )
+            .into_diagnostic()?;
+
+        let rowids = st
+            .query_map(params![&embedding_json, limit], |row| row.get::<_, i64>(0))
+            .into_diagnostic()?
+            .collect::<Result<Vec<_>, rusqlite::Error>>()
+            .into_diagnostic()?;
+
+        let mut query: Vec<f32> = embedding.iter().map(|v| *v as f32).collect();
+        normalize(&mut query);
+
+        let mut nearest = vec![];
+        for rowid in rowids {
+            if let Some(found) = load_normalized_embedding(conn, rowid)? {
+                nearest.push((rowid as u32, 1.0 - dot(&found, &query) as f64));
+            }
+        }
+        nearest.sort_by(|(_, a), (_, b)| a.total_cmp(b));
+
+        Ok(nearest)
+    }
+
+    fn insert(&self, conn: &Connection, rowid: i64) -> Result<()> {
+        conn.execute(
+            "insert into vss_sentences(rowid, embedding)
+  select rowid, embedding from sentences where rowid = ?1 and embedding is not null;",
+            params![rowid],
+        )
+        .into_diagnostic()?;
+
+        Ok(())
+    }
+
+    fn remove(&self, conn: &Connection, rowid: i64) -> Result<()> {
+        conn.execute("delete from vss_sentences where rowid = ?1", params![rowid])
+            .into_diagnostic()?;
+
+        Ok(())
+    }
+
+    fn count(&self, conn: &Connection) -> Result<usize> {
+        conn.query_row("select count(*) from vss_sentences", (), |row| row.get(0))
+            .into_diagnostic()
+    }
+
+    fn rebuild(&self, conn: &Connection, dimension: usize) -> Result<()> {
+        rebuild_vss_index(conn, dimension)
+    }
+}
+
+/// Throws away `vss_sentences` and rebuilds it from every embedding in `sentences`
+///
+/// This is slow on big corpora, so it only runs from a migration or the `reindex` command.
+/// Day to day the index is kept up to date by [VssIndex::insert] and [VssIndex::remove].
+pub fn rebuild_vss_index(conn: &Connection, dimension: usize) -> Result<()> {
+    conn.execute_batch(&format!(
+        "
+  DROP TABLE IF EXISTS vss_sentences;
+  create virtual table vss_sentences using vss0(
+      embedding({dimension}),
+    );
+  "
+    ))
+    .into_diagnostic()?;
+
+    conn.execute(
+        "insert into vss_sentences(rowid, embedding)
+  select rowid, embedding from sentences where embedding is not null;",
+        (),
+    )
+    .into_diagnostic()?;
+
+    Ok(())
+}
@@ -0,0 +1,269 @@
+use miette::{miette, IntoDiagnostic, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+use crate::{
+    context::ContextChunk,
+    embedding::decode_embedding,
+    index::{IndexName, VectorIndex},
+    metadata::{get_metadata, EmbeddingSettings, BUILD_DURATION_KEY, BUILT_AT_KEY},
+    paths::StoragePaths,
+    retrieval::{context_chunk, sentence_embedding},
+    schema::schema_version,
+};
+
+/// An overview of what is in a corpus DB
+#[derive(Clone, Debug, Serialize)]
+pub struct CorpusStats {
+    pub corpus: String,
+    pub pages: usize,
+    pub sentences: usize,
+    pub embedded_sentences: usize,
+    /// In characters
+    pub average_sentence_length: f64,
+    pub db_bytes: u64,
+    /// The size of the HNSW index file, when the corpus has one
+    pub index_bytes: Option<u64>,
+    pub index: IndexName,
+    pub indexed_sentences: usize,
+    pub embedding: EmbeddingSettings,
+    pub schema_version: usize,
+    /// When the last `prepare` run finished, as RFC 3339
+    pub built_at: Option<String>,
+    pub build_duration_secs: Option<f64>,
+}
+
+impl CorpusStats {
+    /// The share of sentences with an embedding, from 0 to 1
+    pub fn embedding_coverage(&self) -> f64 {
+        if self.sentences == 0 {
+            return 0.0;
+        }
+
+        self.embedded_sentences as f64 / self.sentences as f64
+    }
+}
+
+pub fn corpus_stats(
+    conn: &Connection,
+    index: &dyn VectorIndex,
+    paths: &StoragePaths,
+    corpus: &str,
+) -> Result<CorpusStats> {
+    let pages = conn
+        .query_row("select count(*) from pages", (), |row| row.get(0))
+        .into_diagnostic()?;
+    let (sentences, embedded_sentences, average_sentence_length): (usize, usize, Option<f64>) =
+        conn.query_row(
+            "select count(*), count(embedding), avg(length(text)) from sentences",
+            (),
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .into_diagnostic()?;
+
+    let db_bytes = std::fs::metadata(paths.corpus_db_path(corpus))
+        .into_diagnostic()?
+        .len();
+    let index_bytes = std::fs::metadata(paths.hnsw_index_path(corpus))
+        .ok()
+        .map(|metadata| metadata.len());
+
+    Ok(CorpusStats {
+        corpus: corpus.to_string(),
+        pages,
+        sentences,
+        embedded_sentences,
+        average_sentence_length: average_sentence_length.unwrap_or_default(),
+        db_bytes,
+        index_bytes,
+        index: index.name(),
+        indexed_sentences: index.count(conn)?,
+        embedding: EmbeddingSettings::load(conn)?,
+        schema_version: schema_version(conn)?,
+        built_at: get_metadata(conn, BUILT_AT_KEY)?,
+        build_duration_secs: get_metadata(conn, BUILD_DURATION_KEY)?
+            .and_then(|duration| duration.parse().ok()),
+    })
+}
+
+/// A page and every sentence it was split into
+#[derive(Clone, Debug, Serialize)]
+pub struct PageDetails {
+    pub rowid: i64,
+    pub path: String,
+    /// Characters of parsed text, `None` when the page was never split into sentences
+    pub parsed_length: Option<usize>,
+    pub sentences: Vec<SentenceSummary>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SentenceSummary {
+    pub rowid: i64,
+    pub page_index: usize,
+    pub text: String,
+    pub embedded: bool,
+}
+
+/// Escapes text for a `like` pattern ending in `escape '\'`, so a `_` or `%` in it only
+/// matches itself
+pub fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Looks a page up by its path, or by the end of it when that only matches one page
+pub fn inspect_page(conn: &Connection, path: &str) -> Result<PageDetails> {
+    let mut matches = conn
+        .prepare(
+            "select rowid, path, length(parsed_text) from pages
+            where path = ?1 or path like '%' || ?2 escape '\\'
+            order by path = ?1 desc",
+        )
+        .into_diagnostic()?
+        .query_map(params![path, escape_like(path)], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })
+        .into_diagnostic()?
+        .collect::<Result<Vec<(i64, String, Option<usize>)>, _>>()
+        .into_diagnostic()?;
+
+    let exact = matches.first().is_some_and(|(_, found, _)| found == path);
+    if matches.len() > 1 && !exact {
+        return Err(miette!(
+            help = "Use more of the path to pick one",
+            "{path} matches {} pages: {}",
+            matches.len(),
+            matches
+                .iter()
+                .map(|(_, found, _)| found.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    if matches.is_empty() {
+        return Err(miette!("No page found with the path {path}"));
+    }
+    let (rowid, path, parsed_length) = matches.swap_remove(0);
+
+    let sentences = conn
+        .prepare(
+            "select rowid, page_index, text, embedding is not null from sentences
+            where page_id = ?1
+            order by page_index",
+        )
+        .into_diagnostic()?
+        .query_map(params![rowid], |row| {
+            Ok(SentenceSummary {
+                rowid: row.get(0)?,
+                page_index: row.get(1)?,
+                text: row.get(2)?,
+                embedded: row.get(3)?,
+            })
+        })
+        .into_diagnostic()?
+        .collect::<Result<_, _>>()
+        .into_diagnostic()?;
+
+    Ok(PageDetails {
+        rowid,
+        path,
+        parsed_length,
+        sentences,
+    })
+}
+
+/// A sentence, the context window retrieval builds around it, and what the index thinks is
+/// closest to it
+#[derive(Clone, Debug, Serialize)]
+pub struct SentenceDetails {
+    pub rowid: i64,
+    pub page_path: Option<String>,
+    pub page_index: usize,
+    pub text: String,
+    /// The dimension and length of the stored embedding, if there is one
+    pub embedding: Option<(usize, f32)>,
+    /// What gets sent to the model when this sentence is a search hit
+    pub context: Option<ContextChunk>,
+    pub neighbours: Vec<Neighbour>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Neighbour {
+    pub rowid: u32,
+    pub distance: f64,
+    pub text: String,
+}
+
+pub fn inspect_sentence(
+    conn: &Connection,
+    index: &dyn VectorIndex,
+    corpus: &str,
+    rowid: i64,
+    neighbours: usize,
+) -> Result<SentenceDetails> {
+    let (page_path, page_index, text, blob): (Option<String>, usize, String, Option<Vec<u8>>) =
+        conn.query_row(
+            "select pages.path, sentences.page_index, sentences.text, sentences.embedding
+            from sentences
+            left join pages on pages.rowid = sentences.page_id
+            where sentences.rowid = ?1",
+            params![rowid],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+        )
+        .optional()
+        .into_diagnostic()?
+        .ok_or_else(|| miette!("No sentence with rowid {rowid}"))?;
+
+    let embedding = blob
+        .map(|blob| decode_embedding(&blob))
+        .transpose()?
+        .map(|embedding| {
+            let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+            (embedding.len(), norm)
+        });
+
+    // Orphan sentences have no page to build a window from
+    let context = match page_path {
+        Some(_) => Some(context_chunk(conn, corpus, rowid as u32, 0.0)?),
+        None => None,
+    };
+
+    let neighbours = match embedding {
+        Some(_) => {
+            let embedding = sentence_embedding(conn, rowid as u32)?;
+            index
+                .search(conn, &embedding, neighbours + 1)?
+                .into_iter()
+                .filter(|(neighbour, _)| *neighbour as i64 != rowid)
+                .take(neighbours)
+                .map(|(neighbour, distance)| {
+                    let text = conn
+                        .query_row(
+                            "select text from sentences where rowid = ?1",
+                            params![neighbour],
+                            |row| row.get(0),
+                        )
+                        .into_diagnostic()?;
+
+                    Ok(Neighbour {
+                        rowid: neighbour,
+                        distance,
+                        text,
+                    })
+                })
+                .collect::<Result<_>>()?
+        }
+        None => vec![],
+    };
+
+    Ok(SentenceDetails {
+        rowid,
+        page_path,
+        page_index,
+        text,
+        embedding,
+        context,
+        neighbours,
+    })
+}
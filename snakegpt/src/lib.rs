@@ -1,164 +1,233 @@
-use indoc::formatdoc;
-use itertools::Itertools;
 use std::sync::{Arc, Mutex};
 
-use miette::{IntoDiagnostic, Result};
+use miette::{miette, Context as _, IntoDiagnostic, Result};
 use openai::{embeddings::EmbeddingsRequest, Client};
-use rusqlite::{params, Connection};
-
-pub use crate::openai::completion::CompletionRequest;
+use rusqlite::Connection;
+use serde::Serialize;
+
+pub use crate::battlesnake::{
+    advance, board_facts, describe_game_state, find_game_state, render_board, Board, BoardFacts,
+    Coord, Direction, Elimination, EliminationCause, GameState, MoveFacts, MoveOutcome,
+    PastedGameState, Rules, Snake, TurnResult, SNAKE_MAX_HEALTH,
+};
+pub use crate::citations::{cited_sources, parse_citations};
+pub use crate::condense::{condense_question, Exchange};
+pub use crate::context::{Context, ContextChunk};
+pub use crate::corpus::{Corpora, Corpus, DEFAULT_CORPUS};
+pub use crate::doctor::{delete_orphans, diagnose, reembed_sentence, Diagnosis, Finding};
+pub use crate::embedding::{decode_embedding, encode_embedding, Quantization};
+pub use crate::eval::{
+    answer_report_markdown, evaluate_answers, evaluate_retrieval, load_golden_questions,
+    AnswerConfig, AnswerEval, AnswerGrade, AnswerResult, GoldenQuestion, Judge, QuestionDiff,
+    QuestionResult, RetrievalEval,
+};
+pub use crate::expansion::{expand_query, QueryExpansion};
+pub use crate::index::{open_index, BruteForceIndex, HnswIndex, IndexName, VectorIndex, VssIndex};
+pub use crate::inspect::{
+    corpus_stats, inspect_page, inspect_sentence, CorpusStats, Neighbour, PageDetails,
+    SentenceDetails, SentenceSummary,
+};
+pub use crate::metadata::{
+    change_embedding_settings, get_metadata, record_build, requantize, set_metadata,
+    EmbeddingSettings,
+};
+pub use crate::openai::completion::{CompletionRequest, CompletionUsage, CHAT_DEFAULT_MODEL};
 pub use crate::openai::{Client as OpenAiClient, Config};
-
+pub use crate::paths::StoragePaths;
+pub use crate::prompts::{PromptName, Prompts};
+pub use crate::rerank::{HttpReranker, LlmReranker, Reranker};
+pub use crate::retrieval::{get_context, get_context_with_options, RetrievalOptions};
+pub use crate::strategy::{
+    AnswerStrategy, OnToken, React, SimpleRag, Step, StrategyName, StrategyResponse, StrategySetup,
+    ToolCall, DEFAULT_MAX_STEPS,
+};
+pub use crate::tools::{
+    doc_tools, ListPages, Observation, ReadPage, SearchDocs, SimulateTurn, Tool,
+};
+pub use shared::{CorpusSelection, Source};
+
+mod battlesnake;
+mod citations;
+mod condense;
+mod context;
+mod corpus;
+mod doctor;
+mod embedding;
+mod eval;
+mod expansion;
+mod index;
+mod inspect;
+mod metadata;
+mod mmr;
 mod openai;
+mod paths;
+mod prompts;
+mod rerank;
+mod retrieval;
 mod schema;
+mod strategy;
+mod tools;
 
 static APP_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"),);
 
 pub const CONCURRENT_REQUESTS: usize = 5;
-pub const DB_NAME: &str = "sample.v0.db";
 
 #[derive(Clone, Debug)]
 pub struct EmbeddingConnection(pub Arc<Mutex<Connection>>);
 
-pub fn setup() -> Result<Connection> {
-    let conn = Connection::open(DB_NAME).into_diagnostic()?;
-    load_my_extension(&conn)?;
-    schema::setup_schema_v0(&conn)?;
-    Ok(conn)
+#[derive(Clone, Debug, Serialize)]
+pub struct Answer {
+    pub text: String,
+    pub sources: Vec<Source>,
+    /// The chat model that wrote the answer
+    pub model: String,
+    /// Token counts for the request, streamed answers don't report them
+    pub usage: Option<CompletionUsage>,
 }
 
-pub async fn respond_to(query: String, conn: EmbeddingConnection) -> Result<(String, String)> {
-    let (context, question) = get_context(query, conn).await?;
+/// Opens the DB for the named corpus, migrating it to the current schema
+///
+/// Searches with the HNSW index when one has been built for the corpus. Otherwise uses
+/// sqlite-vss when its extensions load, and falls back to an in process brute force search
+/// when they don't
+pub fn setup(paths: &StoragePaths, corpus: &str) -> Result<(Connection, Arc<dyn VectorIndex>)> {
+    let db_path = paths.corpus_db_path(corpus);
+    let mut conn = Connection::open(&db_path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Could not open {}", db_path.display()))?;
+
+    let hnsw_path = paths.hnsw_index_path(corpus);
+    let vss_loaded = if hnsw_path.exists() {
+        false
+    } else {
+        match load_my_extension(&conn, paths) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("{e:?}");
+                eprintln!("Falling back to brute force vector search for {corpus}");
+                false
+            }
+        }
+    };
+
+    schema::migrate(&mut conn)?;
+    let index = open_index(&conn, &hnsw_path, vss_loaded)?;
 
-    respond_to_with_context(context, question).await
+    Ok((conn, index))
+}
+
+pub async fn respond_to(prompts: &Prompts, query: String, corpora: &[Corpus]) -> Result<Answer> {
+    let (context, question) = get_context(query, corpora).await?;
+
+    respond_to_with_context(prompts, &context, &question).await
 }
 
 pub async fn respond_to_with_context(
-    context: String,
-    question: String,
-) -> Result<(String, String)> {
+    prompts: &Prompts,
+    context: &Context,
+    question: &str,
+) -> Result<Answer> {
     let config = Config::from_env()?;
     let client = config.client()?;
 
-    let prompt = formatdoc!(
-        "
-      You are a helpful chatbot Answering questions about Battlesnake.
-      Battlesnake is an online competitve programming game.
-      The goal of a battlesnake developer is to build a snake that can survive
-      on the board the longest.
-
-      Your job is to answer the users questions about Battlesnake as accurately as possible.
-      
-
-      Below is some context about the Users qustion. Use it to help you answer the question.
-      After the context will be dashes like this: ----
-      Below the dashes is the users question that you should answer.
-
-      Context:
-      {context}
-
-      --------------------------------------
-
-      {question}
-      "
-    );
+    let prompt = answer_prompt(prompts, context, question);
+    respond_with_prompt(&client, context, &prompt, CHAT_DEFAULT_MODEL).await
+}
 
-    let completion_request = CompletionRequest::gpt_3_5_turbo(&prompt);
+/// Sends an already built answer prompt to a chosen chat model
+///
+/// `context` has to be what the prompt was built from so the citations can be resolved
+pub async fn respond_with_prompt(
+    client: &Client,
+    context: &Context,
+    prompt: &str,
+    model: &str,
+) -> Result<Answer> {
+    let completion_request = CompletionRequest::with_model(prompt, model);
     let answer = client.completion(completion_request).await?;
 
-    let first_choice = answer.choices.first().unwrap().message.content.clone();
-
-    Ok((first_choice, context))
+    let first_choice = answer
+        .choices
+        .first()
+        .ok_or_else(|| miette!("The answer got no choices back"))?
+        .message
+        .content
+        .clone();
+    let sources = cited_sources(&first_choice, context);
+
+    Ok(Answer {
+        text: first_choice,
+        sources,
+        model: answer.model,
+        usage: Some(answer.usage),
+    })
 }
 
-pub async fn get_context(query: String, conn: EmbeddingConnection) -> Result<(String, String)> {
-    let config = Config::from_env()?;
-    let client = config.client()?;
-    let question = &query;
-    let embedding = fetch_embedding(&client, question).await?;
-    let embedding_json = serde_json::to_string(&embedding).into_diagnostic()?;
-    let nearest_embeddings = {
-        let conn = conn.0.lock().unwrap();
-        let mut st = conn
-            .prepare(
-                "select rowid, distance
-  from vss_sentences
-  where vss_search(
-    embedding,
-    vector_from_json(?1)
-  )
-  limit 10;",
-            )
-            .into_diagnostic()?;
-        let nearest_embeddings: Vec<Result<(u32, f64), _>> = st
-            .query_map(params![&embedding_json], |row| {
-                Ok((row.get(0)?, row.get(1)?))
-            })
-            .into_diagnostic()?
-            .collect_vec();
-
-        let nearest_embeddings: Vec<(String, f64)> = nearest_embeddings
-            .into_iter()
-            .map(|result| {
-                let (rowid, distance) = result.into_diagnostic()?;
-                let mut stmt = conn
-                    .prepare("select page_id, page_index from sentences where rowid = ?1")
-                    .into_diagnostic()?;
-                let (page_id, page_index): (u32, u32) = stmt
-                    .query_row(params![rowid], |row| Ok((row.get(0)?, row.get(1)?)))
-                    .into_diagnostic()?;
-
-                let texts = conn
-                    .prepare(
-                        "
-                    select text
-                    from sentences
-                    where page_id = ?1
-                    AND page_index >= (?2 - 3)
-                    AND page_index <= (?2 + 5)",
-                    )
-                    .into_diagnostic()?
-                    .query_map(params![page_id, page_index], |row| row.get(0))
-                    .into_diagnostic()?
-                    .collect::<Result<Vec<String>, rusqlite::Error>>()
-                    .into_diagnostic()?;
-
-                Ok((texts.join("\n"), distance))
-            })
-            .collect::<Result<Vec<_>>>()?;
-
-        nearest_embeddings
-    };
-    let context = nearest_embeddings
-        .iter()
-        .map(|(text, _)| text.trim().replace("\n\n", "\n"))
-        .join("\n\n");
+/// Like [respond_with_prompt], but handing each piece of the answer to `on_token` as it arrives
+pub async fn stream_with_prompt(
+    client: &Client,
+    context: &Context,
+    prompt: &str,
+    model: &str,
+    on_token: impl FnMut(&str),
+) -> Result<Answer> {
+    let text = client
+        .completion_stream(CompletionRequest::with_model(prompt, model), on_token)
+        .await?;
+    let sources = cited_sources(&text, context);
+
+    Ok(Answer {
+        text,
+        sources,
+        model: model.to_string(),
+        usage: None,
+    })
+}
 
-    Ok((context, question.to_owned()))
+/// The full prompt [respond_to_with_context] sends to the model
+pub fn answer_prompt(prompts: &Prompts, context: &Context, question: &str) -> String {
+    prompts.render(
+        PromptName::Answer,
+        &[("context", &context.to_prompt()), ("question", question)],
+    )
 }
 
-fn load_my_extension(conn: &Connection) -> Result<()> {
+fn load_my_extension(conn: &Connection, paths: &StoragePaths) -> Result<()> {
+    let vector0 = paths.extension_path("vector0")?;
+    let vss0 = paths.extension_path("vss0")?;
+
     // Safety: We fully trust the loaded extension and execute no untrusted SQL
     // while extension loading is enabled.
     unsafe {
         conn.load_extension_enable().into_diagnostic()?;
-        conn.load_extension("./vendor/vector0", None)
-            .into_diagnostic()?;
-        conn.load_extension("./vendor/vss0", None)
-            .into_diagnostic()?;
+        let loaded = conn
+            .load_extension(&vector0, None)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not load {}", vector0.display()))
+            .and_then(|_| {
+                conn.load_extension(&vss0, None)
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Could not load {}", vss0.display()))
+            });
+        // Turn loading back off even if it failed, since we keep using the connection
         conn.load_extension_disable().into_diagnostic()?;
 
-        Ok(())
+        loaded
     }
 }
 
 pub async fn fetch_embedding(
     client: &Client,
+    settings: &EmbeddingSettings,
     sentence: &str,
 ) -> Result<Vec<f64>, miette::ErrReport> {
     let embedding_resp = client
-        .embeddings(EmbeddingsRequest::new(sentence.to_string()))
+        .embeddings(EmbeddingsRequest::with_model(
+            sentence.to_string(),
+            settings.model.clone(),
+        ))
         .await?;
     let embedding = embedding_resp.data[0].embedding.clone();
+    settings.check_embedding(&embedding)?;
     Ok(embedding)
 }
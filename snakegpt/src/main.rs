@@ -2,7 +2,7 @@ use std::{
     fs::File,
     io::Write,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    sync::Arc,
 };
 
 use aws_sdk_s3::primitives::ByteStream;
@@ -10,11 +10,21 @@ use clap::*;
 use futures::{stream, StreamExt};
 
 use itertools::Itertools;
-use miette::{IntoDiagnostic, Result};
+use miette::{Context as _, IntoDiagnostic, Result};
 use rusqlite::{params, Connection, OptionalExtension, Row};
+use rustyline::error::ReadlineError;
+use serde::Serialize;
 use snakegpt::{
-    fetch_embedding, respond_to, setup, Config, EmbeddingConnection, OpenAiClient,
-    CONCURRENT_REQUESTS, DB_NAME,
+    advance, answer_report_markdown, board_facts, change_embedding_settings, condense_question,
+    corpus_stats, delete_orphans, diagnose, encode_embedding, evaluate_answers, evaluate_retrieval,
+    fetch_embedding, find_game_state, get_context_with_options, inspect_page, inspect_sentence,
+    load_golden_questions, record_build, reembed_sentence, render_board, requantize, setup,
+    AnswerConfig, AnswerEval, BoardFacts, BruteForceIndex, CompletionUsage, Config, Context,
+    ContextChunk, Corpora, CorpusSelection, Diagnosis, Direction, EmbeddingSettings, Exchange,
+    HnswIndex, HttpReranker, IndexName, Judge, LlmReranker, OpenAiClient, PromptName, Prompts,
+    Quantization, QueryExpansion, QuestionDiff, Reranker, RetrievalEval, RetrievalOptions, Rules,
+    Source, Step, StoragePaths, StrategyName, StrategySetup, VectorIndex, CHAT_DEFAULT_MODEL,
+    CONCURRENT_REQUESTS, DEFAULT_CORPUS, DEFAULT_MAX_STEPS,
 };
 
 #[derive(Args, Debug)]
@@ -23,26 +33,334 @@ struct PrepareArgs {
     /// Path to search for Markdown files
     #[arg(short, long)]
     path: PathBuf,
+    /// Name of the corpus to build, each corpus gets its own DB
+    #[arg(short, long, default_value = DEFAULT_CORPUS)]
+    corpus: String,
+    /// Embedding model to build the DB with. Can only be changed before anything is embedded.
+    /// Defaults to EMBEDDING_MODEL or whatever the DB already uses
+    #[arg(long)]
+    embedding_model: Option<String>,
+    /// Number of dimensions the embedding model returns. Required when changing models
+    #[arg(long)]
+    embedding_dimension: Option<usize>,
+    /// How to store the embeddings, set before anything is embedded. Use `reindex
+    /// --quantization` to convert a DB that already has embeddings. Quantized DBs are much
+    /// smaller but can't be searched with sqlite-vss
+    #[arg(long, value_enum)]
+    quantization: Option<Quantization>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    /// Human readable output
+    Text,
+    /// A single JSON document on stdout, for scripts. Progress and errors go to stderr
+    Json,
+}
+
+impl OutputFormat {
+    fn is_text(self) -> bool {
+        self == OutputFormat::Text
+    }
 }
 
 #[derive(Subcommand, Debug)]
 enum CliCommand {
     Prepare(PrepareArgs),
+    /// Answer a question using context retrieved from the corpora
     Query(QueryArgs),
+    /// Show what would be retrieved for a question, without asking the model to answer it
+    Search(SearchArgs),
+    /// Ask questions interactively, with follow ups that build on earlier answers
+    Chat(ChatArgs),
     Download(DownloadArgs),
+    /// Rebuild the vector search index from scratch
+    Reindex(ReindexArgs),
+    /// Check that pages, sentences and the vector index agree, and optionally repair them
+    Doctor(DoctorArgs),
+    /// Summarize what is in a corpus DB
+    Stats(StatsArgs),
+    /// Look at a single page or sentence in a corpus DB
+    #[command(subcommand)]
+    Inspect(InspectCommand),
+    /// Measure retrieval against a golden set of questions
+    #[command(subcommand)]
+    Eval(EvalCommand),
+    /// Work with the prompt templates sent to the chat model
+    #[command(subcommand)]
+    Prompts(PromptsCommand),
+    /// Show how a Battlesnake game state is understood, the board as the model sees it and
+    /// the facts worked out from it, or play out a turn on it with --move
+    Board(BoardArgs),
+}
+
+#[derive(Args, Debug)]
+struct BoardArgs {
+    /// File holding a game state like a `/move` request body, or a question with one pasted in.
+    /// `-` reads from stdin
+    path: PathBuf,
+    /// Play one turn with this move, like `Me=up`. Repeat for each snake, snakes without a
+    /// move keep going straight
+    #[arg(long = "move", value_name = "SNAKE=MOVE", value_parser = parse_move)]
+    moves: Vec<(String, Direction)>,
+    /// Play the turn with these rules instead of the game's
+    #[arg(long, value_enum)]
+    ruleset: Option<Rules>,
+}
+
+fn parse_move(s: &str) -> Result<(String, Direction), String> {
+    let (snake, direction) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected SNAKE=MOVE, got {s}"))?;
+    let direction = direction
+        .parse()
+        .map_err(|e: miette::Report| e.to_string())?;
+
+    Ok((snake.to_string(), direction))
+}
+
+#[derive(Subcommand, Debug)]
+enum PromptsCommand {
+    /// Show each prompt's variables and the variants in the prompts directory
+    List,
+    /// Write the built in templates to a directory to use as a starting point with --prompts-dir
+    Export(ExportPromptsArgs),
+}
+
+#[derive(Args, Debug)]
+struct ExportPromptsArgs {
+    dir: PathBuf,
+    /// Overwrite templates that are already there
+    #[arg(long)]
+    force: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum EvalCommand {
+    /// Report recall@k, MRR and nDCG, optionally comparing against a second setup
+    Retrieval(EvalRetrievalArgs),
+    /// Have a judge model grade answers for correctness and faithfulness, optionally comparing
+    /// two models or prompts
+    Answers(EvalAnswersArgs),
+}
+
+#[derive(Args, Debug)]
+struct EvalAnswersArgs {
+    /// JSONL file of questions, each with the correct `answer`
+    questions: PathBuf,
+    #[command(flatten)]
+    retrieval: RetrievalArgs,
+    /// Chat model to answer with
+    #[arg(long, default_value = CHAT_DEFAULT_MODEL)]
+    model: String,
+    /// File with a prompt to answer with instead of the built in one.
+    /// `{context}` and `{question}` are filled in
+    #[arg(long)]
+    prompt: Option<PathBuf>,
+    /// Also answer with this chat model and compare the two
+    #[arg(long)]
+    against_model: Option<String>,
+    /// Also answer with this prompt file and compare the two
+    #[arg(long)]
+    against_prompt: Option<PathBuf>,
+    /// Also answer with this prompt variant and compare the two
+    #[arg(long)]
+    against_variant: Option<String>,
+    /// Chat model that grades the answers
+    #[arg(long, default_value = CHAT_DEFAULT_MODEL)]
+    judge_model: String,
+    /// File with a prompt to grade answers with instead of the built in one.
+    /// `{question}`, `{reference}`, `{context}` and `{answer}` are filled in
+    #[arg(long)]
+    judge_prompt: Option<PathBuf>,
+    /// Where to write the report, as REPORT.json and REPORT.md
+    #[arg(long, default_value = "answer-eval")]
+    report: PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct EvalRetrievalArgs {
+    /// JSONL file of questions, each with the `pages` and/or `passages` retrieval should find
+    questions: PathBuf,
+    #[command(flatten)]
+    retrieval: RetrievalArgs,
+    /// Retrieval options to compare against, written like this command's own, eg "--no-mmr -k 5".
+    /// Anything left out uses its default
+    #[arg(long, allow_hyphen_values = true)]
+    against: Option<String>,
+    /// Data directory holding the corpus DBs to compare against, like a copy built with
+    /// different chunking
+    #[arg(long)]
+    against_data_dir: Option<PathBuf>,
+}
+
+/// Parses `eval retrieval --against`
+#[derive(Parser, Debug)]
+#[command(no_binary_name = true)]
+struct AgainstArgs {
+    #[command(flatten)]
+    retrieval: RetrievalArgs,
+}
+
+#[derive(Args, Debug)]
+struct StatsArgs {
+    #[arg(short, long, default_value = DEFAULT_CORPUS)]
+    corpus: String,
+}
+
+#[derive(Subcommand, Debug)]
+enum InspectCommand {
+    /// Show a page and the sentences it was split into
+    Page(InspectPageArgs),
+    /// Show a sentence, the context built around it and its nearest neighbours
+    Sentence(InspectSentenceArgs),
+}
+
+#[derive(Args, Debug)]
+struct InspectPageArgs {
+    /// The page's path, or enough of the end of it to only match one page
+    path: String,
+    #[arg(short, long, default_value = DEFAULT_CORPUS)]
+    corpus: String,
+}
+
+#[derive(Args, Debug)]
+struct InspectSentenceArgs {
+    rowid: i64,
+    #[arg(short, long, default_value = DEFAULT_CORPUS)]
+    corpus: String,
+    /// How many of the closest sentences to show
+    #[arg(long, default_value = "5")]
+    neighbours: usize,
+}
+
+#[derive(Args, Debug)]
+struct DoctorArgs {
+    #[arg(short, long, default_value = DEFAULT_CORPUS)]
+    corpus: String,
+    /// Embed sentences that are missing an embedding or have an unreadable one
+    #[arg(long)]
+    reembed: bool,
+    /// Delete sentences whose page no longer exists
+    #[arg(long)]
+    delete_orphans: bool,
+    /// Rebuild the vector index when it doesn't match the sentences
+    #[arg(long)]
+    reindex: bool,
+}
+
+#[derive(Args, Debug)]
+struct ReindexArgs {
+    #[arg(short, long, default_value = DEFAULT_CORPUS)]
+    corpus: String,
+    /// Switch the corpus to a different kind of index. Defaults to whatever it uses now
+    #[arg(long, value_enum)]
+    index: Option<IndexKind>,
+    /// Convert the stored embeddings to a smaller format before rebuilding the index, to
+    /// shrink a corpus that is already embedded
+    #[arg(long, value_enum)]
+    quantization: Option<Quantization>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum IndexKind {
+    /// An in process HNSW graph saved next to the DB, needs no native extensions
+    Hnsw,
+    /// sqlite-vss, or brute force search when its extensions can't be loaded
+    Vss,
 }
 
 #[derive(Args, Debug)]
 struct QueryArgs {
     query: String,
+    /// Print the full prompt sent to the model before the answer
     #[arg(short = 'p', long, default_value = "false")]
     show_prompt: bool,
+    /// How to go from the question to an answer
+    #[arg(long, value_enum, default_value = "simple-rag")]
+    strategy: StrategyName,
+    /// How many tools strategies like react may call before they have to answer
+    #[arg(long, default_value_t = DEFAULT_MAX_STEPS)]
+    max_steps: usize,
+    #[command(flatten)]
+    retrieval: RetrievalArgs,
+}
+
+#[derive(Args, Debug)]
+struct ChatArgs {
+    /// Chat model to answer with, can be changed during the chat with /model
+    #[arg(long, default_value = CHAT_DEFAULT_MODEL)]
+    model: String,
+    /// How to go from a question to an answer, can be changed during the chat with /strategy
+    #[arg(long, value_enum, default_value = "simple-rag")]
+    strategy: StrategyName,
+    /// How many tools strategies like react may call before they have to answer
+    #[arg(long, default_value_t = DEFAULT_MAX_STEPS)]
+    max_steps: usize,
+    #[command(flatten)]
+    retrieval: RetrievalArgs,
+}
+
+#[derive(Args, Debug)]
+struct SearchArgs {
+    query: String,
+    #[command(flatten)]
+    retrieval: RetrievalArgs,
+}
+
+// How context is retrieved, shared by every command that searches
+#[derive(Args, Debug)]
+struct RetrievalArgs {
+    /// Corpus to search, as `name` or `name:weight`. Repeat to search several at once
+    #[arg(short, long = "corpus", default_value = DEFAULT_CORPUS)]
+    corpora: Vec<CorpusSelection>,
+    /// How many chunks of context to keep
+    #[arg(short = 'k', long, default_value = "10")]
+    limit: usize,
+    /// MMR lambda used to re-rank search hits, 1.0 is pure relevance and 0.0 is pure diversity
+    #[arg(long, default_value = "0.7")]
+    mmr_lambda: f64,
+    /// Skip MMR re-ranking and use the raw vector search order
+    #[arg(long, default_value = "false")]
+    no_mmr: bool,
+    /// Score the candidates with a reranker and keep the best ones
+    #[arg(long, value_enum)]
+    rerank: Option<RerankerKind>,
+    /// How many vector search hits to consider before re-ranking.
+    /// Defaults to 50 with --rerank and 30 otherwise
+    #[arg(long)]
+    candidates: Option<usize>,
+    /// Have the model rewrite the question before searching
+    #[arg(long, value_enum)]
+    expand: Option<ExpansionKind>,
+    /// How many rewrites to search with when using --expand multi-query
+    #[arg(long, default_value = "3")]
+    expansions: usize,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ExpansionKind {
+    /// Search with several rewordings of the question
+    MultiQuery,
+    /// Search with a hypothetical answer to the question
+    Hyde,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum RerankerKind {
+    /// Ask the chat model to score each candidate
+    Llm,
+    /// Call the reranker at RERANKER_URL
+    Http,
 }
 
 #[derive(Args, Debug)]
 struct DownloadArgs {
     #[arg(short, long)]
     project: String,
+    /// Name of the corpus to save the download as
+    #[arg(short, long, default_value = DEFAULT_CORPUS)]
+    corpus: String,
 }
 
 /// SnakeGPT
@@ -54,38 +372,1140 @@ struct DownloadArgs {
 struct CliArgs {
     #[clap(subcommand)]
     command: CliCommand,
+    /// Directory holding the corpus DBs
+    #[arg(long, global = true, env = "SNAKEGPT_DATA_DIR", default_value = ".")]
+    data_dir: PathBuf,
+    /// Directory holding the sqlite-vss vector0 and vss0 extensions
+    #[arg(
+        long,
+        global = true,
+        env = "SNAKEGPT_VENDOR_DIR",
+        default_value = "./vendor"
+    )]
+    vendor_dir: PathBuf,
+    /// How to print results. `chat` always prints text
+    #[arg(long, global = true, value_enum, default_value = "text")]
+    format: OutputFormat,
+    /// Directory of prompt templates overriding the built in ones, see `prompts list`
+    #[arg(long, global = true, env = "SNAKEGPT_PROMPTS_DIR")]
+    prompts_dir: Option<PathBuf>,
+    /// Named prompt variant from the prompts directory to use instead of the defaults
+    #[arg(long, global = true, env = "SNAKEGPT_PROMPT_VARIANT")]
+    prompt_variant: Option<String>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = CliArgs::parse();
+    let paths = StoragePaths {
+        data_dir: args.data_dir,
+        vendor_dir: args.vendor_dir,
+    };
+
+    let format = args.format;
+    let prompts = match &args.prompts_dir {
+        Some(dir) => Prompts::load(dir)?,
+        None => Prompts::default(),
+    }
+    .with_variant(args.prompt_variant.as_deref())?;
 
     match args.command {
-        CliCommand::Prepare(args) => prepare(args).await,
-        CliCommand::Query(args) => query(args).await,
-        CliCommand::Download(args) => download(args).await,
+        CliCommand::Prepare(args) => prepare(&paths, &prompts, args, format).await,
+        CliCommand::Query(args) => query(&paths, &prompts, args, format).await,
+        CliCommand::Search(args) => search(&paths, &prompts, args, format).await,
+        CliCommand::Chat(args) => chat(&paths, &prompts, args).await,
+        CliCommand::Download(args) => download(&paths, args, format).await,
+        CliCommand::Reindex(args) => reindex(&paths, args, format),
+        CliCommand::Doctor(args) => doctor(&paths, args, format).await,
+        CliCommand::Stats(args) => stats(&paths, args, format),
+        CliCommand::Inspect(InspectCommand::Page(args)) => {
+            inspect_page_command(&paths, args, format)
+        }
+        CliCommand::Inspect(InspectCommand::Sentence(args)) => {
+            inspect_sentence_command(&paths, args, format)
+        }
+        CliCommand::Eval(EvalCommand::Retrieval(args)) => {
+            eval_retrieval(&paths, &prompts, args, format).await
+        }
+        CliCommand::Eval(EvalCommand::Answers(args)) => {
+            eval_answers(&paths, &prompts, args, format).await
+        }
+        CliCommand::Prompts(PromptsCommand::List) => list_prompts(&prompts),
+        CliCommand::Prompts(PromptsCommand::Export(args)) => export_prompts(args),
+        CliCommand::Board(args) => board(args, format),
+    }
+}
+
+impl RetrievalArgs {
+    fn options(&self, prompts: &Prompts) -> Result<RetrievalOptions> {
+        let reranker: Option<Arc<dyn Reranker>> = match self.rerank {
+            Some(RerankerKind::Llm) => Some(Arc::new(LlmReranker::new(
+                Config::from_env()?,
+                prompts.clone(),
+            ))),
+            Some(RerankerKind::Http) => {
+                Some(Arc::new(HttpReranker::from_env().ok_or_else(|| {
+                    miette::miette!("RERANKER_URL must be set to use the http reranker")
+                })?))
+            }
+            None => None,
+        };
+        let default_candidates = if reranker.is_some() { 50 } else { 30 };
+
+        Ok(RetrievalOptions {
+            limit: self.limit,
+            candidates: self
+                .candidates
+                .unwrap_or(default_candidates)
+                .max(self.limit),
+            mmr_lambda: (!self.no_mmr).then_some(self.mmr_lambda),
+            reranker,
+            expansion: match self.expand {
+                Some(ExpansionKind::MultiQuery) => QueryExpansion::MultiQuery(self.expansions),
+                Some(ExpansionKind::Hyde) => QueryExpansion::Hyde,
+                None => QueryExpansion::None,
+            },
+            prompts: prompts.clone(),
+        })
+    }
+}
+
+fn print_json(value: &impl Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value).into_diagnostic()?);
+
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+struct QueryOutput<'a> {
+    query: &'a str,
+    strategy: StrategyName,
+    /// The question the context was retrieved for, after any rewriting
+    question: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt: Option<&'a str>,
+    answer: &'a str,
+    sources: &'a [Source],
+    context: &'a [ContextChunk],
+    model: &'a str,
+    usage: Option<&'a CompletionUsage>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    trace: &'a [Step],
+}
+
+#[derive(Serialize, Debug)]
+struct SearchOutput<'a> {
+    query: &'a str,
+    question: &'a str,
+    context: &'a [ContextChunk],
+}
+
+async fn query(
+    paths: &StoragePaths,
+    prompts: &Prompts,
+    args: QueryArgs,
+    format: OutputFormat,
+) -> Result<()> {
+    if format.is_text() {
+        println!("Query: {}", &args.query);
+    }
+
+    let strategy = args.strategy.build(StrategySetup {
+        config: Config::from_env()?,
+        corpora: Corpora::open(paths, &args.retrieval.corpora)?.0,
+        options: args.retrieval.options(prompts)?,
+        prompts: prompts.clone(),
+        model: CHAT_DEFAULT_MODEL.to_string(),
+        max_steps: args.max_steps,
+    });
+    let response = strategy.answer(&args.query, None).await?;
+    let ans = &response.answer;
+
+    let prompt = args.show_prompt.then_some(response.prompt.as_str());
+    if !format.is_text() {
+        return print_json(&QueryOutput {
+            query: &args.query,
+            strategy: response.strategy,
+            question: &response.question,
+            prompt,
+            answer: &ans.text,
+            sources: &ans.sources,
+            context: &response.context.chunks,
+            model: &ans.model,
+            usage: ans.usage.as_ref(),
+            trace: &response.trace,
+        });
+    }
+
+    if let Some(prompt) = prompt {
+        println!("Prompt:\n{prompt}");
+    }
+    print_trace(&response.trace);
+    println!("Answer: {}", ans.text);
+
+    if !ans.sources.is_empty() {
+        println!("Sources:");
+        print_sources(&ans.sources);
+    }
+
+    Ok(())
+}
+
+async fn search(
+    paths: &StoragePaths,
+    prompts: &Prompts,
+    args: SearchArgs,
+    format: OutputFormat,
+) -> Result<()> {
+    if format.is_text() {
+        println!("Query: {}", &args.query);
+    }
+
+    let corpora = Corpora::open(paths, &args.retrieval.corpora)?;
+    let options = args.retrieval.options(prompts)?;
+    let (context, question) =
+        get_context_with_options(args.query.to_string(), &corpora.0, &options).await?;
+
+    if !format.is_text() {
+        return print_json(&SearchOutput {
+            query: &args.query,
+            question: &question,
+            context: &context.chunks,
+        });
+    }
+
+    if context.chunks.is_empty() {
+        println!("Nothing found");
+    }
+    print_chunks(&context);
+
+    Ok(())
+}
+
+fn print_chunks(context: &Context) {
+    for (i, chunk) in context.chunks.iter().enumerate() {
+        println!(
+            "\n[{number}] {distance:.4} {corpus}: {path}",
+            number = i + 1,
+            distance = chunk.distance,
+            corpus = chunk.corpus,
+            path = chunk.page_path
+        );
+        for line in chunk.text.lines().filter(|line| !line.trim().is_empty()) {
+            println!("    {}", line.trim());
+        }
+    }
+}
+
+/// Each step a strategy took, with just the first line of what the tools returned
+fn print_trace(trace: &[Step]) {
+    for step in trace {
+        println!("Thought: {}", step.thought);
+        if let Some(action) = &step.action {
+            println!("Action: {}({})", action.tool, action.input);
+        }
+        if let Some(observation) = &step.observation {
+            println!(
+                "Observation: {}",
+                observation.lines().next().unwrap_or_default()
+            );
+        }
+    }
+}
+
+fn print_sources(sources: &[Source]) {
+    for source in sources {
+        println!("  [{}] {}: {}", source.number, source.corpus, source.path);
+    }
+}
+
+const CHAT_HELP: &str = "Commands:
+  /context          Show what was retrieved for the last question
+  /sources          Show the sources cited in the last answer
+  /trace            Show the steps taken for the last answer, for strategies like react
+  /reset            Forget the conversation so far
+  /model [name]     Show or change the chat model
+  /strategy [name]  Show or change how questions are answered
+  /help             Show this message
+  /quit             Leave the chat";
+
+async fn chat(paths: &StoragePaths, prompts: &Prompts, args: ChatArgs) -> Result<()> {
+    let corpora = Corpora::open(paths, &args.retrieval.corpora)?;
+    let options = args.retrieval.options(prompts)?;
+    let config = Config::from_env()?;
+    let client = config.client()?;
+
+    let mut editor = rustyline::DefaultEditor::new().into_diagnostic()?;
+    let history_path = paths.data_dir.join(".snakegpt_history");
+    // There is no history file the first time
+    let _ = editor.load_history(&history_path);
+
+    let mut model = args.model;
+    let mut strategy = args.strategy;
+    let mut exchanges: Vec<Exchange> = vec![];
+    let mut last_context: Option<Context> = None;
+    let mut last_sources: Vec<Source> = vec![];
+    let mut last_trace: Vec<Step> = vec![];
+
+    println!(
+        "Chatting about {} with {model}. Type /help for commands",
+        corpora.names().join(", ")
+    );
+
+    loop {
+        let line = match editor.readline(">> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e).into_diagnostic(),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line).into_diagnostic()?;
+
+        if let Some(command) = line.strip_prefix('/') {
+            let (command, argument) = command
+                .split_once(char::is_whitespace)
+                .map_or((command, ""), |(command, argument)| {
+                    (command, argument.trim())
+                });
+
+            match command {
+                "context" => match &last_context {
+                    Some(context) => print_chunks(context),
+                    None => println!("Nothing has been retrieved yet"),
+                },
+                "sources" if last_sources.is_empty() => println!("No sources were cited"),
+                "sources" => print_sources(&last_sources),
+                "trace" if last_trace.is_empty() => println!("No steps were recorded"),
+                "trace" => print_trace(&last_trace),
+                "reset" => {
+                    exchanges.clear();
+                    last_context = None;
+                    last_sources.clear();
+                    last_trace.clear();
+                    println!("Started a new conversation");
+                }
+                "model" if argument.is_empty() => println!("Answering with {model}"),
+                "model" => {
+                    model = argument.to_string();
+                    println!("Now answering with {model}");
+                }
+                "strategy" if argument.is_empty() => println!("Answering with {strategy}"),
+                "strategy" => match argument.parse() {
+                    Ok(name) => {
+                        strategy = name;
+                        println!("Now answering with {strategy}");
+                    }
+                    Err(e) => println!("{e}"),
+                },
+                "help" => println!("{CHAT_HELP}"),
+                "quit" | "exit" => break,
+                _ => println!("Unknown command /{command}\n{CHAT_HELP}"),
+            }
+            continue;
+        }
+
+        let turn = async {
+            let standalone_question = condense_question(&client, prompts, &exchanges, line).await?;
+            if standalone_question != line {
+                println!("(searching for: {standalone_question})");
+            }
+
+            let strategy = strategy.build(StrategySetup {
+                config: config.clone(),
+                corpora: corpora.0.clone(),
+                options: options.clone(),
+                prompts: prompts.clone(),
+                model: model.clone(),
+                max_steps: args.max_steps,
+            });
+            let response = strategy
+                .answer(
+                    &standalone_question,
+                    Some(&mut |token: &str| {
+                        print!("{token}");
+                        let _ = std::io::stdout().flush();
+                    }),
+                )
+                .await?;
+            println!();
+
+            Result::<_>::Ok(response)
+        };
+
+        // A failed question shouldn't end the chat
+        match turn.await {
+            Ok(response) => {
+                let answer = response.answer;
+                print_sources(&answer.sources);
+
+                exchanges.push(Exchange {
+                    question: line.to_string(),
+                    answer: answer.text,
+                });
+                last_context = Some(response.context);
+                last_sources = answer.sources;
+                last_trace = response.trace;
+            }
+            Err(e) => eprintln!("{e:?}"),
+        }
+    }
+
+    editor.save_history(&history_path).into_diagnostic()?;
+
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+struct ReindexOutput<'a> {
+    corpus: &'a str,
+    index: IndexName,
+    /// How many embeddings were converted by --quantization
+    requantized: usize,
+    indexed_sentences: usize,
+    duration_secs: f64,
+}
+
+#[derive(Serialize, Debug)]
+struct EvalRetrievalOutput {
+    baseline: RetrievalEval,
+    against: Option<RetrievalEval>,
+    /// Questions that scored differently in `against`
+    diffs: Vec<QuestionDiff>,
+}
+
+async fn eval_retrieval(
+    paths: &StoragePaths,
+    prompts: &Prompts,
+    args: EvalRetrievalArgs,
+    format: OutputFormat,
+) -> Result<()> {
+    let questions = load_golden_questions(&args.questions)?;
+
+    let corpora = Corpora::open(paths, &args.retrieval.corpora)?;
+    let baseline =
+        evaluate_retrieval(&questions, &corpora.0, &args.retrieval.options(prompts)?).await?;
+
+    let against_args = match &args.against {
+        Some(against) => Some(
+            AgainstArgs::try_parse_from(against.split_whitespace())
+                .into_diagnostic()?
+                .retrieval,
+        ),
+        None => None,
+    };
+    let against = if against_args.is_some() || args.against_data_dir.is_some() {
+        let retrieval = against_args.as_ref().unwrap_or(&args.retrieval);
+        let against_paths = StoragePaths {
+            data_dir: args
+                .against_data_dir
+                .clone()
+                .unwrap_or_else(|| paths.data_dir.clone()),
+            vendor_dir: paths.vendor_dir.clone(),
+        };
+        let corpora = Corpora::open(&against_paths, &retrieval.corpora)?;
+
+        Some(evaluate_retrieval(&questions, &corpora.0, &retrieval.options(prompts)?).await?)
+    } else {
+        None
+    };
+
+    let output = EvalRetrievalOutput {
+        diffs: against
+            .as_ref()
+            .map(|against| baseline.diff(against))
+            .unwrap_or_default(),
+        baseline,
+        against,
+    };
+    if !format.is_text() {
+        return print_json(&output);
+    }
+
+    print_retrieval_eval("Baseline", &output.baseline);
+    let Some(against) = &output.against else {
+        for result in &output.baseline.questions {
+            println!(
+                "  recall {:.2}  RR {:.2}  nDCG {:.2}  {}",
+                result.recall, result.reciprocal_rank, result.ndcg, result.question
+            );
+            for missed in &result.missed {
+                println!("    missed: {missed}");
+            }
+        }
+
+        return Ok(());
+    };
+
+    print_retrieval_eval("Against", against);
+    if output.diffs.is_empty() {
+        println!("No question scored differently");
+    }
+    for diff in &output.diffs {
+        println!(
+            "  recall {:.2} -> {:.2}  RR {:.2} -> {:.2}  nDCG {:.2} -> {:.2}  {}",
+            diff.recall.0,
+            diff.recall.1,
+            diff.reciprocal_rank.0,
+            diff.reciprocal_rank.1,
+            diff.ndcg.0,
+            diff.ndcg.1,
+            diff.question
+        );
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+struct EvalAnswersOutput<'a> {
+    judge_model: &'a str,
+    evals: &'a [AnswerEval],
+}
+
+async fn eval_answers(
+    paths: &StoragePaths,
+    prompts: &Prompts,
+    args: EvalAnswersArgs,
+    format: OutputFormat,
+) -> Result<()> {
+    let questions = load_golden_questions(&args.questions)?;
+    let corpora = Corpora::open(paths, &args.retrieval.corpora)?;
+    let options = args.retrieval.options(prompts)?;
+    let client = Config::from_env()?.client()?;
+
+    let mut configs = vec![answer_config(
+        "baseline",
+        &args.model,
+        prompts,
+        args.prompt.as_deref(),
+    )?];
+    if args.against_model.is_some()
+        || args.against_prompt.is_some()
+        || args.against_variant.is_some()
+    {
+        let against_prompts = match &args.against_variant {
+            Some(variant) => prompts.with_variant(Some(variant))?,
+            None => prompts.clone(),
+        };
+        configs.push(answer_config(
+            "against",
+            args.against_model.as_deref().unwrap_or(&args.model),
+            &against_prompts,
+            args.against_prompt.as_deref().or(args.prompt.as_deref()),
+        )?);
     }
+    let judge = Judge {
+        model: args.judge_model,
+        prompts: match &args.judge_prompt {
+            Some(path) => prompts.with_template(PromptName::Judge, read_prompt(path)?)?,
+            None => prompts.clone(),
+        },
+    };
+
+    let evals =
+        evaluate_answers(&client, &questions, &corpora.0, &options, &configs, &judge).await?;
+
+    let output = EvalAnswersOutput {
+        judge_model: &judge.model,
+        evals: &evals,
+    };
+    let json_path = args.report.with_extension("json");
+    let markdown_path = args.report.with_extension("md");
+    std::fs::write(
+        &json_path,
+        serde_json::to_string_pretty(&output).into_diagnostic()?,
+    )
+    .into_diagnostic()?;
+    std::fs::write(&markdown_path, answer_report_markdown(&evals, &judge)).into_diagnostic()?;
+
+    if !format.is_text() {
+        return print_json(&output);
+    }
+
+    for eval in &evals {
+        println!(
+            "{} with {}: correctness {:.2}/5  faithfulness {:.2}/5  ({} graded, {} failed)",
+            eval.config.name,
+            eval.config.model,
+            eval.correctness,
+            eval.faithfulness,
+            eval.graded,
+            eval.failed
+        );
+    }
+    println!(
+        "Wrote {} and {}",
+        json_path.display(),
+        markdown_path.display()
+    );
+
+    Ok(())
+}
+
+fn answer_config(
+    name: &str,
+    model: &str,
+    prompts: &Prompts,
+    prompt: Option<&Path>,
+) -> Result<AnswerConfig> {
+    let details = prompts
+        .variant()
+        .map(|variant| format!("{variant} prompts"))
+        .into_iter()
+        .chain(prompt.map(|path| path.display().to_string()))
+        .collect_vec();
+
+    Ok(AnswerConfig {
+        name: if details.is_empty() {
+            name.to_string()
+        } else {
+            format!("{name} ({})", details.join(", "))
+        },
+        model: model.to_string(),
+        prompts: match prompt {
+            Some(path) => prompts.with_template(PromptName::Answer, read_prompt(path)?)?,
+            None => prompts.clone(),
+        },
+    })
 }
 
-async fn query(args: QueryArgs) -> Result<()> {
-    println!("Query: {}", &args.query);
+#[derive(Serialize, Debug)]
+struct BoardOutput<'a> {
+    /// The words of the question, what the docs are searched with
+    question: &'a str,
+    board: String,
+    facts: BoardFacts,
+}
 
-    let conn = setup()?;
-    let conn = Arc::new(Mutex::new(conn));
-    let conn = EmbeddingConnection(conn);
-    let ans = respond_to(args.query.to_string(), conn).await?;
+fn board(args: BoardArgs, format: OutputFormat) -> Result<()> {
+    let text = if args.path == Path::new("-") {
+        std::io::read_to_string(std::io::stdin()).into_diagnostic()?
+    } else {
+        std::fs::read_to_string(&args.path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not read {}", args.path.display()))?
+    };
 
-    println!("Answer: {}", ans.0);
+    let pasted = find_game_state(&text).ok_or_else(|| {
+        miette::miette!(
+            help = "It needs at least a `board` with a width, height and snakes",
+            "No Battlesnake game state found in {}",
+            args.path.display()
+        )
+    })?;
+    let mut state = pasted.state;
+    if let Some(ruleset) = args.ruleset {
+        state.game.ruleset.name = ruleset.to_string();
+    }
+
+    if !args.moves.is_empty() {
+        let turn = advance(&state, &args.moves)?;
+        if !format.is_text() {
+            return print_json(&turn);
+        }
+        print!("{}", turn.to_prompt());
+        return Ok(());
+    }
+
+    if !format.is_text() {
+        return print_json(&BoardOutput {
+            question: &pasted.question,
+            board: render_board(&state),
+            facts: board_facts(&state),
+        });
+    }
+
+    println!("Question: {}\n", pasted.question);
+    println!("{}", state.to_prompt());
+
+    Ok(())
+}
+
+fn list_prompts(prompts: &Prompts) -> Result<()> {
+    let variants = prompts.variants();
+
+    for name in PromptName::ALL {
+        let variables = name
+            .variables()
+            .iter()
+            .map(|variable| format!("{{{variable}}}"))
+            .join(" ");
+        println!("{name}: {variables}");
+    }
+    if variants.is_empty() {
+        println!("No variants");
+    } else {
+        println!("Variants: {}", variants.join(", "));
+    }
+    if let Some(variant) = prompts.variant() {
+        println!("Using the {variant} variant");
+    }
+
+    Ok(())
+}
+
+fn export_prompts(args: ExportPromptsArgs) -> Result<()> {
+    std::fs::create_dir_all(&args.dir).into_diagnostic()?;
+
+    for name in PromptName::ALL {
+        let path = args.dir.join(format!("{name}.txt"));
+        if path.exists() && !args.force {
+            println!("Skipping {}, it already exists", path.display());
+            continue;
+        }
+
+        std::fs::write(&path, name.embedded()).into_diagnostic()?;
+        println!("Wrote {}", path.display());
+    }
 
     Ok(())
 }
 
-async fn prepare(args: PrepareArgs) -> Result<()> {
-    let conn = setup()?;
+fn read_prompt(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Could not read the prompt in {}", path.display()))
+}
+
+fn print_retrieval_eval(label: &str, eval: &RetrievalEval) {
+    println!(
+        "{label}: recall@{k} {:.3}  MRR {:.3}  nDCG@{k} {:.3}  ({} questions)",
+        eval.recall,
+        eval.mrr,
+        eval.ndcg,
+        eval.questions.len(),
+        k = eval.k
+    );
+}
+
+fn reindex(paths: &StoragePaths, args: ReindexArgs, format: OutputFormat) -> Result<()> {
+    let hnsw_path = paths.hnsw_index_path(&args.corpus);
+    if matches!(args.index, Some(IndexKind::Vss)) && hnsw_path.exists() {
+        std::fs::remove_file(&hnsw_path).into_diagnostic()?;
+    }
+
+    // Converted before the index is opened, so it is picked for the new format
+    let requantized = match args.quantization {
+        Some(quantization) => {
+            let (mut conn, _) = setup(paths, &args.corpus)?;
+            requantize(&mut conn, quantization)?
+        }
+        None => 0,
+    };
+
+    let (conn, index) = setup(paths, &args.corpus)?;
+    let index: Arc<dyn VectorIndex> = match args.index {
+        Some(IndexKind::Hnsw) if index.name() != IndexName::Hnsw => {
+            Arc::new(HnswIndex::new(&hnsw_path))
+        }
+        _ => index,
+    };
+
+    let started = std::time::Instant::now();
+    let settings = EmbeddingSettings::load(&conn)?;
+    index.rebuild(&conn, settings.dimension)?;
+    index.flush()?;
+
+    if !format.is_text() {
+        return print_json(&ReindexOutput {
+            corpus: &args.corpus,
+            index: index.name(),
+            requantized,
+            indexed_sentences: index.count(&conn)?,
+            duration_secs: started.elapsed().as_secs_f64(),
+        });
+    }
+
+    if requantized > 0 {
+        println!(
+            "Converted {requantized} embeddings to {}",
+            settings.quantization
+        );
+    }
+    println!(
+        "Rebuilt the {} vector index in {:?}",
+        index.name(),
+        started.elapsed()
+    );
+
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+struct DoctorOutput<'a> {
+    corpus: &'a str,
+    index: IndexName,
+    diagnosis: Diagnosis,
+    deleted_orphans: usize,
+    reembedded: usize,
+    reembed_errors: Vec<String>,
+    reindexed: bool,
+    /// The state after any repairs, missing when none were asked for
+    repaired: Option<Diagnosis>,
+}
+
+async fn doctor(paths: &StoragePaths, args: DoctorArgs, format: OutputFormat) -> Result<()> {
+    let (conn, index) = setup(paths, &args.corpus)?;
+    let index = index.as_ref();
+
+    let mut diagnosis = diagnose(&conn, index)?;
+    if format.is_text() {
+        print_diagnosis(&args.corpus, index, &diagnosis);
+    }
+    let mut output = DoctorOutput {
+        corpus: &args.corpus,
+        index: index.name(),
+        diagnosis: diagnosis.clone(),
+        deleted_orphans: 0,
+        reembedded: 0,
+        reembed_errors: vec![],
+        reindexed: false,
+        repaired: None,
+    };
+
+    if args.delete_orphans && diagnosis.orphan_sentences.count() > 0 {
+        output.deleted_orphans = delete_orphans(&conn, index, &diagnosis)?;
+        if format.is_text() {
+            println!("Deleted {} orphan sentences", output.deleted_orphans);
+        }
+        diagnosis = diagnose(&conn, index)?;
+    }
+
+    let to_reembed = diagnosis
+        .missing_embeddings
+        .rowids
+        .iter()
+        .chain(&diagnosis.invalid_embeddings.rowids)
+        .copied()
+        .collect_vec();
+    if args.reembed && !to_reembed.is_empty() {
+        let config = Config::from_env()?;
+        let client = config.client()?;
+        let settings = EmbeddingSettings::load(&conn)?;
+
+        let results: Vec<Result<()>> = stream::iter(to_reembed)
+            .map(|rowid| reembed_sentence(&conn, index, &client, &settings, rowid))
+            .buffer_unordered(CONCURRENT_REQUESTS)
+            .collect()
+            .await;
+        let errors = results
+            .iter()
+            .filter_map(|result| result.as_ref().err())
+            .collect_vec();
+        for e in errors.iter().take(5) {
+            eprintln!("Got an error: {}", e);
+        }
+        output.reembedded = results.len() - errors.len();
+        output.reembed_errors = errors.iter().map(|e| e.to_string()).collect();
+        if format.is_text() {
+            println!(
+                "Re-embedded {} sentences, {} failed",
+                output.reembedded,
+                errors.len()
+            );
+        }
+        diagnosis = diagnose(&conn, index)?;
+    }
+
+    if args.reindex && !diagnosis.index_in_sync() {
+        let settings = EmbeddingSettings::load(&conn)?;
+        index.rebuild(&conn, settings.dimension)?;
+        output.reindexed = true;
+        if format.is_text() {
+            println!("Rebuilt the {} vector index", index.name());
+        }
+        diagnosis = diagnose(&conn, index)?;
+    }
+    index.flush()?;
+
+    let repaired = args.delete_orphans || args.reembed || args.reindex;
+    if !format.is_text() {
+        output.repaired = repaired.then_some(diagnosis);
+        return print_json(&output);
+    }
+    if repaired {
+        print_diagnosis(&args.corpus, index, &diagnosis);
+    }
+
+    Ok(())
+}
+
+fn print_diagnosis(corpus: &str, index: &dyn VectorIndex, diagnosis: &Diagnosis) {
+    if diagnosis.is_healthy() {
+        println!(
+            "{corpus} looks healthy: {} sentences embedded and indexed",
+            diagnosis.embedded_sentences
+        );
+        return;
+    }
+
+    println!("Problems found in {corpus}:");
+    let findings = [
+        ("sentences have no embedding", &diagnosis.missing_embeddings),
+        (
+            "sentences have an unreadable embedding",
+            &diagnosis.invalid_embeddings,
+        ),
+        (
+            "sentences belong to a page that doesn't exist",
+            &diagnosis.orphan_sentences,
+        ),
+        (
+            "pages were never split into sentences",
+            &diagnosis.unparsed_pages,
+        ),
+    ];
+    for (description, finding) in findings {
+        if finding.count() == 0 {
+            continue;
+        }
+
+        println!("  {} {description}", finding.count());
+        for sample in &finding.samples {
+            println!("    {sample}");
+        }
+    }
+
+    if !diagnosis.index_in_sync() {
+        println!(
+            "  The {} index has {} sentences but {} are embedded",
+            index.name(),
+            diagnosis.indexed_sentences,
+            diagnosis.embedded_sentences
+        );
+    }
+}
+
+fn stats(paths: &StoragePaths, args: StatsArgs, format: OutputFormat) -> Result<()> {
+    let (conn, index) = setup(paths, &args.corpus)?;
+    let stats = corpus_stats(&conn, index.as_ref(), paths, &args.corpus)?;
+
+    if !format.is_text() {
+        return print_json(&stats);
+    }
+
+    println!("Corpus: {}", stats.corpus);
+    println!("Pages: {}", stats.pages);
+    println!(
+        "Sentences: {} ({} embedded, {:.1}%)",
+        stats.sentences,
+        stats.embedded_sentences,
+        stats.embedding_coverage() * 100.0
+    );
+    println!(
+        "Average sentence length: {:.1} characters",
+        stats.average_sentence_length
+    );
+    println!(
+        "Embeddings: {} ({} dimensions, {})",
+        stats.embedding.model, stats.embedding.dimension, stats.embedding.quantization
+    );
+    match stats.index_bytes {
+        Some(bytes) => println!(
+            "Vector index: {} ({} sentences, {})",
+            stats.index,
+            stats.indexed_sentences,
+            format_bytes(bytes)
+        ),
+        None => println!(
+            "Vector index: {} ({} sentences)",
+            stats.index, stats.indexed_sentences
+        ),
+    }
+    println!("DB size: {}", format_bytes(stats.db_bytes));
+    println!("Schema version: {}", stats.schema_version);
+    match (stats.built_at, stats.build_duration_secs) {
+        (Some(built_at), Some(duration)) => println!("Last built: {built_at} in {duration}s"),
+        (Some(built_at), None) => println!("Last built: {built_at}"),
+        _ => println!("Last built: unknown"),
+    }
+
+    Ok(())
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+fn inspect_page_command(
+    paths: &StoragePaths,
+    args: InspectPageArgs,
+    format: OutputFormat,
+) -> Result<()> {
+    let (conn, _index) = setup(paths, &args.corpus)?;
+    let page = inspect_page(&conn, &args.path)?;
+
+    if !format.is_text() {
+        return print_json(&page);
+    }
+
+    println!("Page #{}: {}", page.rowid, page.path);
+    match page.parsed_length {
+        Some(length) => println!("Parsed text: {length} characters"),
+        None => println!("Parsed text: never split into sentences"),
+    }
+    println!("Sentences: {}", page.sentences.len());
+    for sentence in page.sentences {
+        let marker = if sentence.embedded {
+            ""
+        } else {
+            " (not embedded)"
+        };
+        println!(
+            "  [{}] #{}{marker}: {}",
+            sentence.page_index,
+            sentence.rowid,
+            sentence.text.trim()
+        );
+    }
+
+    Ok(())
+}
+
+fn inspect_sentence_command(
+    paths: &StoragePaths,
+    args: InspectSentenceArgs,
+    format: OutputFormat,
+) -> Result<()> {
+    let (conn, index) = setup(paths, &args.corpus)?;
+    let sentence = inspect_sentence(
+        &conn,
+        index.as_ref(),
+        &args.corpus,
+        args.rowid,
+        args.neighbours,
+    )?;
+
+    if !format.is_text() {
+        return print_json(&sentence);
+    }
+
+    println!("Sentence #{}", sentence.rowid);
+    println!(
+        "Page: {} (sentence {})",
+        sentence.page_path.as_deref().unwrap_or("missing"),
+        sentence.page_index
+    );
+    println!("Text: {}", sentence.text.trim());
+    match sentence.embedding {
+        Some((dimension, norm)) => println!("Embedding: {dimension} dimensions, norm {norm:.3}"),
+        None => println!("Embedding: missing"),
+    }
+
+    if let Some(context) = sentence.context {
+        println!("\nContext when retrieved:\n{}", context.text.trim());
+    }
+
+    if !sentence.neighbours.is_empty() {
+        println!("\nNearest neighbours in the {} index:", index.name());
+        for neighbour in sentence.neighbours {
+            println!(
+                "  #{} ({:.4}): {}",
+                neighbour.rowid,
+                neighbour.distance,
+                neighbour.text.trim()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn prepare_embedding_settings(
+    conn: &Connection,
+    index: &dyn VectorIndex,
+    args: &PrepareArgs,
+    config: &Config,
+) -> Result<EmbeddingSettings> {
+    let current = EmbeddingSettings::load(conn)?;
+    let model = args
+        .embedding_model
+        .as_deref()
+        .or(config.embedding_model())
+        .unwrap_or(&current.model);
+
+    let dimension = match args.embedding_dimension {
+        Some(dimension) => dimension,
+        None if model == current.model => current.dimension,
+        None => {
+            return Err(miette::miette!(
+                "--embedding-dimension is required when switching to {model}"
+            ))
+        }
+    };
+
+    let settings = EmbeddingSettings {
+        model: model.to_string(),
+        dimension,
+        quantization: args.quantization.unwrap_or(current.quantization),
+    };
+    change_embedding_settings(conn, index, &settings)?;
+
+    Ok(settings)
+}
+
+#[derive(Serialize, Debug)]
+struct PrepareOutput<'a> {
+    corpus: &'a str,
+    embedding: &'a EmbeddingSettings,
+    /// Splitting each page into sentences
+    parsed: Vec<PageResult>,
+    /// Embedding the sentences of each page
+    embedded: Vec<PageResult>,
+    duration_secs: f64,
+}
+
+#[derive(Serialize, Debug)]
+struct PageResult {
+    path: String,
+    page_id: Option<i64>,
+    error: Option<String>,
+}
+
+async fn prepare(
+    paths: &StoragePaths,
+    prompts: &Prompts,
+    args: PrepareArgs,
+    format: OutputFormat,
+) -> Result<()> {
+    let started = std::time::Instant::now();
+    let (conn, index) = setup(paths, &args.corpus)?;
 
     let config = Config::from_env()?;
     let client = config.client()?;
 
+    let settings = prepare_embedding_settings(&conn, index.as_ref(), &args, &config)?;
+    if format.is_text() {
+        println!(
+            "Embedding with {} ({} dimensions, stored as {})",
+            settings.model, settings.dimension, settings.quantization
+        );
+    }
+    // sqlite-vss can't index quantized embeddings
+    let index: Arc<dyn VectorIndex> =
+        if index.name() == IndexName::Vss && settings.quantization != Quantization::F32 {
+            Arc::new(BruteForceIndex::load(&conn)?)
+        } else {
+            index
+        };
+
     let pages = walkdir::WalkDir::new(&args.path)
         .into_iter()
         .filter(|entry| {
@@ -101,113 +1521,147 @@ async fn prepare(args: PrepareArgs) -> Result<()> {
         })
         .collect_vec();
 
-    println!("Found {} pages", pages.len());
+    if format.is_text() {
+        println!("Found {} pages", pages.len());
+    }
 
-    let bodies = stream::iter(pages)
+    let parsed: Vec<PageResult> = stream::iter(pages)
         .map(|page| {
             let client = &client;
             let conn = &conn;
             let page = page.unwrap();
             let path: PathBuf = page.path().into();
             async move {
-                println!("About to Process Path: {}", path.display());
+                if format.is_text() {
+                    println!("About to Process Path: {}", path.display());
+                }
 
                 let display_path = path.display().to_string();
 
-                let page_id = conn.query_row(
-                    "INSERT OR IGNORE INTO pages (path) VALUES (?) returning rowid",
-                    params![display_path],
-                    |row: &Row| -> Result<i64, _> { row.get(0) },
-                );
-
-                let (page_id, parsed_text) = match page_id {
-                    Ok(id) => (id, None),
-                    Err(_) => conn
-                        .query_row(
-                            "SELECT rowid, parsed_text FROM pages WHERE path = ?",
-                            params![display_path],
-                            |row: &Row| -> Result<(i64, Option<String>), _> {
-                                Ok((row.get(0)?, row.get(1)?))
-                            },
-                        )
-                        .into_diagnostic()?,
-                };
+                let result = async {
+                    let page_id = conn.query_row(
+                        "INSERT OR IGNORE INTO pages (path) VALUES (?) returning rowid",
+                        params![display_path],
+                        |row: &Row| -> Result<i64, _> { row.get(0) },
+                    );
 
-                let parsed_text = if let Some(parsed_text) = parsed_text {
-                    parsed_text
-                } else {
-                    let content = std::fs::read_to_string(path).into_diagnostic()?;
-                    let sentences = client.split_by_sentences(&content).await?;
+                    let (page_id, parsed_text) = match page_id {
+                        Ok(id) => (id, None),
+                        Err(_) => conn
+                            .query_row(
+                                "SELECT rowid, parsed_text FROM pages WHERE path = ?",
+                                params![display_path],
+                                |row: &Row| -> Result<(i64, Option<String>), _> {
+                                    Ok((row.get(0)?, row.get(1)?))
+                                },
+                            )
+                            .into_diagnostic()?,
+                    };
 
-                    let parsed_text = sentences.join("\n\n");
+                    if parsed_text.is_none() {
+                        let content = std::fs::read_to_string(&path).into_diagnostic()?;
+                        let sentences = client.split_by_sentences(prompts, &content).await?;
 
-                    conn.execute(
-                        "
+                        let parsed_text = sentences.join("\n\n");
+
+                        conn.execute(
+                            "
                     UPDATE pages SET parsed_text = ? where rowid = ?",
-                        (&parsed_text, page_id),
-                    )
-                    .into_diagnostic()?;
+                            (&parsed_text, page_id),
+                        )
+                        .into_diagnostic()?;
+                    }
 
-                    parsed_text
-                };
+                    Result::<_>::Ok(page_id)
+                }
+                .await;
 
-                Result::<_>::Ok((page_id, parsed_text))
+                PageResult {
+                    page_id: result.as_ref().ok().copied(),
+                    error: result.err().map(|e| e.to_string()),
+                    path: display_path,
+                }
             }
         })
-        .buffer_unordered(CONCURRENT_REQUESTS);
-
-    bodies
-        .for_each(|b| async {
-            match b {
-                Ok((pid, _parsed_text)) => println!("Processed page with id {pid}"),
-                Err(e) => eprintln!("Got an error: {}", e),
-            }
+        .buffer_unordered(CONCURRENT_REQUESTS)
+        .inspect(|result| match (&result.error, result.page_id) {
+            (Some(e), _) => eprintln!("Got an error: {}", e),
+            (None, Some(pid)) if format.is_text() => println!("Processed page with id {pid}"),
+            _ => {}
         })
+        .collect()
         .await;
-    let mut st = conn
-        .prepare("SELECT rowid, parsed_text FROM pages")
-        .into_diagnostic()?;
-    let rows = st
+
+    let rows = conn
+        .prepare("SELECT rowid, path, parsed_text FROM pages")
+        .into_diagnostic()?
         .query_map(params![], |row| {
             let id: i64 = row.get(0)?;
-            let parsed_text: String = row.get(1)?;
-            Ok((id, parsed_text))
+            let path: String = row.get(1)?;
+            let parsed_text: Option<String> = row.get(2)?;
+            Ok((id, path, parsed_text))
         })
+        .into_diagnostic()?
+        .collect::<Result<Vec<_>, _>>()
         .into_diagnostic()?;
 
-    stream::iter(rows)
-        .map(|row| {
+    let embedded: Vec<PageResult> = stream::iter(rows)
+        .map(|(page_id, path, text)| {
             let client = &client;
             let conn = &conn;
+            let index = index.as_ref();
+            let settings = &settings;
 
             async move {
-                let (page_id, text) = row.into_diagnostic()?;
-                for (i, sentence) in text.split("\n\n").enumerate() {
-                    // TODO: Skip if already embedded
-                    embed_sentence(conn, client, sentence, page_id, i).await?;
+                let result = async {
+                    let text = text
+                        .ok_or_else(|| miette::miette!("{path} was never split into sentences"))?;
+                    for (i, sentence) in text.split("\n\n").enumerate() {
+                        // TODO: Skip if already embedded
+                        embed_sentence(conn, index, client, settings, sentence, page_id, i).await?;
+                    }
+
+                    Result::<_>::Ok(())
                 }
+                .await;
 
-                Result::<_>::Ok(page_id)
+                PageResult {
+                    path,
+                    page_id: Some(page_id),
+                    error: result.err().map(|e| e.to_string()),
+                }
             }
         })
         .buffer_unordered(CONCURRENT_REQUESTS)
-        .for_each(|b| async {
-            match b {
-                Ok(pid) => println!("Embedded page with id {pid}"),
-                Err(e) => eprintln!("Got an error: {}", e),
-            }
+        .inspect(|result| match (&result.error, result.page_id) {
+            (Some(e), _) => eprintln!("Got an error: {}", e),
+            (None, Some(pid)) if format.is_text() => println!("Embedded page with id {pid}"),
+            _ => {}
         })
+        .collect()
         .await;
+    index.flush()?;
+    record_build(&conn, started.elapsed())?;
+
+    if !format.is_text() {
+        print_json(&PrepareOutput {
+            corpus: &args.corpus,
+            embedding: &settings,
+            parsed,
+            embedded,
+            duration_secs: started.elapsed().as_secs_f64(),
+        })?;
+    }
 
-    upload_db(&args).await?;
+    upload_db(paths, &args).await?;
 
     Ok(())
 }
 
-async fn upload_db(args: &PrepareArgs) -> Result<()> {
+async fn upload_db(paths: &StoragePaths, args: &PrepareArgs) -> Result<()> {
     let config = aws_config::load_from_env().await;
     let client = aws_sdk_s3::Client::new(&config);
-    let file = ByteStream::from_path(Path::new(DB_NAME))
+    let file = ByteStream::from_path(paths.corpus_db_path(&args.corpus))
         .await
         .into_diagnostic()?;
 
@@ -246,7 +1700,16 @@ async fn upload_db(args: &PrepareArgs) -> Result<()> {
     Ok(())
 }
 
-async fn download(args: DownloadArgs) -> Result<()> {
+#[derive(Serialize, Debug)]
+struct DownloadOutput<'a> {
+    corpus: &'a str,
+    /// The S3 key of the DB that was downloaded
+    key: &'a str,
+    path: PathBuf,
+    bytes: usize,
+}
+
+async fn download(paths: &StoragePaths, args: DownloadArgs, format: OutputFormat) -> Result<()> {
     let config = aws_config::load_from_env().await;
     let client = aws_sdk_s3::Client::new(&config);
 
@@ -264,21 +1727,33 @@ async fn download(args: DownloadArgs) -> Result<()> {
     let resp = client
         .get_object()
         .bucket(std::env::var("S3_BUCKET").into_diagnostic()?)
-        .key(latest_key)
+        .key(&latest_key)
         .send()
         .await
         .into_diagnostic()?;
-    let data = resp.body.collect().await.into_diagnostic()?;
+    let data = resp.body.collect().await.into_diagnostic()?.to_vec();
 
-    let mut file = File::create(DB_NAME).into_diagnostic()?;
-    file.write_all(&data.to_vec()).into_diagnostic()?;
+    let path = paths.corpus_db_path(&args.corpus);
+    let mut file = File::create(&path).into_diagnostic()?;
+    file.write_all(&data).into_diagnostic()?;
+
+    if !format.is_text() {
+        return print_json(&DownloadOutput {
+            corpus: &args.corpus,
+            key: &latest_key,
+            path,
+            bytes: data.len(),
+        });
+    }
 
     Ok(())
 }
 
 async fn embed_sentence(
     conn: &Connection,
+    index: &dyn VectorIndex,
     client: &OpenAiClient,
+    settings: &EmbeddingSettings,
     sentence: &str,
     page_id: i64,
     page_index: usize,
@@ -291,8 +1766,7 @@ async fn embed_sentence(
         .into_diagnostic()?;
 
     if row_id.is_none() {
-        let embedding = fetch_embedding(client, sentence).await?;
-        let embedding_json = serde_json::to_string(&embedding).into_diagnostic()?;
+        let embedding = fetch_embedding(client, settings, sentence).await?;
 
         let mut stmt = conn
             .prepare(
@@ -301,11 +1775,18 @@ async fn embed_sentence(
         sentences
         (text, embedding, page_id, page_index)
         VALUES
-        (?, vector_to_blob(vector_from_json(?)), ?, ?)",
+        (?, ?, ?, ?)",
             )
             .into_diagnostic()?;
-        stmt.execute((sentence, embedding_json, page_id, page_index))
-            .into_diagnostic()?;
+        stmt.execute((
+            sentence,
+            encode_embedding(&embedding, settings.quantization),
+            page_id,
+            page_index,
+        ))
+        .into_diagnostic()?;
+
+        index.insert(conn, conn.last_insert_rowid())?;
     }
 
     Ok(())
@@ -0,0 +1,370 @@
+use miette::{miette, IntoDiagnostic, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+use crate::{
+    embedding::{decode_embedding, encode_embedding, Quantization},
+    index::VectorIndex,
+    openai::embeddings::EMBEDDING_DEFAULT_MODEL,
+};
+
+pub const EMBEDDING_DEFAULT_DIMENSION: usize = 1536;
+
+const EMBEDDING_MODEL_KEY: &str = "embedding_model";
+const EMBEDDING_DIMENSION_KEY: &str = "embedding_dimension";
+const EMBEDDING_QUANTIZATION_KEY: &str = "embedding_quantization";
+pub(crate) const BUILT_AT_KEY: &str = "built_at";
+pub(crate) const BUILD_DURATION_KEY: &str = "build_duration_secs";
+
+pub fn get_metadata(conn: &Connection, key: &str) -> Result<Option<String>> {
+    conn.query_row(
+        "SELECT value FROM metadata WHERE key = ?1",
+        params![key],
+        |row| row.get(0),
+    )
+    .optional()
+    .into_diagnostic()
+}
+
+pub fn set_metadata(conn: &Connection, key: &str, value: &str) -> Result<()> {
+    conn.execute(
+        "INSERT INTO metadata (key, value) VALUES (?1, ?2)
+        ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+        params![key, value],
+    )
+    .into_diagnostic()?;
+
+    Ok(())
+}
+
+/// Remembers when the last `prepare` run finished and how long it took, for `stats`
+pub fn record_build(conn: &Connection, duration: std::time::Duration) -> Result<()> {
+    set_metadata(conn, BUILT_AT_KEY, &chrono::Utc::now().to_rfc3339())?;
+    set_metadata(
+        conn,
+        BUILD_DURATION_KEY,
+        &duration.as_secs_f64().round().to_string(),
+    )
+}
+
+/// The embedding model a DB was built with. Every query has to be embedded the same way
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize)]
+pub struct EmbeddingSettings {
+    pub model: String,
+    pub dimension: usize,
+    pub quantization: Quantization,
+}
+
+impl Default for EmbeddingSettings {
+    fn default() -> Self {
+        Self {
+            model: EMBEDDING_DEFAULT_MODEL.to_string(),
+            dimension: EMBEDDING_DEFAULT_DIMENSION,
+            quantization: Quantization::default(),
+        }
+    }
+}
+
+impl EmbeddingSettings {
+    pub fn load(conn: &Connection) -> Result<Self> {
+        let model = get_metadata(conn, EMBEDDING_MODEL_KEY)?
+            .ok_or_else(|| miette!("The DB does not record which embedding model built it"))?;
+        let dimension = get_metadata(conn, EMBEDDING_DIMENSION_KEY)?
+            .ok_or_else(|| miette!("The DB does not record its embedding dimension"))?
+            .parse()
+            .into_diagnostic()?;
+        // Everything from before quantization was supported is f32
+        let quantization = get_metadata(conn, EMBEDDING_QUANTIZATION_KEY)?
+            .map(|quantization| quantization.parse())
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(Self {
+            model,
+            dimension,
+            quantization,
+        })
+    }
+
+    pub fn save(&self, conn: &Connection) -> Result<()> {
+        set_metadata(conn, EMBEDDING_MODEL_KEY, &self.model)?;
+        set_metadata(conn, EMBEDDING_DIMENSION_KEY, &self.dimension.to_string())?;
+        set_metadata(
+            conn,
+            EMBEDDING_QUANTIZATION_KEY,
+            &self.quantization.to_string(),
+        )?;
+
+        Ok(())
+    }
+
+    /// Errors when asked to use a different model than the one the DB was built with
+    pub fn check_model(&self, model: &str) -> Result<()> {
+        if model != self.model {
+            return Err(miette!(
+                help = "Unset EMBEDDING_MODEL or rebuild the DB with `prepare --embedding-model`",
+                "The DB was embedded with {expected} but {model} was requested",
+                expected = self.model
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn check_embedding(&self, embedding: &[f64]) -> Result<()> {
+        if embedding.len() != self.dimension {
+            return Err(miette!(
+                "{model} returned an embedding with {actual} dimensions but the DB expects {expected}",
+                model = self.model,
+                actual = embedding.len(),
+                expected = self.dimension
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Switches the DB over to a different embedding model
+///
+/// Only allowed before anything has been embedded, since mixing models or quantizations in one
+/// index makes the distances meaningless. The vector index is rebuilt for the new dimension.
+pub fn change_embedding_settings(
+    conn: &Connection,
+    index: &dyn VectorIndex,
+    settings: &EmbeddingSettings,
+) -> Result<()> {
+    let current = EmbeddingSettings::load(conn)?;
+    if &current == settings {
+        return Ok(());
+    }
+
+    let embedded: usize = conn
+        .query_row(
+            "SELECT count(*) FROM sentences WHERE embedding IS NOT NULL",
+            (),
+            |row| row.get(0),
+        )
+        .into_diagnostic()?;
+    if embedded > 0 && current.model == settings.model && current.dimension == settings.dimension {
+        return Err(miette!(
+            help = if current.quantization.can_convert_to(settings.quantization) {
+                format!(
+                    "Convert the stored embeddings with `reindex --quantization {}`",
+                    settings.quantization
+                )
+            } else {
+                "Prepare into a fresh DB to store more precise embeddings".to_string()
+            },
+            "The DB already has {embedded} sentences embedded as {}",
+            current.quantization
+        ));
+    }
+    if embedded > 0 {
+        return Err(miette!(
+            help = "Prepare into a fresh DB to use a different embedding model",
+            "The DB already has {embedded} sentences embedded with {model} ({dimension} dimensions, {quantization})",
+            model = current.model,
+            dimension = current.dimension,
+            quantization = current.quantization
+        ));
+    }
+
+    settings.save(conn)?;
+    index.rebuild(conn, settings.dimension)
+}
+
+/// Converts every stored embedding to `quantization`, returning how many were converted
+///
+/// Only goes towards smaller formats, the precision a quantized embedding lost can't be won
+/// back without embedding the sentence again. Runs in one transaction and then vacuums, so the
+/// DB file actually shrinks. The vector index has to be rebuilt afterwards.
+pub fn requantize(conn: &mut Connection, quantization: Quantization) -> Result<usize> {
+    let mut settings = EmbeddingSettings::load(conn)?;
+    if settings.quantization == quantization {
+        return Ok(0);
+    }
+    if !settings.quantization.can_convert_to(quantization) {
+        return Err(miette!(
+            help = "Prepare into a fresh DB to store full precision embeddings again",
+            "Embeddings stored as {} can't be converted to {quantization}",
+            settings.quantization
+        ));
+    }
+
+    let tx = conn.transaction().into_diagnostic()?;
+    let blobs = tx
+        .prepare("SELECT rowid, embedding FROM sentences WHERE embedding IS NOT NULL")
+        .into_diagnostic()?
+        .query_map((), |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, Vec<u8>>(1)?))
+        })
+        .into_diagnostic()?
+        .collect::<Result<Vec<_>, _>>()
+        .into_diagnostic()?;
+
+    for (rowid, blob) in &blobs {
+        let embedding: Vec<f64> = decode_embedding(blob)
+            .map_err(|e| miette!("Could not read the embedding of sentence {rowid}: {e}"))?
+            .into_iter()
+            .map(f64::from)
+            .collect();
+        tx.execute(
+            "UPDATE sentences SET embedding = ?1 WHERE rowid = ?2",
+            params![encode_embedding(&embedding, quantization), rowid],
+        )
+        .into_diagnostic()?;
+    }
+
+    settings.quantization = quantization;
+    settings.save(&tx)?;
+    tx.commit().into_diagnostic()?;
+
+    conn.execute_batch("VACUUM").into_diagnostic()?;
+
+    Ok(blobs.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{index::BruteForceIndex, schema::migrate};
+
+    fn db() -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        conn
+    }
+
+    fn embed(conn: &Connection, text: &str, embedding: &[f64], quantization: Quantization) {
+        conn.execute(
+            "INSERT INTO sentences (page_id, page_index, text, embedding) VALUES (1, 0, ?1, ?2)",
+            params![text, encode_embedding(embedding, quantization)],
+        )
+        .unwrap();
+    }
+
+    fn small() -> EmbeddingSettings {
+        EmbeddingSettings {
+            model: "small-model".to_string(),
+            dimension: 3,
+            quantization: Quantization::Int8,
+        }
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        let conn = db();
+        assert_eq!(
+            EmbeddingSettings::load(&conn).unwrap(),
+            EmbeddingSettings::default()
+        );
+
+        small().save(&conn).unwrap();
+        assert_eq!(EmbeddingSettings::load(&conn).unwrap(), small());
+    }
+
+    #[test]
+    fn dbs_from_before_quantization_are_f32() {
+        let conn = db();
+        small().save(&conn).unwrap();
+        conn.execute(
+            "DELETE FROM metadata WHERE key = ?1",
+            params![EMBEDDING_QUANTIZATION_KEY],
+        )
+        .unwrap();
+
+        assert_eq!(
+            EmbeddingSettings::load(&conn).unwrap().quantization,
+            Quantization::F32
+        );
+    }
+
+    #[test]
+    fn loading_needs_the_model() {
+        let conn = db();
+        conn.execute(
+            "DELETE FROM metadata WHERE key = ?1",
+            params![EMBEDDING_MODEL_KEY],
+        )
+        .unwrap();
+
+        assert!(EmbeddingSettings::load(&conn).is_err());
+    }
+
+    #[test]
+    fn rejects_a_different_model() {
+        let settings = small();
+
+        settings.check_model("small-model").unwrap();
+        let err = settings.check_model("large-model").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The DB was embedded with small-model but large-model was requested"
+        );
+
+        settings.check_embedding(&[0.0, 0.1, 0.2]).unwrap();
+        assert!(settings.check_embedding(&[0.0, 0.1]).is_err());
+    }
+
+    #[test]
+    fn only_changes_settings_before_anything_is_embedded() {
+        let conn = db();
+        let index = BruteForceIndex::load(&conn).unwrap();
+
+        change_embedding_settings(&conn, &index, &small()).unwrap();
+        assert_eq!(EmbeddingSettings::load(&conn).unwrap(), small());
+
+        embed(&conn, "Eat food", &[0.1, 0.2, 0.3], Quantization::Int8);
+        change_embedding_settings(&conn, &index, &small()).unwrap();
+        assert!(change_embedding_settings(
+            &conn,
+            &index,
+            &EmbeddingSettings {
+                model: "large-model".to_string(),
+                ..small()
+            }
+        )
+        .is_err());
+        assert!(change_embedding_settings(
+            &conn,
+            &index,
+            &EmbeddingSettings {
+                quantization: Quantization::Binary,
+                ..small()
+            }
+        )
+        .is_err());
+        assert_eq!(EmbeddingSettings::load(&conn).unwrap(), small());
+    }
+
+    #[test]
+    fn requantizes_towards_smaller_formats_only() {
+        let mut conn = db();
+        EmbeddingSettings {
+            quantization: Quantization::F32,
+            ..small()
+        }
+        .save(&conn)
+        .unwrap();
+        embed(&conn, "Eat food", &[0.5, -0.25, 1.0], Quantization::F32);
+        embed(&conn, "Avoid walls", &[-1.0, 0.5, 0.0], Quantization::F32);
+
+        assert_eq!(requantize(&mut conn, Quantization::Int8).unwrap(), 2);
+        assert_eq!(
+            EmbeddingSettings::load(&conn).unwrap().quantization,
+            Quantization::Int8
+        );
+        let blob: Vec<u8> = conn
+            .query_row(
+                "SELECT embedding FROM sentences WHERE rowid = 1",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(blob.len(), 2 + 4 + 3);
+
+        assert_eq!(requantize(&mut conn, Quantization::Int8).unwrap(), 0);
+        assert!(requantize(&mut conn, Quantization::F32).is_err());
+    }
+}
@@ -0,0 +1,108 @@
+/// Vectors from different embedding models can't be compared, so they count as unrelated
+pub fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+
+    let dot: f64 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a: f64 = a.iter().map(|x| x * x).sum::<f64>().sqrt();
+    let norm_b: f64 = b.iter().map(|x| x * x).sum::<f64>().sqrt();
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+
+    dot / (norm_a * norm_b)
+}
+
+/// Picks `k` candidates using Maximal Marginal Relevance
+///
+/// Each step picks the candidate with the best
+/// `lambda * sim(query, c) - (1 - lambda) * max(sim(c, already_picked))`
+/// so a `lambda` of 1.0 is plain relevance order and 0.0 only cares about diversity.
+///
+/// `relevance` is each candidate's similarity to the query.
+/// Returns indexes into `candidates` in the order they were picked
+pub fn mmr(relevance: &[f64], candidates: &[Vec<f64>], lambda: f64, k: usize) -> Vec<usize> {
+    let mut picked: Vec<usize> = Vec::with_capacity(k);
+    let mut remaining: Vec<usize> = (0..candidates.len()).collect();
+
+    while picked.len() < k && !remaining.is_empty() {
+        let (best_pos, _) = remaining
+            .iter()
+            .enumerate()
+            .map(|(pos, &i)| {
+                let redundancy = picked
+                    .iter()
+                    .map(|&j| cosine_similarity(&candidates[i], &candidates[j]))
+                    .reduce(f64::max)
+                    .unwrap_or(0.0);
+
+                (pos, lambda * relevance[i] - (1.0 - lambda) * redundancy)
+            })
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .expect("remaining is not empty");
+
+        picked.push(remaining.remove(best_pos));
+    }
+
+    picked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two near duplicates that are the most relevant, and a less relevant different one
+    fn candidates() -> (Vec<f64>, Vec<Vec<f64>>) {
+        let candidates = vec![vec![1.0, 0.0], vec![0.99, 0.14], vec![0.0, 1.0]];
+        let relevance = vec![0.9, 0.85, 0.5];
+        (relevance, candidates)
+    }
+
+    #[test]
+    fn a_lambda_of_one_is_relevance_order() {
+        let (relevance, candidates) = candidates();
+
+        assert_eq!(mmr(&relevance, &candidates, 1.0, 3), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn a_lambda_of_zero_picks_the_least_similar_next() {
+        let (relevance, candidates) = candidates();
+
+        // Nothing is picked yet so the first pick is a tie, which goes to the last candidate.
+        // After that relevance doesn't matter, only being unlike what was picked
+        assert_eq!(mmr(&relevance, &candidates, 0.0, 3), vec![2, 0, 1]);
+    }
+
+    #[test]
+    fn balanced_lambdas_skip_near_duplicates() {
+        let (relevance, candidates) = candidates();
+
+        assert_eq!(mmr(&relevance, &candidates, 0.5, 2), vec![0, 2]);
+    }
+
+    #[test]
+    fn picks_at_most_k() {
+        let (relevance, candidates) = candidates();
+
+        assert_eq!(mmr(&relevance, &candidates, 0.7, 1), vec![0]);
+        assert_eq!(mmr(&relevance, &candidates, 0.7, 10).len(), 3);
+        assert!(mmr(&[], &[], 0.7, 10).is_empty());
+    }
+
+    #[test]
+    fn cosine_similarity_of_matching_vectors() {
+        assert!((cosine_similarity(&[1.0, 2.0], &[2.0, 4.0]) - 1.0).abs() < 1e-12);
+        assert!((cosine_similarity(&[1.0, 0.0], &[-3.0, 0.0]) + 1.0).abs() < 1e-12);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 5.0]), 0.0);
+    }
+
+    #[test]
+    fn mismatched_or_empty_vectors_are_unrelated() {
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[1.0, 0.0, 0.0]), 0.0);
+        assert_eq!(cosine_similarity(&[], &[]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+}
@@ -6,20 +6,48 @@ use crate::APP_USER_AGENT;
 pub mod completion;
 pub mod embeddings;
 
+const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+
 #[derive(Debug, Clone)]
 pub struct Config {
     api_key: String,
+    base_url: String,
+    embeddings_base_url: String,
+    embedding_model: Option<String>,
 }
 
-pub struct Client(pub reqwest::Client);
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    embeddings_base_url: String,
+}
 
 impl Config {
+    /// Reads `OPENAI_API_KEY` plus some optional overrides:
+    ///
+    /// - `OPENAI_BASE_URL` points every request at an OpenAI compatible API
+    /// - `EMBEDDINGS_BASE_URL` points just the embedding requests somewhere else, like a local model
+    /// - `EMBEDDING_MODEL` is the embedding model we expect the DB to be built with
     pub fn from_env() -> Result<Self> {
         let api_key = std::env::var("OPENAI_API_KEY")
             .into_diagnostic()
             .wrap_err("Could not find OPENAI_API_KEY env var")?;
+        let base_url =
+            std::env::var("OPENAI_BASE_URL").unwrap_or_else(|_| DEFAULT_BASE_URL.to_string());
+        let embeddings_base_url =
+            std::env::var("EMBEDDINGS_BASE_URL").unwrap_or_else(|_| base_url.clone());
+        let embedding_model = std::env::var("EMBEDDING_MODEL").ok();
+
+        Ok(Self {
+            api_key,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            embeddings_base_url: embeddings_base_url.trim_end_matches('/').to_string(),
+            embedding_model,
+        })
+    }
 
-        Ok(Self { api_key })
+    pub fn embedding_model(&self) -> Option<&str> {
+        self.embedding_model.as_deref()
     }
 
     pub fn client(&self) -> Result<Client> {
@@ -40,6 +68,10 @@ impl Config {
             .into_diagnostic()
             .wrap_err("Could not build reqwest client")?;
 
-        Ok(Client(client))
+        Ok(Client {
+            http: client,
+            base_url: self.base_url.clone(),
+            embeddings_base_url: self.embeddings_base_url.clone(),
+        })
     }
 }
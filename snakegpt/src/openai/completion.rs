@@ -1,7 +1,10 @@
-use miette::{IntoDiagnostic, Result};
+use miette::{miette, IntoDiagnostic, Result};
 use serde::{Deserialize, Serialize};
 
 use super::Client;
+use crate::{PromptName, Prompts};
+
+pub const CHAT_DEFAULT_MODEL: &str = "gpt-3.5-turbo";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
@@ -13,16 +16,23 @@ pub struct Message {
 pub struct CompletionRequest {
     messages: Vec<Message>,
     model: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
 }
 
 impl CompletionRequest {
     pub fn gpt_3_5_turbo(prompt: &str) -> Self {
+        Self::with_model(prompt, CHAT_DEFAULT_MODEL)
+    }
+
+    pub fn with_model(prompt: &str, model: &str) -> Self {
         Self {
-            model: "gpt-3.5-turbo".to_string(),
+            model: model.to_string(),
             messages: vec![Message {
                 content: prompt.to_string(),
                 role: "user".to_string(),
             }],
+            stream: false,
         }
     }
 }
@@ -36,9 +46,25 @@ pub struct CompletionChoice {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct CompletionUsage {
-    completion_tokens: i64,
-    prompt_tokens: i64,
-    total_tokens: i64,
+    pub completion_tokens: i64,
+    pub prompt_tokens: i64,
+    pub total_tokens: i64,
+}
+
+/// One server sent event from a streamed completion
+#[derive(Deserialize, Debug, Clone)]
+struct CompletionChunk {
+    choices: Vec<CompletionChunkChoice>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct CompletionChunkChoice {
+    delta: CompletionDelta,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct CompletionDelta {
+    content: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -54,8 +80,8 @@ pub struct CompletionResponse {
 impl Client {
     pub async fn completion(&self, request: CompletionRequest) -> Result<CompletionResponse> {
         let response = self
-            .0
-            .post("https://api.openai.com/v1/chat/completions")
+            .http
+            .post(format!("{}/chat/completions", self.base_url))
             .json(&request)
             .send()
             .await
@@ -66,20 +92,67 @@ impl Client {
         Ok(response_body)
     }
 
-    pub async fn split_by_sentences(&self, blob: &str) -> Result<Vec<String>> {
+    /// Streams the completion, handing each piece of text to `on_delta` as it arrives
+    ///
+    /// Returns the whole message once the stream is done
+    pub async fn completion_stream(
+        &self,
+        mut request: CompletionRequest,
+        mut on_delta: impl FnMut(&str),
+    ) -> Result<String> {
+        request.stream = true;
+
+        let mut response = self
+            .http
+            .post(format!("{}/chat/completions", self.base_url))
+            .json(&request)
+            .send()
+            .await
+            .into_diagnostic()?
+            .error_for_status()
+            .into_diagnostic()?;
+
+        let mut message = String::new();
+        // Raw bytes, a character can be split across network chunks but never across lines
+        let mut buffer: Vec<u8> = vec![];
+        while let Some(bytes) = response.chunk().await.into_diagnostic()? {
+            buffer.extend_from_slice(&bytes);
+
+            while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim();
+
+                let Some(data) = line.strip_prefix("data:") else {
+                    continue;
+                };
+                let data = data.trim();
+                if data == "[DONE]" {
+                    return Ok(message);
+                }
+
+                let chunk: CompletionChunk = serde_json::from_str(data)
+                    .map_err(|e| miette!("Could not parse completion chunk {data}: {e}"))?;
+                for delta in chunk.choices.into_iter().filter_map(|c| c.delta.content) {
+                    on_delta(&delta);
+                    message.push_str(&delta);
+                }
+            }
+        }
+
+        Ok(message)
+    }
+
+    pub async fn split_by_sentences(&self, prompts: &Prompts, blob: &str) -> Result<Vec<String>> {
         let started = std::time::Instant::now();
 
-        let prompt = format!(
-        "I will paste a block of markdown. I need you to remove all the formatting, and break each sentence onto its own line
-        Make sure each sentence has a blank line between it. Code blocks should be considered a single sentence.
-        
-        {blob}");
+        let prompt = prompts.render(PromptName::SplitSentences, &[("markdown", blob)]);
         let request = CompletionRequest::gpt_3_5_turbo(&prompt);
         let resp = self.completion(request).await?;
 
         let message = resp.choices[0].message.content.clone();
 
-        println!("Splitting by sentences took {:?}", started.elapsed());
+        eprintln!("Splitting by sentences took {:?}", started.elapsed());
 
         Ok(message.split("\n\n").map(|s| s.to_string()).collect())
     }
@@ -3,12 +3,12 @@ use serde::{Deserialize, Serialize};
 
 use super::Client;
 
-const EMBEDDING_DEFAULT_MODEL: &str = "text-embedding-ada-002";
+pub const EMBEDDING_DEFAULT_MODEL: &str = "text-embedding-ada-002";
 
 #[derive(Debug, Clone, Serialize)]
 pub struct EmbeddingsRequest {
     input: String,
-    model: &'static str,
+    model: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -34,10 +34,11 @@ pub struct EmbeddingResponse {
 
 impl EmbeddingsRequest {
     pub fn new(input: String) -> Self {
-        Self {
-            input,
-            model: EMBEDDING_DEFAULT_MODEL,
-        }
+        Self::with_model(input, EMBEDDING_DEFAULT_MODEL.to_string())
+    }
+
+    pub fn with_model(input: String, model: String) -> Self {
+        Self { input, model }
     }
 }
 
@@ -48,8 +49,8 @@ impl Client {
     ) -> Result<EmbeddingResponse> {
         let request: EmbeddingsRequest = request.into();
         let response = self
-            .0
-            .post("https://api.openai.com/v1/embeddings")
+            .http
+            .post(format!("{}/embeddings", self.embeddings_base_url))
             .json(&request)
             .send()
             .await
@@ -0,0 +1,72 @@
+use std::path::{Path, PathBuf};
+
+use miette::{miette, Result};
+
+/// Where the corpus DBs and the sqlite-vss extensions live on disk
+///
+/// Defaults to the working directory so running from the repo root keeps working
+#[derive(Clone, Debug)]
+pub struct StoragePaths {
+    pub data_dir: PathBuf,
+    pub vendor_dir: PathBuf,
+}
+
+impl Default for StoragePaths {
+    fn default() -> Self {
+        Self {
+            data_dir: PathBuf::from("."),
+            vendor_dir: PathBuf::from("./vendor"),
+        }
+    }
+}
+
+impl StoragePaths {
+    /// Reads `SNAKEGPT_DATA_DIR` and `SNAKEGPT_VENDOR_DIR`, falling back to the defaults
+    pub fn from_env() -> Self {
+        let default = Self::default();
+
+        Self {
+            data_dir: std::env::var_os("SNAKEGPT_DATA_DIR")
+                .map(PathBuf::from)
+                .unwrap_or(default.data_dir),
+            vendor_dir: std::env::var_os("SNAKEGPT_VENDOR_DIR")
+                .map(PathBuf::from)
+                .unwrap_or(default.vendor_dir),
+        }
+    }
+
+    /// Each corpus lives in its own DB so they can be built and downloaded separately
+    pub fn corpus_db_path(&self, name: &str) -> PathBuf {
+        self.data_dir.join(format!("{name}.v0.db"))
+    }
+
+    /// Built by `snakegpt-cli reindex --index hnsw`, and used instead of sqlite-vss when present
+    pub fn hnsw_index_path(&self, name: &str) -> PathBuf {
+        self.data_dir.join(format!("{name}.v0.hnsw"))
+    }
+
+    /// The path to hand SQLite for a vendored extension, which adds the platform's suffix itself
+    ///
+    /// Errors up front when the file is missing since SQLite's own error doesn't say where it looked
+    pub fn extension_path(&self, name: &str) -> Result<PathBuf> {
+        let path = self.vendor_dir.join(name);
+
+        if !has_extension_file(&path) {
+            return Err(miette!(
+                help = "Download the sqlite-vss release for your platform into that directory, \
+                or point --vendor-dir / SNAKEGPT_VENDOR_DIR at where it lives",
+                "Could not find the {name} SQLite extension in {dir}",
+                dir = self.vendor_dir.display()
+            ));
+        }
+
+        Ok(path)
+    }
+}
+
+fn has_extension_file(path: &Path) -> bool {
+    path.exists()
+        || ["so", "dylib", "dll"]
+            .iter()
+            .any(|suffix| path.with_extension(suffix).exists())
+}
@@ -0,0 +1,375 @@
+use std::{collections::HashMap, fmt, path::Path, str::FromStr, sync::Arc};
+
+use miette::{miette, Context as _, IntoDiagnostic, Result};
+
+/// A prompt we send to the chat model, each one is a template with `{variables}` to fill in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PromptName {
+    /// Answers the question from the retrieved context
+    Answer,
+    /// Strips markdown and puts each sentence on its own line while preparing a corpus
+    SplitSentences,
+    /// Rewrites a follow-up question to stand on its own
+    Condense,
+    /// Grades answers in `eval answers`
+    Judge,
+    /// Drives the `react` strategy, which calls tools before answering
+    React,
+    /// Rewrites the question several ways for `--expand multi-query`
+    MultiQuery,
+    /// Writes a hypothetical docs passage to search with for `--expand hyde`
+    Hyde,
+    /// Scores a batch of passages against the question for the LLM reranker
+    Rerank,
+}
+
+impl PromptName {
+    pub const ALL: [PromptName; 8] = [
+        PromptName::Answer,
+        PromptName::SplitSentences,
+        PromptName::Condense,
+        PromptName::Judge,
+        PromptName::React,
+        PromptName::MultiQuery,
+        PromptName::Hyde,
+        PromptName::Rerank,
+    ];
+
+    /// Every variable the template has to use
+    pub fn variables(self) -> &'static [&'static str] {
+        match self {
+            PromptName::Answer => &["context", "question"],
+            PromptName::SplitSentences => &["markdown"],
+            PromptName::Condense => &["history", "question"],
+            PromptName::Judge => &["question", "reference", "context", "answer"],
+            PromptName::React => &["tools", "question", "scratchpad"],
+            PromptName::MultiQuery => &["count", "question"],
+            PromptName::Hyde => &["question"],
+            PromptName::Rerank => &["count", "question", "passages"],
+        }
+    }
+
+    /// The template built into the binary
+    pub fn embedded(self) -> &'static str {
+        match self {
+            PromptName::Answer => include_str!("../prompts/answer.txt"),
+            PromptName::SplitSentences => include_str!("../prompts/split_sentences.txt"),
+            PromptName::Condense => include_str!("../prompts/condense.txt"),
+            PromptName::Judge => include_str!("../prompts/judge.txt"),
+            PromptName::React => include_str!("../prompts/react.txt"),
+            PromptName::MultiQuery => include_str!("../prompts/multi_query.txt"),
+            PromptName::Hyde => include_str!("../prompts/hyde.txt"),
+            PromptName::Rerank => include_str!("../prompts/rerank.txt"),
+        }
+    }
+}
+
+impl fmt::Display for PromptName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PromptName::Answer => "answer",
+            PromptName::SplitSentences => "split_sentences",
+            PromptName::Condense => "condense",
+            PromptName::Judge => "judge",
+            PromptName::React => "react",
+            PromptName::MultiQuery => "multi_query",
+            PromptName::Hyde => "hyde",
+            PromptName::Rerank => "rerank",
+        })
+    }
+}
+
+impl FromStr for PromptName {
+    type Err = miette::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        PromptName::ALL
+            .into_iter()
+            .find(|name| name.to_string() == s)
+            .ok_or_else(|| {
+                miette!(
+                    "Unknown prompt {s}, expected one of: {}",
+                    PromptName::ALL.map(|name| name.to_string()).join(", ")
+                )
+            })
+    }
+}
+
+/// The prompt templates to use, the embedded ones unless a prompts directory overrides them
+///
+/// A prompts directory holds `{name}.txt` files that replace the embedded templates, and
+/// `{name}.{variant}.txt` files that add named variants. With a variant selected its templates
+/// are used where it has them, and the defaults everywhere else.
+#[derive(Clone, Debug, Default)]
+pub struct Prompts {
+    /// Keyed by prompt and variant, `None` being the default
+    templates: Arc<HashMap<(PromptName, Option<String>), String>>,
+    variant: Option<String>,
+}
+
+impl Prompts {
+    /// Reads every template in `dir` on top of the embedded ones
+    pub fn load(dir: &Path) -> Result<Self> {
+        let entries = std::fs::read_dir(dir)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Could not read the prompts in {}", dir.display()))?;
+
+        let mut templates = HashMap::new();
+        for entry in entries {
+            let path = entry.into_diagnostic()?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("txt") {
+                continue;
+            }
+
+            let stem = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .ok_or_else(|| miette!("{} is not a valid prompt name", path.display()))?;
+            let (name, variant) = match stem.split_once('.') {
+                Some((name, variant)) => (name, Some(variant.to_string())),
+                None => (stem, None),
+            };
+            let name: PromptName = name
+                .parse()
+                .wrap_err_with(|| format!("Could not load {}", path.display()))?;
+
+            let template = std::fs::read_to_string(&path).into_diagnostic()?;
+            check_template(name, &template)
+                .wrap_err_with(|| format!("Could not load {}", path.display()))?;
+            templates.insert((name, variant), template);
+        }
+
+        Ok(Self {
+            templates: Arc::new(templates),
+            variant: None,
+        })
+    }
+
+    /// Loads the directory in `SNAKEGPT_PROMPTS_DIR`, or just the embedded templates when it
+    /// isn't set
+    pub fn from_env() -> Result<Self> {
+        match std::env::var_os("SNAKEGPT_PROMPTS_DIR") {
+            Some(dir) => Self::load(Path::new(&dir)),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Every variant with at least one template
+    pub fn variants(&self) -> Vec<&str> {
+        let mut variants = self
+            .templates
+            .keys()
+            .filter_map(|(_, variant)| variant.as_deref())
+            .collect::<Vec<_>>();
+        variants.sort_unstable();
+        variants.dedup();
+
+        variants
+    }
+
+    pub fn variant(&self) -> Option<&str> {
+        self.variant.as_deref()
+    }
+
+    /// The same templates with a variant selected, `None` going back to the defaults
+    pub fn with_variant(&self, variant: Option<&str>) -> Result<Self> {
+        if let Some(variant) = variant {
+            if !self.variants().contains(&variant) {
+                return Err(miette!(
+                    help = "Variants come from {name}.{variant}.txt files in the prompts directory",
+                    "Unknown prompt variant {variant}, expected one of: {}",
+                    self.variants().join(", ")
+                ));
+            }
+        }
+
+        Ok(Self {
+            templates: self.templates.clone(),
+            variant: variant.map(str::to_string),
+        })
+    }
+
+    /// Replaces one template for the selected variant
+    pub fn with_template(&self, name: PromptName, template: String) -> Result<Self> {
+        check_template(name, &template)?;
+
+        let mut templates = (*self.templates).clone();
+        templates.insert((name, self.variant.clone()), template);
+
+        Ok(Self {
+            templates: Arc::new(templates),
+            variant: self.variant.clone(),
+        })
+    }
+
+    /// The template that will be used for `name`
+    pub fn template(&self, name: PromptName) -> &str {
+        self.variant
+            .as_ref()
+            .and_then(|variant| self.templates.get(&(name, Some(variant.clone()))))
+            .or_else(|| self.templates.get(&(name, None)))
+            .map_or_else(|| name.embedded(), String::as_str)
+    }
+
+    /// Fills in the template's variables, `values` are `(variable, value)` pairs
+    pub fn render(&self, name: PromptName, values: &[(&str, &str)]) -> String {
+        render(self.template(name), values)
+    }
+}
+
+/// Makes sure a template uses every variable it is given and no others
+fn check_template(name: PromptName, template: &str) -> Result<()> {
+    let used = placeholders(template);
+
+    if let Some(unknown) = used.iter().find(|used| !name.variables().contains(used)) {
+        return Err(miette!(
+            "The {name} prompt uses {{{unknown}}}, expected only: {}",
+            name.variables().join(", ")
+        ));
+    }
+    if let Some(missing) = name.variables().iter().find(|var| !used.contains(var)) {
+        return Err(miette!("The {name} prompt has to use {{{missing}}}"));
+    }
+
+    Ok(())
+}
+
+/// Every `{variable}` in a template. Braces around anything else, like JSON, are left alone
+fn placeholders(template: &str) -> Vec<&str> {
+    template
+        .split('{')
+        .skip(1)
+        .filter_map(|rest| rest.split_once('}'))
+        .map(|(name, _)| name)
+        .filter(|name| !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase() || c == '_'))
+        .collect()
+}
+
+/// Fills in `{name}` placeholders in one pass, so values that contain braces are left alone
+fn render(template: &str, values: &[(&str, &str)]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+
+        let placeholder = values.iter().find(|(name, _)| {
+            after
+                .strip_prefix(name)
+                .is_some_and(|after| after.starts_with('}'))
+        });
+        match placeholder {
+            Some((name, value)) => {
+                rendered.push_str(value);
+                rest = &after[name.len() + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = after;
+            }
+        }
+    }
+    rendered.push_str(rest);
+
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prompts(templates: &[(PromptName, Option<&str>, &str)]) -> Prompts {
+        Prompts {
+            templates: Arc::new(
+                templates
+                    .iter()
+                    .map(|(name, variant, template)| {
+                        ((*name, variant.map(str::to_string)), template.to_string())
+                    })
+                    .collect(),
+            ),
+            variant: None,
+        }
+    }
+
+    #[test]
+    fn embedded_templates_are_valid() {
+        for name in PromptName::ALL {
+            check_template(name, name.embedded()).unwrap();
+        }
+    }
+
+    #[test]
+    fn values_are_not_expanded_again() {
+        let rendered = render(
+            "Context: {context}\nQuestion: {question}",
+            &[("context", "Ask me a {question}"), ("question", "Why?")],
+        );
+
+        assert_eq!(rendered, "Context: Ask me a {question}\nQuestion: Why?");
+    }
+
+    #[test]
+    fn json_braces_are_left_alone() {
+        let template = r#"Reply with {"answer": "..."} to {question} using {context}"#;
+
+        check_template(PromptName::Answer, template).unwrap();
+        assert_eq!(
+            render(template, &[("context", "docs"), ("question", "Why?")]),
+            r#"Reply with {"answer": "..."} to Why? using docs"#
+        );
+    }
+
+    #[test]
+    fn missing_variables_are_rejected() {
+        let err = check_template(PromptName::Answer, "Answer {question}").unwrap_err();
+
+        assert_eq!(err.to_string(), "The answer prompt has to use {context}");
+    }
+
+    #[test]
+    fn unknown_variables_are_rejected() {
+        let err = check_template(PromptName::Answer, "{context} {question} {history}").unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "The answer prompt uses {history}, expected only: context, question"
+        );
+    }
+
+    #[test]
+    fn variants_fall_back_to_the_default_template() {
+        let prompts = prompts(&[
+            (
+                PromptName::Answer,
+                Some("terse"),
+                "Tersely: {context} {question}",
+            ),
+            (PromptName::Condense, None, "Condense {history} {question}"),
+        ])
+        .with_variant(Some("terse"))
+        .unwrap();
+
+        assert_eq!(
+            prompts.template(PromptName::Answer),
+            "Tersely: {context} {question}"
+        );
+        assert_eq!(
+            prompts.template(PromptName::Condense),
+            "Condense {history} {question}"
+        );
+        assert_eq!(
+            prompts.template(PromptName::Judge),
+            PromptName::Judge.embedded()
+        );
+    }
+
+    #[test]
+    fn unknown_variants_are_rejected() {
+        let prompts = prompts(&[(PromptName::Answer, Some("terse"), "{context} {question}")]);
+
+        assert!(prompts.with_variant(Some("verbose")).is_err());
+        assert_eq!(prompts.with_variant(None).unwrap().variant(), None);
+    }
+}
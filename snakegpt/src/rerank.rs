@@ -0,0 +1,217 @@
+use std::fmt::Debug;
+
+use async_trait::async_trait;
+use itertools::Itertools;
+use miette::{miette, IntoDiagnostic, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{context::ContextChunk, CompletionRequest, Config, PromptName, Prompts};
+
+/// Second retrieval stage that scores candidates against the question
+#[async_trait]
+pub trait Reranker: Debug + Send + Sync {
+    /// Returns one relevance score per candidate, in the same order. Higher is more relevant
+    async fn score(&self, question: &str, candidates: &[ContextChunk]) -> Result<Vec<f64>>;
+}
+
+/// Asks the chat model to grade each candidate from 0 to 10
+#[derive(Debug, Clone)]
+pub struct LlmReranker {
+    config: Config,
+    prompts: Prompts,
+}
+
+/// How many candidates go into a single scoring prompt, to stay inside the context window
+const LLM_RERANK_BATCH_SIZE: usize = 10;
+
+impl LlmReranker {
+    pub fn new(config: Config, prompts: Prompts) -> Self {
+        Self { config, prompts }
+    }
+}
+
+#[async_trait]
+impl Reranker for LlmReranker {
+    async fn score(&self, question: &str, candidates: &[ContextChunk]) -> Result<Vec<f64>> {
+        let client = self.config.client()?;
+        let mut scores = Vec::with_capacity(candidates.len());
+
+        for batch in candidates.chunks(LLM_RERANK_BATCH_SIZE) {
+            let passages = batch
+                .iter()
+                .enumerate()
+                .map(|(i, chunk)| format!("Passage {}:\n{}", i + 1, chunk.text.trim()))
+                .join("\n\n");
+
+            let prompt = self.prompts.render(
+                PromptName::Rerank,
+                &[
+                    ("count", &batch.len().to_string()),
+                    ("question", question),
+                    ("passages", &passages),
+                ],
+            );
+
+            let response = client
+                .completion(CompletionRequest::gpt_3_5_turbo(&prompt))
+                .await?;
+            let content = &response
+                .choices
+                .first()
+                .ok_or_else(|| miette!("Reranker got no choices back"))?
+                .message
+                .content;
+
+            scores.extend(parse_scores(content, batch.len())?);
+        }
+
+        Ok(scores)
+    }
+}
+
+/// Reads the JSON array of scores out of the model's response, which has to score every passage
+fn parse_scores(content: &str, passages: usize) -> Result<Vec<f64>> {
+    let start = content.find('[');
+    let end = content.rfind(']');
+
+    let scores: Vec<f64> = match (start, end) {
+        (Some(start), Some(end)) if start < end => {
+            serde_json::from_str(&content[start..=end]).into_diagnostic()?
+        }
+        _ => return Err(miette!("Reranker response had no JSON array: {content}")),
+    };
+    if scores.len() != passages {
+        return Err(miette!(
+            "Reranker returned {} scores for {passages} passages",
+            scores.len()
+        ));
+    }
+
+    Ok(scores)
+}
+
+/// Calls a local reranker over HTTP
+///
+/// Speaks the `/rerank` API of Hugging Face's text-embeddings-inference, so any cross-encoder
+/// it can serve works here
+#[derive(Debug, Clone)]
+pub struct HttpReranker {
+    url: String,
+    client: reqwest::Client,
+}
+
+#[derive(Serialize, Debug)]
+struct HttpRerankRequest<'a> {
+    query: &'a str,
+    texts: Vec<&'a str>,
+}
+
+#[derive(Deserialize, Debug)]
+struct HttpRerankScore {
+    index: usize,
+    score: f64,
+}
+
+impl HttpReranker {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn from_env() -> Option<Self> {
+        std::env::var("RERANKER_URL").ok().map(Self::new)
+    }
+}
+
+#[async_trait]
+impl Reranker for HttpReranker {
+    async fn score(&self, question: &str, candidates: &[ContextChunk]) -> Result<Vec<f64>> {
+        let request = HttpRerankRequest {
+            query: question,
+            texts: candidates.iter().map(|c| c.text.as_str()).collect(),
+        };
+
+        let response: Vec<HttpRerankScore> = self
+            .client
+            .post(&self.url)
+            .json(&request)
+            .send()
+            .await
+            .into_diagnostic()?
+            .error_for_status()
+            .into_diagnostic()?
+            .json()
+            .await
+            .into_diagnostic()?;
+
+        Ok(scores_by_index(response, candidates.len()))
+    }
+}
+
+/// Puts the scores back in candidate order. Candidates the reranker left out score lowest and
+/// indexes past the last candidate are ignored
+fn scores_by_index(response: Vec<HttpRerankScore>, candidates: usize) -> Vec<f64> {
+    let mut scores = vec![f64::NEG_INFINITY; candidates];
+    for HttpRerankScore { index, score } in response {
+        if let Some(slot) = scores.get_mut(index) {
+            *slot = score;
+        }
+    }
+
+    scores
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_scores_from_the_response() {
+        assert_eq!(parse_scores("[3, 7.5, 0]", 3).unwrap(), vec![3.0, 7.5, 0.0]);
+        assert_eq!(
+            parse_scores("Here are the scores:\n```json\n[10, 2]\n```", 2).unwrap(),
+            vec![10.0, 2.0]
+        );
+    }
+
+    #[test]
+    fn rejects_missing_or_extra_scores() {
+        assert!(parse_scores("[3, 7]", 3).is_err());
+        assert!(parse_scores("[3, 7, 1, 9]", 3).is_err());
+        assert!(parse_scores("[]", 1).is_err());
+    }
+
+    #[test]
+    fn rejects_scores_that_are_not_numbers() {
+        assert!(parse_scores(r#"["high", 3]"#, 2).is_err());
+        assert!(parse_scores("[null, 3]", 2).is_err());
+        assert!(parse_scores("[{\"passage\": 1, \"score\": 3}]", 1).is_err());
+        assert!(parse_scores("Passage 1 is a 7", 1).is_err());
+        assert!(parse_scores("] 7 [", 1).is_err());
+    }
+
+    #[test]
+    fn orders_http_scores_by_index() {
+        let response = vec![
+            HttpRerankScore {
+                index: 2,
+                score: 0.9,
+            },
+            HttpRerankScore {
+                index: 0,
+                score: 0.1,
+            },
+            HttpRerankScore {
+                index: 7,
+                score: 5.0,
+            },
+        ];
+
+        assert_eq!(
+            scores_by_index(response, 3),
+            vec![0.1, f64::NEG_INFINITY, 0.9]
+        );
+    }
+}
@@ -0,0 +1,280 @@
+use std::{collections::HashMap, sync::Arc};
+
+use itertools::Itertools;
+use miette::{IntoDiagnostic, Result};
+use rusqlite::{params, Connection};
+
+use crate::{
+    context::{Context, ContextChunk},
+    corpus::Corpus,
+    embedding::decode_embedding,
+    expansion::{expand_query, QueryExpansion},
+    fetch_embedding,
+    metadata::EmbeddingSettings,
+    mmr::{cosine_similarity, mmr},
+    rerank::Reranker,
+    Config, Prompts,
+};
+
+#[derive(Clone, Debug)]
+pub struct RetrievalOptions {
+    /// How many chunks end up in the context
+    pub limit: usize,
+    /// How many vector search hits to pull before re-ranking them down to `limit`
+    pub candidates: usize,
+    /// Re-rank the candidates with Maximal Marginal Relevance using this lambda.
+    /// 1.0 is pure relevance, 0.0 is pure diversity. `None` skips re-ranking.
+    pub mmr_lambda: Option<f64>,
+    /// Score every candidate against the question and keep the best `limit`.
+    /// When set this replaces MMR.
+    pub reranker: Option<Arc<dyn Reranker>>,
+    /// Search with LLM written rewrites of the question as well as the question itself
+    pub expansion: QueryExpansion,
+    /// Templates for the query expansion prompts
+    pub prompts: Prompts,
+}
+
+impl Default for RetrievalOptions {
+    fn default() -> Self {
+        Self {
+            limit: 10,
+            candidates: 30,
+            mmr_lambda: Some(0.7),
+            reranker: None,
+            expansion: QueryExpansion::None,
+            prompts: Prompts::default(),
+        }
+    }
+}
+
+/// A vector search hit, with its distance already scaled by the corpus weight
+#[derive(Clone, Copy, Debug)]
+struct Candidate {
+    corpus: usize,
+    rowid: u32,
+    distance: f64,
+}
+
+pub async fn get_context(query: String, corpora: &[Corpus]) -> Result<(Context, String)> {
+    get_context_with_options(query, corpora, &RetrievalOptions::default()).await
+}
+
+pub async fn get_context_with_options(
+    query: String,
+    corpora: &[Corpus],
+    options: &RetrievalOptions,
+) -> Result<(Context, String)> {
+    let config = Config::from_env()?;
+    let client = config.client()?;
+    let question = &query;
+
+    let settings = corpora
+        .iter()
+        .map(|corpus| EmbeddingSettings::load(&corpus.conn.0.lock().unwrap()))
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(model) = config.embedding_model() {
+        for s in &settings {
+            s.check_model(model)?;
+        }
+    }
+
+    let queries = expand_query(&client, &options.prompts, question, options.expansion).await?;
+
+    // Corpora built with different models need the queries embedded once per model
+    let mut embeddings: HashMap<&EmbeddingSettings, Vec<Vec<f64>>> = HashMap::new();
+    for s in settings.iter().unique() {
+        let query_embeddings = futures::future::try_join_all(
+            queries
+                .iter()
+                .map(|query| fetch_embedding(&client, s, query)),
+        )
+        .await?;
+        embeddings.insert(s, query_embeddings);
+    }
+
+    let mut candidates = vec![];
+    for (i, corpus) in corpora.iter().enumerate() {
+        let conn = corpus.conn.0.lock().unwrap();
+
+        for query_embedding in &embeddings[&settings[i]] {
+            candidates.extend(
+                corpus
+                    .index
+                    .search(&conn, query_embedding, options.candidates)?
+                    .into_iter()
+                    .map(|(rowid, distance)| Candidate {
+                        corpus: i,
+                        rowid,
+                        distance: distance / corpus.weight,
+                    }),
+            );
+        }
+    }
+    // Keep each sentence once, at its best distance across all the queries
+    let candidates = candidates
+        .into_iter()
+        .sorted_by(|a, b| a.distance.total_cmp(&b.distance))
+        .unique_by(|c| (c.corpus, c.rowid))
+        .take(options.candidates)
+        .collect_vec();
+
+    let candidates = match (&options.reranker, options.mmr_lambda) {
+        // The reranker needs to see every candidate
+        (Some(_), _) => candidates,
+        (None, Some(lambda)) => {
+            let candidate_embeddings = candidates
+                .iter()
+                .map(|c| sentence_embedding(&corpora[c.corpus].conn.0.lock().unwrap(), c.rowid))
+                .collect::<Result<Vec<_>>>()?;
+            // MMR measures relevance against the question itself, not the expansions
+            let relevance = candidates
+                .iter()
+                .zip(&candidate_embeddings)
+                .map(|(c, embedding)| {
+                    let question_embedding = &embeddings[&settings[c.corpus]][0];
+                    weighted_relevance(
+                        cosine_similarity(question_embedding, embedding),
+                        corpora[c.corpus].weight,
+                    )
+                })
+                .collect_vec();
+
+            mmr(&relevance, &candidate_embeddings, lambda, options.limit)
+                .into_iter()
+                .map(|i| candidates[i])
+                .collect_vec()
+        }
+        (None, None) => candidates.into_iter().take(options.limit).collect_vec(),
+    };
+
+    let chunks = candidates
+        .into_iter()
+        .map(|c| {
+            let corpus = &corpora[c.corpus];
+            context_chunk(
+                &corpus.conn.0.lock().unwrap(),
+                &corpus.name,
+                c.rowid,
+                c.distance,
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let chunks = match &options.reranker {
+        Some(reranker) => {
+            let scores = reranker.score(question, &chunks).await?;
+
+            chunks
+                .into_iter()
+                .zip(scores)
+                .sorted_by(|(_, a), (_, b)| b.total_cmp(a))
+                .map(|(chunk, _)| chunk)
+                .take(options.limit)
+                .collect_vec()
+        }
+        None => chunks,
+    };
+
+    Ok((Context::new(chunks), question.to_owned()))
+}
+
+/// Scales a similarity by the corpus weight the same way search distances are, so weights
+/// still count when MMR re-ranks the candidates
+fn weighted_relevance(similarity: f64, weight: f64) -> f64 {
+    1.0 - (1.0 - similarity) / weight
+}
+
+/// Builds the chunk for a search hit from the sentences around it on the same page
+pub(crate) fn context_chunk(
+    conn: &Connection,
+    corpus: &str,
+    rowid: u32,
+    distance: f64,
+) -> Result<ContextChunk> {
+    let mut stmt = conn
+        .prepare(
+            "
+        select sentences.page_id, sentences.page_index, pages.path
+        from sentences
+        join pages on pages.rowid = sentences.page_id
+        where sentences.rowid = ?1",
+        )
+        .into_diagnostic()?;
+    let (page_id, page_index, page_path): (u32, u32, String) = stmt
+        .query_row(params![rowid], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })
+        .into_diagnostic()?;
+
+    let texts = conn
+        .prepare(
+            "
+        select text
+        from sentences
+        where page_id = ?1
+        AND page_index >= (?2 - 3)
+        AND page_index <= (?2 + 5)",
+        )
+        .into_diagnostic()?
+        .query_map(params![page_id, page_index], |row| row.get(0))
+        .into_diagnostic()?
+        .collect::<Result<Vec<String>, rusqlite::Error>>()
+        .into_diagnostic()?;
+
+    Ok(ContextChunk {
+        corpus: corpus.to_string(),
+        page_path,
+        text: texts.join("\n"),
+        distance,
+    })
+}
+
+pub(crate) fn sentence_embedding(conn: &Connection, rowid: u32) -> Result<Vec<f64>> {
+    let blob: Vec<u8> = conn
+        .query_row(
+            "select embedding from sentences where rowid = ?1",
+            params![rowid],
+            |row| row.get(0),
+        )
+        .into_diagnostic()?;
+
+    Ok(decode_embedding(&blob)?
+        .into_iter()
+        .map(f64::from)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn weights_scale_relevance_like_distance() {
+        assert_eq!(weighted_relevance(0.8, 1.0), 0.8);
+        assert!((weighted_relevance(0.8, 2.0) - 0.9).abs() < 1e-9);
+        assert!((weighted_relevance(0.8, 0.5) - 0.6).abs() < 1e-9);
+    }
+
+    #[test]
+    fn a_down_weighted_corpus_ranks_lower_with_mmr() {
+        let question = [1.0, 0.0];
+        // A sentence from a corpus at full weight, and a slightly closer one from a corpus
+        // weighted at 0.5
+        let embeddings = vec![vec![0.8, 0.6], vec![0.85, -(1.0f64 - 0.85 * 0.85).sqrt()]];
+        let weights = [1.0, 0.5];
+        let lambda = RetrievalOptions::default().mmr_lambda.unwrap();
+
+        let unweighted = embeddings
+            .iter()
+            .map(|embedding| cosine_similarity(&question, embedding))
+            .collect_vec();
+        assert_eq!(mmr(&unweighted, &embeddings, lambda, 2), vec![1, 0]);
+
+        let weighted = unweighted
+            .iter()
+            .zip(weights)
+            .map(|(similarity, weight)| weighted_relevance(*similarity, weight))
+            .collect_vec();
+        assert_eq!(mmr(&weighted, &embeddings, lambda, 2), vec![0, 1]);
+    }
+}
@@ -1,7 +1,82 @@
-use miette::{IntoDiagnostic, Result};
+use miette::{miette, IntoDiagnostic, Result};
 use rusqlite::Connection;
 
-pub fn setup_schema_v0(conn: &Connection) -> Result<()> {
+use crate::{
+    index::{rebuild_vss_index, VssIndex},
+    metadata::{EmbeddingSettings, EMBEDDING_DEFAULT_DIMENSION},
+};
+
+struct Migration {
+    name: &'static str,
+    up: fn(&Connection) -> Result<()>,
+    /// Only runs when sqlite-vss is loaded. Without it the step is counted as done and
+    /// [VssIndex::open] builds what it would have the first time the DB is opened with sqlite-vss
+    needs_vss: bool,
+}
+
+/// Every schema change, oldest first. The DB's `user_version` is how many of these have run.
+///
+/// Only ever append to this list. Editing a step that has shipped would leave DBs that already
+/// ran it out of sync with new ones.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        name: "create pages and sentences",
+        up: create_pages_and_sentences,
+        needs_vss: false,
+    },
+    Migration {
+        name: "persist vss_sentences index",
+        up: |conn| rebuild_vss_index(conn, EMBEDDING_DEFAULT_DIMENSION),
+        needs_vss: true,
+    },
+    Migration {
+        name: "record embedding settings in metadata",
+        up: create_metadata,
+        needs_vss: false,
+    },
+];
+
+/// The schema version this binary builds, and the newest one it can read
+pub const SCHEMA_VERSION: usize = MIGRATIONS.len();
+
+pub fn schema_version(conn: &Connection) -> Result<usize> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+        .into_diagnostic()
+}
+
+/// Brings the DB up to [SCHEMA_VERSION], running each missing step in its own transaction
+pub fn migrate(conn: &mut Connection) -> Result<()> {
+    let version = schema_version(conn)?;
+
+    if version > SCHEMA_VERSION {
+        return Err(miette!(
+            help = "Update snakegpt to a version that knows about this schema",
+            "The DB is at schema version {version} but this build only supports up to {SCHEMA_VERSION}"
+        ));
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let to_version = i + 1;
+
+        let tx = conn.transaction().into_diagnostic()?;
+        if !migration.needs_vss || VssIndex::available(&tx)? {
+            (migration.up)(&tx).map_err(|e| {
+                miette!(
+                    "Migration to schema version {to_version} ({name}) failed: {e}",
+                    name = migration.name
+                )
+            })?;
+        }
+        tx.pragma_update(None, "user_version", to_version)
+            .into_diagnostic()?;
+        tx.commit().into_diagnostic()?;
+    }
+
+    Ok(())
+}
+
+/// DBs built before versioning already have these tables, so this step has to tolerate them
+fn create_pages_and_sentences(conn: &Connection) -> Result<()> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS pages (
             path                  TEXT NOT NULL,
@@ -33,22 +108,104 @@ pub fn setup_schema_v0(conn: &Connection) -> Result<()> {
     )
     .into_diagnostic()?;
 
-    conn.execute_batch(
-        "
-  DROP TABLE IF EXISTS vss_sentences;
-  create virtual table vss_sentences using vss0(
-      embedding(1536),
-    );
-  ",
-    )
-    .into_diagnostic()?;
+    Ok(())
+}
 
+/// Everything built before this was embedded with the default model
+fn create_metadata(conn: &Connection) -> Result<()> {
     conn.execute(
-        "insert into vss_sentences(rowid, embedding)
-  select rowid, embedding from sentences;",
+        "CREATE TABLE IF NOT EXISTS metadata (
+            key                   TEXT PRIMARY KEY,
+            value                 TEXT NOT NULL
+        )",
         (),
     )
     .into_diagnostic()?;
 
-    Ok(())
+    EmbeddingSettings::default().save(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The tables as they were before the schema was versioned
+    fn baseline_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE pages (path TEXT NOT NULL, parsed_text TEXT);
+            CREATE UNIQUE INDEX uniq_index_pages_path on pages (path);
+            CREATE TABLE sentences (
+                page_id INTEGER NOT NULL,
+                page_index INTEGER NOT NULL,
+                text TEXT NOT NULL,
+                embedding fvector
+            );
+            CREATE UNIQUE INDEX uniq_index_sentences_text on sentences (text);
+            INSERT INTO pages (path, parsed_text) VALUES ('rules/food.md', 'Eat food');
+            INSERT INTO sentences (page_id, page_index, text) VALUES (1, 0, 'Eat food');",
+        )
+        .unwrap();
+        conn
+    }
+
+    fn count(conn: &Connection, table: &str) -> usize {
+        conn.query_row(&format!("select count(*) from {table}"), (), |row| {
+            row.get(0)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn upgrades_a_baseline_db_in_place() {
+        let mut conn = baseline_db();
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+        assert_eq!(count(&conn, "pages"), 1);
+        assert_eq!(count(&conn, "sentences"), 1);
+        assert_eq!(
+            EmbeddingSettings::load(&conn).unwrap(),
+            EmbeddingSettings::default()
+        );
+    }
+
+    #[test]
+    fn creates_a_new_db() {
+        let mut conn = Connection::open_in_memory().unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+        assert_eq!(count(&conn, "pages"), 0);
+    }
+
+    #[test]
+    fn rejects_a_db_from_a_newer_build() {
+        let mut conn = baseline_db();
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+
+        assert!(migrate(&mut conn).is_err());
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION + 1);
+    }
+
+    #[test]
+    fn migrating_again_changes_nothing() {
+        let mut conn = baseline_db();
+        migrate(&mut conn).unwrap();
+        conn.execute(
+            "UPDATE metadata SET value = 'kept' WHERE key = 'embedding_model'",
+            (),
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+        assert_eq!(count(&conn, "pages"), 1);
+        assert_eq!(EmbeddingSettings::load(&conn).unwrap().model, "kept");
+    }
 }
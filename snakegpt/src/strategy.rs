@@ -0,0 +1,243 @@
+use std::{fmt, str::FromStr, sync::Arc};
+
+use async_trait::async_trait;
+use miette::{miette, Result};
+use serde::Serialize;
+
+use crate::{
+    answer_prompt,
+    battlesnake::describe_game_state,
+    context::Context,
+    corpus::Corpus,
+    respond_with_prompt,
+    retrieval::{get_context_with_options, RetrievalOptions},
+    stream_with_prompt, Answer, Config, Prompts,
+};
+
+mod react;
+
+pub use react::React;
+
+/// Gets each piece of an answer as it is generated
+pub type OnToken<'a> = &'a mut (dyn FnMut(&str) + Send);
+
+/// One way of turning a question into an answer
+///
+/// Most strategies retrieve context, build a prompt from it and generate an answer, which is
+/// what [AnswerStrategy::answer] does by default. Strategies that work differently, like ones
+/// that go back and forth with the model, override it.
+#[async_trait]
+pub trait AnswerStrategy: fmt::Debug + Send + Sync {
+    fn name(&self) -> StrategyName;
+
+    /// Returns the context for the question, and the question it was retrieved for after any
+    /// rewriting
+    ///
+    /// Any game state pasted into the question has already been taken out
+    async fn retrieve(&self, question: &str) -> Result<(Context, String)>;
+
+    fn prompt(&self, context: &Context, question: &str) -> String;
+
+    /// Sends the prompt to the model, streaming the answer to `on_token` when given one
+    async fn generate(
+        &self,
+        context: &Context,
+        prompt: &str,
+        on_token: Option<OnToken<'_>>,
+    ) -> Result<Answer>;
+
+    async fn answer(
+        &self,
+        question: &str,
+        on_token: Option<OnToken<'_>>,
+    ) -> Result<StrategyResponse> {
+        let (question, game) = describe_game_state(question);
+        let (context, question) = self.retrieve(&question).await?;
+        let prompt = self.prompt(&context, &with_game(&question, game.as_deref()));
+        let answer = self.generate(&context, &prompt, on_token).await?;
+
+        Ok(StrategyResponse {
+            strategy: self.name(),
+            question,
+            context,
+            prompt,
+            answer,
+            trace: vec![],
+        })
+    }
+}
+
+/// An answer along with what it was based on
+#[derive(Clone, Debug, Serialize)]
+pub struct StrategyResponse {
+    pub strategy: StrategyName,
+    /// The question the context was retrieved for, after any rewriting
+    pub question: String,
+    pub context: Context,
+    /// The prompt that produced the answer
+    pub prompt: String,
+    pub answer: Answer,
+    /// What the strategy did along the way, empty for strategies that don't call tools
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub trace: Vec<Step>,
+}
+
+/// One round of a strategy that calls tools
+#[derive(Clone, Debug, Serialize)]
+pub struct Step {
+    pub thought: String,
+    /// `None` for the step that gave the final answer
+    pub action: Option<ToolCall>,
+    /// What the tool returned, as shown to the model
+    pub observation: Option<String>,
+    /// The model didn't follow the format, so the observation tells it how to
+    pub invalid: bool,
+}
+
+impl Step {
+    /// What the step did, the tool it called, `invalid` for a turn that didn't follow the
+    /// format, or `answer`
+    pub fn action_type(&self) -> &str {
+        match &self.action {
+            Some(action) => &action.tool,
+            None if self.invalid => "invalid",
+            None => "answer",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ToolCall {
+    pub tool: String,
+    pub input: String,
+}
+
+/// How many tools a strategy may call before it has to answer
+pub const DEFAULT_MAX_STEPS: usize = 6;
+
+/// Everything a strategy is built from, chosen per request
+#[derive(Clone, Debug)]
+pub struct StrategySetup {
+    pub config: Config,
+    pub corpora: Vec<Corpus>,
+    pub options: RetrievalOptions,
+    pub prompts: Prompts,
+    /// The chat model that writes the answer
+    pub model: String,
+    /// How many tools the strategy may call, ignored by strategies that don't call any
+    pub max_steps: usize,
+}
+
+/// The strategies that can be picked by name
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum StrategyName {
+    /// Retrieve context for the question once and answer from it
+    #[default]
+    SimpleRag,
+    /// Let the model search, read and list the docs, and play out game turns, as much as it
+    /// needs before answering
+    React,
+}
+
+impl StrategyName {
+    pub const ALL: [StrategyName; 2] = [StrategyName::SimpleRag, StrategyName::React];
+
+    pub fn build(self, setup: StrategySetup) -> Arc<dyn AnswerStrategy> {
+        match self {
+            StrategyName::SimpleRag => Arc::new(SimpleRag::new(setup)),
+            StrategyName::React => Arc::new(React::new(setup)),
+        }
+    }
+}
+
+impl fmt::Display for StrategyName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StrategyName::SimpleRag => "simple-rag",
+            StrategyName::React => "react",
+        })
+    }
+}
+
+impl FromStr for StrategyName {
+    type Err = miette::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        StrategyName::ALL
+            .into_iter()
+            .find(|name| name.to_string() == s)
+            .ok_or_else(|| {
+                miette!(
+                    "Unknown strategy {s}, expected one of: {}",
+                    StrategyName::ALL.map(|name| name.to_string()).join(", ")
+                )
+            })
+    }
+}
+
+/// The original pipeline: search the corpora for the question and answer from what was found
+#[derive(Clone, Debug)]
+pub struct SimpleRag {
+    setup: StrategySetup,
+}
+
+impl SimpleRag {
+    pub fn new(setup: StrategySetup) -> Self {
+        Self { setup }
+    }
+}
+
+#[async_trait]
+impl AnswerStrategy for SimpleRag {
+    fn name(&self) -> StrategyName {
+        StrategyName::SimpleRag
+    }
+
+    async fn retrieve(&self, question: &str) -> Result<(Context, String)> {
+        get_context_with_options(
+            question.to_string(),
+            &self.setup.corpora,
+            &self.setup.options,
+        )
+        .await
+    }
+
+    fn prompt(&self, context: &Context, question: &str) -> String {
+        answer_prompt(&self.setup.prompts, context, question)
+    }
+
+    async fn generate(
+        &self,
+        context: &Context,
+        prompt: &str,
+        on_token: Option<OnToken<'_>>,
+    ) -> Result<Answer> {
+        generate(&self.setup, context, prompt, on_token).await
+    }
+}
+
+/// Shows the model the board from a pasted game state after the question
+fn with_game(question: &str, game: Option<&str>) -> String {
+    match game {
+        Some(game) => format!("{question}\n\n{game}"),
+        None => question.to_string(),
+    }
+}
+
+/// Sends a prompt to the setup's model, streaming when there is somewhere to stream to
+async fn generate(
+    setup: &StrategySetup,
+    context: &Context,
+    prompt: &str,
+    on_token: Option<OnToken<'_>>,
+) -> Result<Answer> {
+    let client = setup.config.client()?;
+
+    match on_token {
+        Some(on_token) => {
+            stream_with_prompt(&client, context, prompt, &setup.model, on_token).await
+        }
+        None => respond_with_prompt(&client, context, prompt, &setup.model).await,
+    }
+}
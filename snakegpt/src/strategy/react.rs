@@ -0,0 +1,537 @@
+use std::{fmt::Write as _, sync::Arc};
+
+use async_trait::async_trait;
+use itertools::Itertools;
+use miette::Result;
+
+use super::{
+    generate, with_game, AnswerStrategy, OnToken, Step, StrategyName, StrategyResponse,
+    StrategySetup, ToolCall,
+};
+use crate::{
+    battlesnake::{find_game_state, GameState},
+    citations::cited_sources,
+    context::{Context, ContextChunk},
+    retrieval::RetrievalOptions,
+    tools::{doc_tools, Observation, SimulateTurn, Tool},
+    Answer, CompletionUsage, PromptName,
+};
+
+/// Fewer passages per search than a one shot retrieval, the model can always search again
+const SEARCH_LIMIT: usize = 5;
+
+/// Every run starts by searching for the question, before the model has had a turn
+const FIRST_THOUGHT: &str = "I should start by searching the docs for the question";
+
+const OUT_OF_STEPS_THOUGHT: &str = "I have looked up all I can and have to answer now";
+
+/// Lets the model call tools, looking through the docs as many times as it needs up to the
+/// step limit, before it answers
+///
+/// Besides the doc tools the model can play out a turn of a Battlesnake game with
+/// `simulate_turn`, on the board from the question when there is one.
+///
+/// Every passage a tool turns up is added to the context and numbered as it is shown to the
+/// model, so the final answer can cite passages from any step.
+#[derive(Debug)]
+pub struct React {
+    setup: StrategySetup,
+    tools: Vec<Arc<dyn Tool>>,
+}
+
+/// What the model decided to do on its turn
+enum Turn {
+    Call {
+        thought: String,
+        call: ToolCall,
+    },
+    Answer {
+        thought: String,
+        answer: String,
+    },
+    /// The model didn't follow the format
+    Invalid {
+        thought: String,
+    },
+}
+
+impl React {
+    pub fn new(setup: StrategySetup) -> Self {
+        let options = RetrievalOptions {
+            limit: setup.options.limit.min(SEARCH_LIMIT),
+            ..setup.options.clone()
+        };
+        let mut tools = doc_tools(&setup.corpora, &options);
+        tools.push(Arc::new(SimulateTurn::default()));
+
+        Self { setup, tools }
+    }
+
+    /// Adds another tool the model can call
+    pub fn with_tool(mut self, tool: Arc<dyn Tool>) -> Self {
+        self.tools.push(tool);
+        self
+    }
+
+    /// The tools with `simulate_turn` playing on the game from the question
+    fn tools_for(&self, game: Option<&GameState>) -> Vec<Arc<dyn Tool>> {
+        self.tools
+            .iter()
+            .map(|tool| match tool.name() {
+                "simulate_turn" => Arc::new(SimulateTurn {
+                    game: game.cloned(),
+                }),
+                _ => tool.clone(),
+            })
+            .collect()
+    }
+
+    fn render(&self, tools: &[Arc<dyn Tool>], question: &str, scratchpad: &str) -> String {
+        let tools = tools
+            .iter()
+            .map(|tool| format!("{}: {}", tool.name(), tool.description()))
+            .join("\n");
+
+        self.setup.prompts.render(
+            PromptName::React,
+            &[
+                ("tools", &tools),
+                ("question", question),
+                ("scratchpad", scratchpad),
+            ],
+        )
+    }
+
+    /// Runs the tool, turning failures into observations so the model can try something else
+    async fn call(tools: &[Arc<dyn Tool>], call: &ToolCall, context: &mut Context) -> String {
+        let Some(tool) = tools.iter().find(|tool| tool.name() == call.tool) else {
+            return format!(
+                "There is no {} tool, use one of: {}",
+                call.tool,
+                tools.iter().map(|tool| tool.name()).join(", ")
+            );
+        };
+
+        match tool.call(&call.input).await {
+            Ok(observation) => show_observation(observation, context),
+            Err(e) => format!("{} failed: {e}", call.tool),
+        }
+    }
+}
+
+#[async_trait]
+impl AnswerStrategy for React {
+    fn name(&self) -> StrategyName {
+        StrategyName::React
+    }
+
+    /// Searches for the question as it was asked, the same first step the model would take
+    async fn retrieve(&self, question: &str) -> Result<(Context, String)> {
+        let mut context = Context::default();
+        Self::call(&self.tools, &first_search(question), &mut context).await;
+
+        Ok((context, question.to_string()))
+    }
+
+    /// The prompt for the model's first turn, after the search for the question
+    fn prompt(&self, context: &Context, question: &str) -> String {
+        let observation = show_observation(
+            Observation {
+                text: format!("Found {} passages", context.chunks.len()),
+                chunks: context.chunks.clone(),
+            },
+            &mut Context::default(),
+        );
+
+        self.render(
+            &self.tools,
+            question,
+            &scratchpad(&[Step {
+                thought: FIRST_THOUGHT.to_string(),
+                action: Some(first_search(question)),
+                observation: Some(observation),
+                invalid: false,
+            }]),
+        )
+    }
+
+    async fn generate(
+        &self,
+        context: &Context,
+        prompt: &str,
+        on_token: Option<OnToken<'_>>,
+    ) -> Result<Answer> {
+        generate(&self.setup, context, prompt, on_token).await
+    }
+
+    async fn answer(
+        &self,
+        question: &str,
+        on_token: Option<OnToken<'_>>,
+    ) -> Result<StrategyResponse> {
+        // Search with just the words of the question, the model gets the board instead
+        let pasted = find_game_state(question);
+        let (question, game) = match &pasted {
+            Some(pasted) => (pasted.question.clone(), Some(pasted.state.to_prompt())),
+            None => (question.to_string(), None),
+        };
+        let prompt_question = with_game(&question, game.as_deref());
+        let tools = self.tools_for(pasted.as_ref().map(|pasted| &pasted.state));
+
+        let mut context = Context::default();
+        let first_call = first_search(&question);
+        let observation = Self::call(&tools, &first_call, &mut context).await;
+        let mut trace = vec![Step {
+            thought: FIRST_THOUGHT.to_string(),
+            action: Some(first_call),
+            observation: Some(observation),
+            invalid: false,
+        }];
+        let mut usage: Option<CompletionUsage> = None;
+
+        let (prompt, text) = loop {
+            if trace.len() >= self.setup.max_steps {
+                // Out of steps, so make the model answer with what it has
+                let prompt = format!(
+                    "{}Thought: {OUT_OF_STEPS_THOUGHT}\nFinal Answer:",
+                    self.render(&tools, &prompt_question, &scratchpad(&trace))
+                );
+                let turn = self.generate(&context, &prompt, None).await?;
+                add_usage(&mut usage, turn.usage);
+
+                let answer = turn.text.trim().to_string();
+                trace.push(Step {
+                    thought: OUT_OF_STEPS_THOUGHT.to_string(),
+                    action: None,
+                    observation: None,
+                    invalid: false,
+                });
+                break (prompt, answer);
+            }
+
+            let prompt = self.render(&tools, &prompt_question, &scratchpad(&trace));
+            let turn = self.generate(&context, &prompt, None).await?;
+            add_usage(&mut usage, turn.usage);
+
+            match parse_turn(&turn.text) {
+                Turn::Answer { thought, answer } => {
+                    trace.push(Step {
+                        thought,
+                        action: None,
+                        observation: None,
+                        invalid: false,
+                    });
+                    break (prompt, answer);
+                }
+                Turn::Call { thought, call } => {
+                    let observation = Self::call(&tools, &call, &mut context).await;
+                    trace.push(Step {
+                        thought,
+                        action: Some(call),
+                        observation: Some(observation),
+                        invalid: false,
+                    });
+                }
+                Turn::Invalid { thought } => trace.push(Step {
+                    thought,
+                    action: None,
+                    observation: Some(
+                        "Respond with an Action and Action Input, or with a Final Answer"
+                            .to_string(),
+                    ),
+                    invalid: true,
+                }),
+            }
+        };
+
+        if let Some(on_token) = on_token {
+            on_token(&text);
+        }
+
+        Ok(StrategyResponse {
+            strategy: self.name(),
+            question,
+            answer: Answer {
+                sources: cited_sources(&text, &context),
+                text,
+                model: self.setup.model.clone(),
+                usage,
+            },
+            context,
+            prompt,
+            trace,
+        })
+    }
+}
+
+fn first_search(question: &str) -> ToolCall {
+    ToolCall {
+        tool: "search_docs".to_string(),
+        input: question.to_string(),
+    }
+}
+
+/// Adds the observation's passages to the context and writes them out with their numbers
+///
+/// A passage that is already in the context keeps the number it was first shown with
+fn show_observation(observation: Observation, context: &mut Context) -> String {
+    let mut shown = observation.text;
+
+    for chunk in observation.chunks {
+        let number = match context
+            .chunks
+            .iter()
+            .position(|seen| same_chunk(seen, &chunk))
+        {
+            Some(i) => i + 1,
+            None => {
+                context.chunks.push(chunk.clone());
+                context.chunks.len()
+            }
+        };
+
+        let _ = write!(
+            shown,
+            "\n\n[{number}] (from {path})\n{text}",
+            path = chunk.page_path,
+            text = chunk.text.trim().replace("\n\n", "\n")
+        );
+    }
+
+    shown
+}
+
+fn same_chunk(a: &ContextChunk, b: &ContextChunk) -> bool {
+    a.corpus == b.corpus && a.page_path == b.page_path && a.text == b.text
+}
+
+/// The steps so far, in the format the model is asked to follow
+fn scratchpad(trace: &[Step]) -> String {
+    let mut scratchpad = String::new();
+
+    for step in trace {
+        let _ = writeln!(scratchpad, "Thought: {}", step.thought);
+        if let Some(action) = &step.action {
+            let _ = writeln!(scratchpad, "Action: {}", action.tool);
+            let _ = writeln!(scratchpad, "Action Input: {}", action.input);
+        }
+        if let Some(observation) = &step.observation {
+            let _ = writeln!(scratchpad, "Observation: {observation}");
+        }
+    }
+
+    scratchpad
+}
+
+fn parse_turn(text: &str) -> Turn {
+    // Models like to carry on and make up the observation themselves
+    let text = text.split("\nObservation:").next().unwrap_or_default();
+
+    if let Some((before, answer)) = text.split_once("Final Answer:") {
+        return Turn::Answer {
+            thought: thought(before),
+            answer: answer.trim().to_string(),
+        };
+    }
+
+    let Some((before, action)) = text.split_once("Action:") else {
+        return Turn::Invalid {
+            thought: thought(text),
+        };
+    };
+    let (tool, input) = match action.split_once("Action Input:") {
+        Some((tool, input)) => (tool.trim(), input.trim()),
+        // Some models write the call out like `search_docs(food)` instead
+        None => match action.trim().split_once(['(', '[']) {
+            Some((tool, input)) => (tool.trim(), input.trim().trim_end_matches([')', ']'])),
+            None => (action.trim(), ""),
+        },
+    };
+
+    Turn::Call {
+        thought: thought(before),
+        call: ToolCall {
+            tool: tool.to_string(),
+            input: input.trim_matches(['"', '\'', '`']).to_string(),
+        },
+    }
+}
+
+fn thought(text: &str) -> String {
+    let text = text.trim();
+    text.strip_prefix("Thought:")
+        .unwrap_or(text)
+        .trim()
+        .to_string()
+}
+
+fn add_usage(total: &mut Option<CompletionUsage>, usage: Option<CompletionUsage>) {
+    let Some(usage) = usage else {
+        return;
+    };
+
+    *total = Some(match total.take() {
+        Some(total) => CompletionUsage {
+            completion_tokens: total.completion_tokens + usage.completion_tokens,
+            prompt_tokens: total.prompt_tokens + usage.prompt_tokens,
+            total_tokens: total.total_tokens + usage.total_tokens,
+        },
+        None => usage,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(text: &str) -> (String, String, String) {
+        match parse_turn(text) {
+            Turn::Call { thought, call } => (thought, call.tool, call.input),
+            _ => panic!("expected a tool call from {text:?}"),
+        }
+    }
+
+    fn chunk(page_path: &str, text: &str) -> ContextChunk {
+        ContextChunk {
+            corpus: "official".to_string(),
+            page_path: page_path.to_string(),
+            text: text.to_string(),
+            distance: 0.0,
+        }
+    }
+
+    #[test]
+    fn parses_actions() {
+        assert_eq!(
+            call("Thought: I need the food rules\nAction: search_docs\nAction Input: \"food\""),
+            (
+                "I need the food rules".to_string(),
+                "search_docs".to_string(),
+                "food".to_string()
+            )
+        );
+        assert_eq!(
+            call("Action: read_page(rules/food.md)"),
+            (
+                String::new(),
+                "read_page".to_string(),
+                "rules/food.md".to_string()
+            )
+        );
+        assert_eq!(
+            call("Thought: list them\nAction: list_pages"),
+            (
+                "list them".to_string(),
+                "list_pages".to_string(),
+                String::new()
+            )
+        );
+    }
+
+    #[test]
+    fn ignores_observations_the_model_makes_up() {
+        let (_, tool, input) = call(
+            "Action: search_docs\nAction Input: food\nObservation: Food heals\nFinal Answer: 100",
+        );
+
+        assert_eq!(tool, "search_docs");
+        assert_eq!(input, "food");
+    }
+
+    #[test]
+    fn parses_final_answers() {
+        let Turn::Answer { thought, answer } =
+            parse_turn("Thought: I know this now\nFinal Answer: Food restores health [1].\n")
+        else {
+            panic!("expected an answer");
+        };
+
+        assert_eq!(thought, "I know this now");
+        assert_eq!(answer, "Food restores health [1].");
+    }
+
+    #[test]
+    fn turns_without_an_action_or_answer_are_invalid() {
+        let Turn::Invalid { thought } = parse_turn("Thought: Snakes are great\nI like them") else {
+            panic!("expected an invalid turn");
+        };
+
+        assert_eq!(thought, "Snakes are great\nI like them");
+    }
+
+    #[test]
+    fn steps_are_labelled_by_what_they_did() {
+        let step = |action: Option<ToolCall>, invalid| Step {
+            thought: String::new(),
+            action,
+            observation: None,
+            invalid,
+        };
+
+        assert_eq!(
+            step(Some(first_search("food")), false).action_type(),
+            "search_docs"
+        );
+        assert_eq!(step(None, true).action_type(), "invalid");
+        assert_eq!(step(None, false).action_type(), "answer");
+    }
+
+    #[test]
+    fn writes_the_scratchpad_in_the_prompt_format() {
+        let trace = [
+            Step {
+                thought: FIRST_THOUGHT.to_string(),
+                action: Some(first_search("How do snakes eat?")),
+                observation: Some("Found 2 passages".to_string()),
+                invalid: false,
+            },
+            Step {
+                thought: "Hmm".to_string(),
+                action: None,
+                observation: Some("Respond with an Action".to_string()),
+                invalid: true,
+            },
+        ];
+
+        assert_eq!(
+            scratchpad(&trace),
+            format!(
+                "Thought: {FIRST_THOUGHT}\nAction: search_docs\nAction Input: How do snakes eat?\n\
+                Observation: Found 2 passages\nThought: Hmm\nObservation: Respond with an Action\n"
+            )
+        );
+    }
+
+    #[test]
+    fn passages_keep_the_number_they_were_first_shown_with() {
+        let mut context = Context::default();
+
+        let first = show_observation(
+            Observation {
+                text: "Found 2 passages".to_string(),
+                chunks: vec![
+                    chunk("food.md", "Food heals\n\nfully"),
+                    chunk("hazards.md", "Ouch"),
+                ],
+            },
+            &mut context,
+        );
+        assert_eq!(
+            first,
+            "Found 2 passages\n\n[1] (from food.md)\nFood heals\nfully\n\n[2] (from hazards.md)\nOuch"
+        );
+
+        let second = show_observation(
+            Observation {
+                text: "Found 2 passages".to_string(),
+                chunks: vec![chunk("moves.md", "Up"), chunk("hazards.md", "Ouch")],
+            },
+            &mut context,
+        );
+        assert_eq!(
+            second,
+            "Found 2 passages\n\n[3] (from moves.md)\nUp\n\n[2] (from hazards.md)\nOuch"
+        );
+        assert_eq!(context.chunks.len(), 3);
+    }
+}
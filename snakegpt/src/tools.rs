@@ -0,0 +1,466 @@
+use std::{fmt::Debug, sync::Arc};
+
+use async_trait::async_trait;
+use itertools::Itertools;
+use miette::{miette, Context as _, IntoDiagnostic, Result};
+use rusqlite::{params, Connection};
+use serde::Deserialize;
+
+use crate::{
+    battlesnake::{
+        advance, Board, Coord, Direction, Game, GameState, Ruleset, Snake, SNAKE_MAX_HEALTH,
+    },
+    context::ContextChunk,
+    corpus::Corpus,
+    inspect::escape_like,
+    retrieval::{get_context_with_options, RetrievalOptions},
+};
+
+/// Something an answer strategy can ask to have done while working out an answer
+#[async_trait]
+pub trait Tool: Debug + Send + Sync {
+    /// What the model calls the tool by
+    fn name(&self) -> &'static str;
+
+    /// Tells the model what the tool does and what to give it, shown in the prompt
+    fn description(&self) -> &'static str;
+
+    async fn call(&self, input: &str) -> Result<Observation>;
+}
+
+/// What a tool found
+#[derive(Clone, Debug, Default)]
+pub struct Observation {
+    pub text: String,
+    /// Pieces of the docs the answer can cite
+    pub chunks: Vec<ContextChunk>,
+}
+
+/// Longest page `read_page` will return, in characters, to stay inside the context window
+const MAX_PAGE_CHARS: usize = 6000;
+
+/// Most paths `list_pages` will return
+const MAX_LISTED_PAGES: usize = 50;
+
+/// The tools for looking through the corpora: `search_docs`, `read_page` and `list_pages`
+pub fn doc_tools(corpora: &[Corpus], options: &RetrievalOptions) -> Vec<Arc<dyn Tool>> {
+    vec![
+        Arc::new(SearchDocs {
+            corpora: corpora.to_vec(),
+            options: options.clone(),
+        }),
+        Arc::new(ReadPage {
+            corpora: corpora.to_vec(),
+        }),
+        Arc::new(ListPages {
+            corpora: corpora.to_vec(),
+        }),
+    ]
+}
+
+/// Searches the corpora the same way a question is searched
+#[derive(Clone, Debug)]
+pub struct SearchDocs {
+    pub corpora: Vec<Corpus>,
+    pub options: RetrievalOptions,
+}
+
+#[async_trait]
+impl Tool for SearchDocs {
+    fn name(&self) -> &'static str {
+        "search_docs"
+    }
+
+    fn description(&self) -> &'static str {
+        "Searches the docs for passages related to the input, which can be a question or a few keywords"
+    }
+
+    async fn call(&self, input: &str) -> Result<Observation> {
+        let (context, _question) =
+            get_context_with_options(input.to_string(), &self.corpora, &self.options).await?;
+        // Hits close together on a short page can end up with the same window of sentences
+        let chunks = context
+            .chunks
+            .into_iter()
+            .unique_by(|chunk| {
+                (
+                    chunk.corpus.clone(),
+                    chunk.page_path.clone(),
+                    chunk.text.clone(),
+                )
+            })
+            .collect_vec();
+
+        Ok(Observation {
+            text: if chunks.is_empty() {
+                "Nothing found".to_string()
+            } else {
+                format!("Found {} passages", chunks.len())
+            },
+            chunks,
+        })
+    }
+}
+
+/// Returns a whole page, for when a passage isn't enough
+#[derive(Clone, Debug)]
+pub struct ReadPage {
+    pub corpora: Vec<Corpus>,
+}
+
+#[async_trait]
+impl Tool for ReadPage {
+    fn name(&self) -> &'static str {
+        "read_page"
+    }
+
+    fn description(&self) -> &'static str {
+        "Reads a whole page, the input is the page's path or the end of it like rules/food.md"
+    }
+
+    async fn call(&self, input: &str) -> Result<Observation> {
+        let input = input.trim();
+        let mut matches = vec![];
+        for corpus in &self.corpora {
+            let conn = corpus.conn.0.lock().unwrap();
+            for (page_id, path) in find_pages(&conn, input)? {
+                matches.push((corpus, page_id, path));
+            }
+        }
+
+        // An exact path wins over pages that just end the same way
+        let exact = matches.iter().position(|(_, _, path)| path == input);
+        let (corpus, page_id, path) = match (exact, matches.len()) {
+            (Some(i), _) => matches.swap_remove(i),
+            (None, 1) => matches.remove(0),
+            (None, 0) => {
+                return Ok(Observation {
+                    text: format!("No page matches {input}, use list_pages to find one"),
+                    ..Default::default()
+                })
+            }
+            (None, _) => {
+                return Ok(Observation {
+                    text: format!(
+                        "Several pages match {input}, use the full path of one of:\n{}",
+                        matches.iter().map(|(_, _, path)| path).join("\n")
+                    ),
+                    ..Default::default()
+                })
+            }
+        };
+
+        let mut text = page_text(&corpus.conn.0.lock().unwrap(), page_id)?;
+        if let Some((cut, _)) = text.char_indices().nth(MAX_PAGE_CHARS) {
+            text.truncate(cut);
+            text.push_str("\n(the rest of the page was cut off)");
+        }
+
+        Ok(Observation {
+            text: format!("{}: {path}", corpus.name),
+            chunks: vec![ContextChunk {
+                corpus: corpus.name.clone(),
+                page_path: path,
+                text,
+                distance: 0.0,
+            }],
+        })
+    }
+}
+
+/// Lists the pages under a directory, so the model can find pages to read
+#[derive(Clone, Debug)]
+pub struct ListPages {
+    pub corpora: Vec<Corpus>,
+}
+
+#[async_trait]
+impl Tool for ListPages {
+    fn name(&self) -> &'static str {
+        "list_pages"
+    }
+
+    fn description(&self) -> &'static str {
+        "Lists the paths of the pages under a directory like rules/, leave the input empty to list everything"
+    }
+
+    async fn call(&self, input: &str) -> Result<Observation> {
+        let prefix = input.trim().trim_start_matches('/');
+
+        let mut paths = vec![];
+        for corpus in &self.corpora {
+            let corpus_paths = list_pages(&corpus.conn.0.lock().unwrap(), prefix)?;
+            paths.extend(
+                corpus_paths
+                    .into_iter()
+                    .map(|path| format!("{}: {path}", corpus.name)),
+            );
+        }
+
+        let text = match paths.len() {
+            0 => format!("No pages under {prefix}"),
+            count if count > MAX_LISTED_PAGES => format!(
+                "{}\n(and {} more, list a narrower directory to see them)",
+                paths[..MAX_LISTED_PAGES].join("\n"),
+                count - MAX_LISTED_PAGES
+            ),
+            _ => paths.join("\n"),
+        };
+
+        Ok(Observation {
+            text,
+            ..Default::default()
+        })
+    }
+}
+
+/// The paths of the pages under `prefix`, which can start anywhere after a `/`
+fn list_pages(conn: &Connection, prefix: &str) -> Result<Vec<String>> {
+    conn.prepare(
+        "select path from pages
+        where ?1 = '' or path like ?2 || '%' escape '\\' or path like '%/' || ?2 || '%' escape '\\'
+        order by path",
+    )
+    .into_diagnostic()?
+    .query_map(params![prefix, escape_like(prefix)], |row| row.get(0))
+    .into_diagnostic()?
+    .collect::<Result<Vec<_>, _>>()
+    .into_diagnostic()
+}
+
+/// Pages whose path is `path`, or ends with it after a `/`
+fn find_pages(conn: &Connection, path: &str) -> Result<Vec<(i64, String)>> {
+    conn.prepare(
+        "select rowid, path from pages where path = ?1 or path like '%/' || ?2 escape '\\'",
+    )
+    .into_diagnostic()?
+    .query_map(params![path, escape_like(path)], |row| {
+        Ok((row.get(0)?, row.get(1)?))
+    })
+    .into_diagnostic()?
+    .collect::<Result<Vec<_>, _>>()
+    .into_diagnostic()
+}
+
+fn page_text(conn: &Connection, page_id: i64) -> Result<String> {
+    let sentences = conn
+        .prepare("select text from sentences where page_id = ?1 order by page_index")
+        .into_diagnostic()?
+        .query_map(params![page_id], |row| row.get::<_, String>(0))
+        .into_diagnostic()?
+        .collect::<Result<Vec<_>, _>>()
+        .into_diagnostic()?;
+
+    Ok(sentences.join("\n"))
+}
+
+/// Plays out one turn of a Battlesnake game with the built in rules engine
+///
+/// With a game state from the question the model only has to give the moves, otherwise it
+/// describes the board itself.
+#[derive(Clone, Debug, Default)]
+pub struct SimulateTurn {
+    pub game: Option<GameState>,
+}
+
+/// A board the model describes itself, with a move for each snake
+#[derive(Debug, Deserialize)]
+struct Scenario {
+    #[serde(default = "default_ruleset")]
+    ruleset: String,
+    #[serde(default = "default_board_size")]
+    width: i32,
+    #[serde(default = "default_board_size")]
+    height: i32,
+    snakes: Vec<ScenarioSnake>,
+    #[serde(default)]
+    food: Vec<(i32, i32)>,
+    #[serde(default)]
+    hazards: Vec<(i32, i32)>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScenarioSnake {
+    name: String,
+    /// Head first
+    body: Vec<(i32, i32)>,
+    #[serde(default = "default_health")]
+    health: i32,
+    #[serde(rename = "move")]
+    direction: Option<Direction>,
+}
+
+fn default_ruleset() -> String {
+    "standard".to_string()
+}
+
+fn default_board_size() -> i32 {
+    11
+}
+
+fn default_health() -> i32 {
+    SNAKE_MAX_HEALTH
+}
+
+#[async_trait]
+impl Tool for SimulateTurn {
+    fn name(&self) -> &'static str {
+        "simulate_turn"
+    }
+
+    fn description(&self) -> &'static str {
+        if self.game.is_some() {
+            "Plays one turn of the game in the question with the official rules and shows what happens, the input is a move for each snake like Me: up, Big: left, snakes left out keep going straight"
+        } else {
+            "Plays one turn of a Battlesnake game with the official rules and shows what happens, the input is JSON like {\"ruleset\": \"standard\", \"width\": 11, \"height\": 11, \"snakes\": [{\"name\": \"a\", \"body\": [[5, 5], [5, 4], [5, 3]], \"health\": 100, \"move\": \"up\"}], \"food\": [[5, 6]], \"hazards\": []} with bodies head first"
+        }
+    }
+
+    async fn call(&self, input: &str) -> Result<Observation> {
+        let input = input.trim();
+        let (state, moves) = match &self.game {
+            Some(game) if !input.starts_with('{') => (game.clone(), parse_moves(input)?),
+            _ => scenario_state(input)?,
+        };
+
+        Ok(Observation {
+            text: advance(&state, &moves)?.to_prompt(),
+            ..Default::default()
+        })
+    }
+}
+
+/// Moves written like `Me: up, Big: left`, one per snake
+fn parse_moves(input: &str) -> Result<Vec<(String, Direction)>> {
+    input
+        .split([',', '\n', ';'])
+        .filter(|part| !part.trim().is_empty())
+        .map(|part| {
+            let (name, direction) = part
+                .trim()
+                .rsplit_once([':', '=', ' '])
+                .ok_or_else(|| miette!("Give each move as snake: direction, not {part}"))?;
+            Ok((
+                name.trim().trim_end_matches([':', '=']).trim().to_string(),
+                direction.parse()?,
+            ))
+        })
+        .collect()
+}
+
+fn scenario_state(input: &str) -> Result<(GameState, Vec<(String, Direction)>)> {
+    let scenario: Scenario = serde_json::from_str(input)
+        .into_diagnostic()
+        .wrap_err("The input should be a JSON board with the snakes and their moves")?;
+    let coord = |(x, y): (i32, i32)| Coord { x, y };
+
+    let mut moves = vec![];
+    let mut snakes = vec![];
+    for (i, snake) in scenario.snakes.into_iter().enumerate() {
+        if snake.body.is_empty() {
+            return Err(miette!("{} needs at least one square of body", snake.name));
+        }
+        // Names can repeat, so each snake gets its own id for the moves to refer to
+        let id = format!("snake-{i}");
+        if let Some(direction) = snake.direction {
+            moves.push((id.clone(), direction));
+        }
+        snakes.push(Snake {
+            id,
+            name: snake.name,
+            health: snake.health,
+            body: snake.body.into_iter().map(coord).collect(),
+            squad: String::new(),
+        });
+    }
+
+    let state = GameState {
+        game: Game {
+            ruleset: Ruleset {
+                name: scenario.ruleset,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        turn: 0,
+        board: Board {
+            width: scenario.width,
+            height: scenario.height,
+            food: scenario.food.into_iter().map(coord).collect(),
+            hazards: scenario.hazards.into_iter().map(coord).collect(),
+            snakes,
+        },
+        you: None,
+    };
+
+    Ok((state, moves))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::migrate;
+
+    fn db(paths: &[&str]) -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        for path in paths {
+            conn.execute("insert into pages (path) values (?1)", params![path])
+                .unwrap();
+        }
+        conn
+    }
+
+    #[test]
+    fn finds_pages_by_the_end_of_their_path() {
+        let conn = db(&["docs/rules/food.md", "docs/food.md", "docs/seafood.md"]);
+
+        let found = |path| {
+            find_pages(&conn, path)
+                .unwrap()
+                .into_iter()
+                .map(|(_, path)| path)
+                .collect_vec()
+        };
+        assert_eq!(found("rules/food.md"), vec!["docs/rules/food.md"]);
+        assert_eq!(found("food.md").len(), 2);
+        assert!(found("ood.md").is_empty());
+    }
+
+    #[test]
+    fn wildcards_in_paths_only_match_themselves() {
+        let conn = db(&["docs/snake_case.md", "docs/snakeXcase.md", "docs/100%.md"]);
+
+        let found = |path| {
+            find_pages(&conn, path)
+                .unwrap()
+                .into_iter()
+                .map(|(_, path)| path)
+                .collect_vec()
+        };
+        assert_eq!(found("snake_case.md"), vec!["docs/snake_case.md"]);
+        assert!(found("%.md").is_empty());
+
+        assert_eq!(
+            list_pages(&conn, "snake_").unwrap(),
+            vec!["docs/snake_case.md"]
+        );
+        assert_eq!(list_pages(&conn, "100%").unwrap(), vec!["docs/100%.md"]);
+        assert!(list_pages(&conn, "%").unwrap().is_empty());
+    }
+
+    #[test]
+    fn lists_pages_under_a_directory() {
+        let conn = db(&[
+            "docs/rules/food.md",
+            "docs/rules/hazards.md",
+            "docs/guides/start.md",
+        ]);
+
+        assert_eq!(
+            list_pages(&conn, "rules/").unwrap(),
+            vec!["docs/rules/food.md", "docs/rules/hazards.md"]
+        );
+        assert_eq!(list_pages(&conn, "").unwrap().len(), 3);
+    }
+}